@@ -2,18 +2,87 @@
  * A session represents a unique ID for a given user.
  */
 export type Session = number;
+/**
+ * A snapshot of the connection quality.
+ */
+export type ConnectionStats = {
+  /**
+   * Voice packets received from the server.
+   */
+  from_server: CryptStats;
+  tcp: PingStats;
+  /**
+   * Voice packets received tunneled over the control connection.
+   */
+  tcp_packets: number;
+  /**
+   * Voice packets the server received from us, as of its last ping reply.
+   */
+  to_server: CryptStats;
+  udp: PingStats;
+  /**
+   * Voice packets received over UDP.
+   */
+  udp_packets: number;
+  /**
+   * Voice is sent over UDP, otherwise it is tunneled over the control connection.
+   */
+  udp_working: boolean;
+}
+;
 /**
  * Only opus is supported.
  */
 export type VoiceMessage = {
   data: number[];
   frame_number: number;
+  /**
+   * Set on the last frame before the sender stops talking.
+   */
+  is_terminator: boolean;
   sender: Session;
+  /**
+   * 0 for normal talking, 31 for server loopback.
+   */
+  target: number;
+}
+;
+/**
+ * Packet counters kept by a `CryptState`.
+ */
+export type CryptStats = {
+  good: number;
+  late: number;
+  lost: number;
+  resync: number;
+}
+;
+/**
+ * Round trip times of one transport.
+ */
+export type PingStats = {
+  /**
+   * Average round trip in milliseconds.
+   */
+  avg: number;
+  /**
+   * Pings answered.
+   */
+  packets: number;
+  /**
+   * Variance of the round trip in milliseconds squared.
+   */
+  var: number;
 }
 ;
+export type AcceptCertificate = null;
 export type Channel = {
   description: string;
   id: ChannelID;
+  /**
+   * Linked channels hear normal talking from this one. Links always go both ways.
+   */
+  links: ChannelID[];
   max_users?: number;
   name: string;
   parent?: ChannelID;
@@ -50,6 +119,22 @@ export type Event = {
   type: "UserJoinedServer";
 }
 ;
+export type ExportIdentity = null;
+export type ImportIdentity = {
+  /**
+   * Fingerprint of the imported certificate.
+   */
+  fingerprint: string;
+}
+;
+export type MonitorMic = {
+  monitor: boolean;
+}
+;
+export type MuteMic = {
+  mute: boolean;
+}
+;
 export type Response = {
   data: Connect;
   type: "Connect";
@@ -57,6 +142,39 @@ export type Response = {
  | {
   data: Disconnect;
   type: "Disconnect";
+}
+ | {
+  data: SendMessage;
+  type: "SendMessage";
+}
+ | {
+  data: MuteMic;
+  type: "MuteMic";
+}
+ | {
+  data: MonitorMic;
+  type: "MonitorMic";
+}
+ | {
+  data: AcceptCertificate;
+  type: "AcceptCertificate";
+}
+ | {
+  data: ImportIdentity;
+  type: "ImportIdentity";
+}
+ | {
+  data: ExportIdentity;
+  type: "ExportIdentity";
+}
+ | {
+  data: Stats;
+  type: "Stats";
+}
+;
+export type SendMessage = null;
+export type Stats = {
+  stats: ConnectionStats;
 }
 ;
 export type User = {
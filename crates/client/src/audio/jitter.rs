@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Audio covered by one frame number, senders count frames in 10ms steps.
+pub const FRAME_DURATION: Duration = Duration::from_millis(10);
+/// Frame numbers buffered before a speaker starts playing.
+const MIN_DEPTH: u64 = 2;
+const MAX_DEPTH: u64 = 20;
+/// Frames buffered past this are dropped, oldest first.
+const MAX_FRAMES: usize = 50;
+/// Frames concealed in a row before waiting for the buffer to fill again.
+const MAX_CONCEALED: u32 = 5;
+
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Audio(Vec<u8>),
+    /// The next frame is missing. `fec` is the packet after it when its forward error
+    /// correction data covers the missing frame, otherwise the decoder should conceal it.
+    Lost {
+        fec: Option<Vec<u8>>,
+    },
+}
+
+/// Reorders a speaker's frames by frame number and holds enough of them to cover the
+/// jitter measured on arrival.
+#[derive(Debug, Default)]
+pub struct JitterBuffer {
+    frames: BTreeMap<u64, Vec<u8>>,
+    /// The frame number to play next, None until enough frames are buffered.
+    next: Option<u64>,
+    /// Frame numbers covered by the last frame played.
+    last_advance: u64,
+    /// Estimated jitter in milliseconds, calculated as in RFC 3550.
+    jitter: f32,
+    last_arrival: Option<(Instant, u64)>,
+    /// Frames concealed in a row.
+    concealed: u32,
+    /// A terminator was received, play what is left without waiting for more.
+    ending: bool,
+}
+
+impl JitterBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Frame numbers to buffer before playing, twice the measured jitter.
+    pub fn depth(&self) -> u64 {
+        let frames = (2.0 * self.jitter / FRAME_DURATION.as_millis() as f32).ceil() as u64;
+        (frames + 1).clamp(MIN_DEPTH, MAX_DEPTH)
+    }
+
+    pub fn push(&mut self, frame_number: u64, data: Vec<u8>, is_terminator: bool, now: Instant) {
+        if let Some((at, n)) = self.last_arrival {
+            let arrived = now.saturating_duration_since(at).as_secs_f32() * 1000.0;
+            let expected =
+                (frame_number as i64 - n as i64) as f32 * FRAME_DURATION.as_millis() as f32;
+            self.jitter += ((arrived - expected).abs() - self.jitter) / 16.0;
+        }
+        self.last_arrival = Some((now, frame_number));
+        if is_terminator {
+            self.ending = true;
+        }
+
+        if self.next.is_some_and(|next| frame_number < next) {
+            // too late to be played
+            return;
+        }
+        self.frames.insert(frame_number, data);
+        while self.frames.len() > MAX_FRAMES {
+            self.frames.pop_first();
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.next.is_some()
+    }
+
+    /// Returns the next frame to decode, None while buffering or when the speaker stopped.
+    /// Missing frames are only concealed when the output is `starving`, until then they
+    /// may still arrive. `advance` has to be called after each frame with the number of
+    /// frames it covered.
+    pub fn pop(&mut self, starving: bool) -> Option<Frame> {
+        let next = match self.next {
+            Some(next) => next,
+            None => {
+                let first = *self.frames.keys().next()?;
+                let last = *self.frames.keys().next_back()?;
+                if last - first + 1 < self.depth() && !self.ending {
+                    return None;
+                }
+                self.next = Some(first);
+                first
+            }
+        };
+
+        let Some((&n, data)) = self.frames.first_key_value() else {
+            if self.ending || self.concealed >= MAX_CONCEALED {
+                self.reset();
+                return None;
+            }
+            if !starving {
+                return None;
+            }
+            self.concealed += 1;
+            return Some(Frame::Lost { fec: None });
+        };
+
+        if n == next || n - next > MAX_DEPTH {
+            // too far behind to conceal everything in between, skip ahead
+            self.next = Some(n);
+            self.concealed = 0;
+            let (_, data) = self.frames.pop_first()?;
+            return Some(Frame::Audio(data));
+        }
+
+        if !starving {
+            return None;
+        }
+        self.concealed += 1;
+        let fec = (n - next <= self.last_advance).then(|| data.clone());
+        Some(Frame::Lost { fec })
+    }
+
+    pub fn advance(&mut self, frames: u64) {
+        let frames = frames.max(1);
+        self.last_advance = frames;
+        if let Some(next) = self.next.as_mut() {
+            *next += frames;
+            self.frames = self.frames.split_off(next);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.frames.clear();
+        self.next = None;
+        self.concealed = 0;
+        self.ending = false;
+        self.last_arrival = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn play(j: &mut JitterBuffer) -> Vec<Frame> {
+        std::iter::from_fn(|| {
+            let f = j.pop(true)?;
+            j.advance(1);
+            Some(f)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_reorder() {
+        let now = Instant::now();
+        let mut j = JitterBuffer::new();
+        j.push(1, vec![1], false, now);
+        assert_eq!(j.pop(true), None, "waits for the buffer to fill");
+        j.push(0, vec![0], false, now);
+        j.push(2, vec![2], true, now);
+
+        assert_eq!(
+            play(&mut j),
+            vec![
+                Frame::Audio(vec![0]),
+                Frame::Audio(vec![1]),
+                Frame::Audio(vec![2])
+            ]
+        );
+        // the terminator ends playback without concealing
+        assert_eq!(j.pop(true), None);
+
+        j.push(0, vec![9], false, now);
+        j.push(1, vec![9], false, now);
+        assert_eq!(
+            j.pop(false),
+            Some(Frame::Audio(vec![9])),
+            "restarts after ending"
+        );
+    }
+
+    #[test]
+    fn test_loss() {
+        let now = Instant::now();
+        let mut j = JitterBuffer::new();
+        for n in [0, 2, 5] {
+            j.push(n, vec![n as u8], false, now);
+        }
+        assert_eq!(j.pop(false), Some(Frame::Audio(vec![0])));
+        j.advance(1);
+        assert_eq!(j.pop(false), None, "frame 1 may still arrive");
+        j.push(1, vec![1], false, now);
+        assert_eq!(j.pop(false), Some(Frame::Audio(vec![1])));
+        j.advance(1);
+
+        j.push(6, vec![6], true, now);
+
+        assert_eq!(
+            play(&mut j),
+            vec![
+                Frame::Audio(vec![2]),
+                Frame::Lost { fec: None },
+                Frame::Lost { fec: Some(vec![5]) },
+                Frame::Audio(vec![5]),
+                Frame::Audio(vec![6]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_late_frames_dropped() {
+        let now = Instant::now();
+        let mut j = JitterBuffer::new();
+        j.push(0, vec![0], false, now);
+        j.push(1, vec![1], false, now);
+        assert_eq!(j.pop(false), Some(Frame::Audio(vec![0])));
+        j.advance(1);
+        assert_eq!(j.pop(false), Some(Frame::Audio(vec![1])));
+        j.advance(1);
+
+        j.push(0, vec![0], false, now);
+        assert_eq!(j.pop(false), None, "waits while the output has audio");
+        assert_eq!(j.pop(true), Some(Frame::Lost { fec: None }));
+    }
+
+    #[test]
+    fn test_depth_adapts() {
+        let start = Instant::now();
+        let mut j = JitterBuffer::new();
+        for n in 0..50 {
+            j.push(n, vec![], false, start + FRAME_DURATION * n as u32);
+        }
+        assert_eq!(j.depth(), MIN_DEPTH);
+
+        let mut j = JitterBuffer::new();
+        for n in 0..50u32 {
+            // frames arrive in bursts of 4
+            let at = start + FRAME_DURATION * (n / 4 * 4);
+            j.push(n as u64, vec![], false, at);
+        }
+        assert!(j.depth() > MIN_DEPTH, "depth {}", j.depth());
+    }
+}
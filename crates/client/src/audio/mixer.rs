@@ -0,0 +1,128 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use speakez::mumble::session::Session;
+
+/// Gain for everyone else while a priority speaker talks, about -12 dB.
+const DUCK_GAIN: f32 = 0.25;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Source {
+    Speaker(Session),
+    /// The local microphone, played back while monitoring.
+    Monitor,
+}
+
+/// Mixes decoded audio from each source into a single stream.
+#[derive(Debug, Default)]
+pub struct Mixer {
+    sources: HashMap<Source, VecDeque<f32>>,
+    /// Sources that duck everyone else while they have audio queued.
+    priority: HashSet<Source>,
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue interleaved samples from `source`.
+    pub fn push(&mut self, source: Source, pcm: &[f32]) {
+        self.sources.entry(source).or_default().extend(pcm);
+    }
+
+    /// The number of samples queued for `source`.
+    pub fn buffered(&self, source: Source) -> usize {
+        self.sources.get(&source).map_or(0, |s| s.len())
+    }
+
+    pub fn remove(&mut self, source: Source) {
+        self.sources.remove(&source);
+    }
+
+    pub fn set_priority(&mut self, sources: impl IntoIterator<Item = Source>) {
+        self.priority = sources.into_iter().collect();
+    }
+
+    /// The number of samples ready to be mixed, sources with fewer samples are padded
+    /// with silence.
+    pub fn available(&self) -> usize {
+        self.sources.values().map(|s| s.len()).max().unwrap_or(0)
+    }
+
+    /// Mix up to `out.len()` samples into `out`, returns the number written. Samples are
+    /// clamped so overlapping sources do not wrap around. While a priority source has
+    /// audio the others are ducked.
+    pub fn mix(&mut self, out: &mut [f32]) -> usize {
+        let n = self.available().min(out.len());
+        let out = &mut out[..n];
+        out.fill(0.0);
+
+        let ducking = self
+            .sources
+            .iter()
+            .any(|(source, samples)| !samples.is_empty() && self.priority.contains(source));
+        for (source, samples) in self.sources.iter_mut() {
+            let gain = if ducking && !self.priority.contains(source) {
+                DUCK_GAIN
+            } else {
+                1.0
+            };
+            let count = n.min(samples.len());
+            for (o, s) in out.iter_mut().zip(samples.drain(..count)) {
+                *o += s * gain;
+            }
+        }
+        for o in out.iter_mut() {
+            *o = o.clamp(-1.0, 1.0);
+        }
+        n
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mix() {
+        let alice = Source::Speaker(Session::new(1).unwrap());
+        let bob = Source::Monitor;
+
+        let mut m = Mixer::new();
+        m.push(alice, &[0.25, 0.5, 0.75, -0.5]);
+        m.push(bob, &[0.25, 0.75]);
+        assert_eq!(m.available(), 4);
+
+        let mut out = [9.0; 3];
+        assert_eq!(m.mix(&mut out), 3);
+        assert_eq!(out, [0.5, 1.0, 0.75]);
+
+        m.push(bob, &[-0.75]);
+        let mut out = [9.0; 8];
+        assert_eq!(m.mix(&mut out), 1);
+        assert_eq!(out[0], -1.0, "mixed samples are clamped");
+        assert_eq!(m.available(), 0);
+    }
+
+    #[test]
+    fn test_ducking() {
+        let host = Source::Speaker(Session::new(1).unwrap());
+        let guest = Source::Speaker(Session::new(2).unwrap());
+
+        let mut m = Mixer::new();
+        m.set_priority([host]);
+        m.push(guest, &[0.4, 0.4]);
+        let mut out = [0.0; 2];
+        m.mix(&mut out);
+        assert_eq!(
+            out,
+            [0.4, 0.4],
+            "not ducked while the priority speaker is quiet"
+        );
+
+        m.push(host, &[0.5, 0.5]);
+        m.push(guest, &[0.4, 0.4]);
+        m.mix(&mut out);
+        assert_eq!(out, [0.6, 0.6]);
+    }
+}
@@ -1,11 +1,17 @@
 mod input;
+mod jitter;
+mod mixer;
 mod output;
+mod process;
 pub mod state;
+mod transmit;
 
 pub use input::Input;
 pub use libopus::calc_frame_size;
 pub use output::Output;
+pub use process::{InputProcessing, NoiseGate};
 pub use state::State;
+pub use transmit::TransmitMode;
 
 use std::sync::Arc;
 
@@ -33,7 +39,33 @@ pub struct DeviceConfig {
     pub buf_size: u32,
 }
 
-pub fn get_input(host: &cpal::Host, cfg: &DeviceConfig, buf: Ringbuf) -> (Input<Stream>, Consumer) {
+/// The stream config closest to `cfg` that the device supports, falling back to its
+/// default sample rate and channels. Input is resampled from whatever is used.
+fn input_config(device: &cpal::Device, cfg: &DeviceConfig) -> cpal::SupportedStreamConfig {
+    let rate = cpal::SampleRate(cfg.sample_rate);
+    let supported = device
+        .supported_input_configs()
+        .ok()
+        .and_then(|mut configs| {
+            configs.find(|c| {
+                c.channels() == cfg.channels as u16
+                    && c.sample_format() == cpal::SampleFormat::F32
+                    && c.min_sample_rate() <= rate
+                    && rate <= c.max_sample_rate()
+            })
+        });
+    match supported {
+        Some(config) => config.with_sample_rate(rate),
+        None => device.default_input_config().unwrap(),
+    }
+}
+
+/// Returns the input and the stream config it captures with, which may differ from `cfg`.
+pub fn get_input(
+    host: &cpal::Host,
+    cfg: &DeviceConfig,
+    buf: Ringbuf,
+) -> (Input<Stream>, Consumer, cpal::StreamConfig) {
     let device = match &cfg.name {
         None => host
             .default_input_device()
@@ -46,12 +78,10 @@ pub fn get_input(host: &cpal::Host, cfg: &DeviceConfig, buf: Ringbuf) -> (Input<
     };
 
     println!("Using input device: \"{}\"", device.name().unwrap());
-    let config = device.default_input_config().unwrap();
+    let config = input_config(&device, cfg);
     let buf_size = *config.buffer_size();
 
     let mut config: cpal::StreamConfig = config.into();
-    config.sample_rate = cpal::SampleRate(cfg.sample_rate);
-    config.channels = cfg.channels as u16;
     config.buffer_size = match buf_size {
         cpal::SupportedBufferSize::Range { min, max } => {
             if cfg.buf_size >= min && cfg.buf_size <= max {
@@ -72,7 +102,7 @@ pub fn get_input(host: &cpal::Host, cfg: &DeviceConfig, buf: Ringbuf) -> (Input<
     let (producer, consumer) = buf.split();
     let input = Input::new(device).build_stream(&config, producer);
 
-    (input, consumer)
+    (input, consumer, config)
 }
 
 pub fn get_output(
@@ -0,0 +1,437 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use super::transmit::level;
+
+/// The gate closes once the input drops this many dB below the threshold.
+const GATE_HYSTERESIS: f32 = 6.0;
+/// Keep the gate open for this long after the input drops, so word endings are not cut.
+const GATE_HOLD: Duration = Duration::from_millis(200);
+/// Level in dBFS automatic gain control aims for.
+const AGC_TARGET: f32 = -20.0;
+const AGC_MAX_GAIN: f32 = 30.0;
+const AGC_MIN_GAIN: f32 = -20.0;
+/// How fast the gain drops in dB per second when the input is too loud.
+const AGC_ATTACK: f32 = 60.0;
+/// How fast the gain rises in dB per second when the input is too quiet.
+const AGC_RELEASE: f32 = 6.0;
+/// Input quieter than this in dBFS is not speech and does not change the gain.
+const AGC_SILENCE: f32 = -60.0;
+/// Cutoff of the anti-aliasing filter as a fraction of the output sample rate, a little
+/// below the Nyquist frequency.
+const ANTI_ALIAS_CUTOFF: f64 = 0.4;
+/// Q of the two filter stages that make up a fourth order Butterworth low-pass.
+const BUTTERWORTH_Q: [f64; 2] = [0.5412, 1.3066];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoiseGate {
+    /// Level in dBFS that opens the gate.
+    pub threshold: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputProcessing {
+    /// Gain in dB applied before anything else.
+    pub gain: f32,
+    /// Silence input quieter than the threshold.
+    pub noise_gate: Option<NoiseGate>,
+    /// Adjust the gain so speech is sent at a steady level.
+    pub agc: bool,
+}
+
+impl Default for InputProcessing {
+    fn default() -> Self {
+        Self {
+            gain: 0.0,
+            noise_gate: Some(NoiseGate { threshold: -60.0 }),
+            agc: true,
+        }
+    }
+}
+
+/// Converts interleaved audio between sample rates and channel counts, interpolating
+/// linearly between input frames. When downsampling, the input is low-pass filtered
+/// first so frequencies the output can not hold do not alias. The filter rolls off at
+/// 24 dB per octave, very high rates like 192 kHz still leak some aliasing.
+#[derive(Debug)]
+pub struct Resampler {
+    from_channels: usize,
+    to_channels: usize,
+    /// Anti-aliasing filter stages for each output channel, empty unless downsampling.
+    filters: Vec<[Biquad; 2]>,
+    /// Input frames per output frame.
+    step: f64,
+    /// Position of the next output frame between `last` and the next input frame.
+    pos: f64,
+    /// The last input frame, already converted to the output channels.
+    last: Vec<f32>,
+    frame: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(from: (u32, u8), to: (u32, u8)) -> Self {
+        let to_channels = to.1.max(1) as usize;
+        let filters = if from.0 > to.0 {
+            let cutoff = to.0 as f64 * ANTI_ALIAS_CUTOFF;
+            let stage = |q| Biquad::low_pass(from.0 as f64, cutoff, q);
+            vec![BUTTERWORTH_Q.map(stage); to_channels]
+        } else {
+            vec![]
+        };
+        Self {
+            from_channels: from.1.max(1) as usize,
+            to_channels,
+            filters,
+            step: from.0 as f64 / to.0 as f64,
+            pos: 0.0,
+            last: vec![0.0; to_channels],
+            frame: vec![0.0; to_channels],
+        }
+    }
+
+    pub fn process(&mut self, pcm: &[f32], out: &mut impl Extend<f32>) {
+        for input in pcm.chunks_exact(self.from_channels) {
+            self.convert_channels(input);
+            for (s, stages) in self.frame.iter_mut().zip(&mut self.filters) {
+                *s = stages.iter_mut().fold(*s, |s, stage| stage.process(s));
+            }
+            while self.pos < 1.0 {
+                let pos = self.pos as f32;
+                out.extend(
+                    self.last
+                        .iter()
+                        .zip(&self.frame)
+                        .map(|(a, b)| a + (b - a) * pos),
+                );
+                self.pos += self.step;
+            }
+            self.pos -= 1.0;
+            std::mem::swap(&mut self.last, &mut self.frame);
+        }
+    }
+
+    /// Convert `input` to the output channels in `self.frame`, channels are averaged
+    /// when they do not match.
+    fn convert_channels(&mut self, input: &[f32]) {
+        if input.len() == self.to_channels {
+            self.frame.copy_from_slice(input);
+            return;
+        }
+        let sample = input.iter().sum::<f32>() / input.len() as f32;
+        self.frame.fill(sample);
+    }
+}
+
+/// Second order low-pass filter, coefficients from the Audio EQ Cookbook.
+#[derive(Clone, Copy, Debug)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    /// The last two inputs and outputs.
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl Biquad {
+    fn low_pass(rate: f64, cutoff: f64, q: f64) -> Self {
+        let w0 = 2.0 * std::f64::consts::PI * cutoff / rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        let b1 = (1.0 - w0.cos()) / a0;
+        Self {
+            b0: (b1 / 2.0) as f32,
+            b1: b1 as f32,
+            b2: (b1 / 2.0) as f32,
+            a1: (-2.0 * w0.cos() / a0) as f32,
+            a2: ((1.0 - alpha) / a0) as f32,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.x[0] + self.b2 * self.x[1]
+            - self.a1 * self.y[0]
+            - self.a2 * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// Prepares captured audio for the encoder: resampling, gain, noise gate and automatic
+/// gain control.
+#[derive(Debug)]
+pub struct Processor {
+    config: InputProcessing,
+    resampler: Resampler,
+    /// Resampled audio waiting for a full frame.
+    pending: VecDeque<f32>,
+    gate_open: bool,
+    /// Time since the input was last above the gate threshold.
+    gate_quiet_for: Duration,
+    /// Gate gain at the end of the last frame, ramped to avoid clicks.
+    gate_gain: f32,
+    /// Automatic gain in dB.
+    agc_gain: f32,
+}
+
+impl Processor {
+    /// Process audio captured at `from` (sample rate, channels) for an encoder at `to`.
+    pub fn new(config: InputProcessing, from: (u32, u8), to: (u32, u8)) -> Self {
+        Self {
+            config,
+            resampler: Resampler::new(from, to),
+            pending: VecDeque::new(),
+            gate_open: false,
+            gate_quiet_for: Duration::ZERO,
+            gate_gain: 0.0,
+            agc_gain: 0.0,
+        }
+    }
+
+    pub fn set_config(&mut self, config: InputProcessing) {
+        self.config = config;
+    }
+
+    /// Queue captured audio.
+    pub fn push(&mut self, pcm: &[f32]) {
+        self.resampler.process(pcm, &mut self.pending);
+    }
+
+    /// Fill `frame` with the next processed frame, returns false until enough audio is
+    /// queued.
+    pub fn next_frame(&mut self, frame: &mut [f32], duration: Duration) -> bool {
+        if self.pending.len() < frame.len() {
+            return false;
+        }
+        let len = frame.len();
+        for (s, p) in frame.iter_mut().zip(self.pending.drain(..len)) {
+            *s = p;
+        }
+
+        if self.config.gain != 0.0 {
+            let gain = db_to_gain(self.config.gain);
+            frame.iter_mut().for_each(|s| *s *= gain);
+        }
+
+        let level = level(frame);
+        let gate = self.gate(level, duration);
+        ramp(frame, self.gate_gain, gate);
+        self.gate_gain = gate;
+
+        if self.config.agc {
+            let before = self.agc_gain;
+            if gate > 0.0 && level > AGC_SILENCE {
+                self.adjust_agc(level, duration);
+            }
+            ramp(frame, db_to_gain(before), db_to_gain(self.agc_gain));
+        }
+
+        for s in frame.iter_mut() {
+            *s = s.clamp(-1.0, 1.0);
+        }
+        true
+    }
+
+    /// The gate gain for a frame at `level`, 1.0 when open.
+    fn gate(&mut self, level: f32, duration: Duration) -> f32 {
+        let Some(NoiseGate { threshold }) = self.config.noise_gate else {
+            return 1.0;
+        };
+
+        if level > threshold || (self.gate_open && level > threshold - GATE_HYSTERESIS) {
+            self.gate_open = true;
+            self.gate_quiet_for = Duration::ZERO;
+        } else if self.gate_open {
+            self.gate_quiet_for += duration;
+            self.gate_open = self.gate_quiet_for < GATE_HOLD;
+        }
+        if self.gate_open {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn adjust_agc(&mut self, level: f32, duration: Duration) {
+        let desired = (AGC_TARGET - level).clamp(AGC_MIN_GAIN, AGC_MAX_GAIN);
+        let secs = duration.as_secs_f32();
+        self.agc_gain = if desired < self.agc_gain {
+            (self.agc_gain - AGC_ATTACK * secs).max(desired)
+        } else {
+            (self.agc_gain + AGC_RELEASE * secs).min(desired)
+        };
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Scale `pcm` by a gain moving linearly from `from` to `to`.
+fn ramp(pcm: &mut [f32], from: f32, to: f32) {
+    if from == to {
+        if from != 1.0 {
+            pcm.iter_mut().for_each(|s| *s *= from);
+        }
+        return;
+    }
+    let step = (to - from) / pcm.len() as f32;
+    for (i, s) in pcm.iter_mut().enumerate() {
+        *s *= from + step * (i + 1) as f32;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(10);
+
+    fn tone(amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| if i % 2 == 0 { amplitude } else { -amplitude })
+            .collect()
+    }
+
+    fn processor(config: InputProcessing) -> Processor {
+        Processor::new(config, (48000, 1), (48000, 1))
+    }
+
+    #[test]
+    fn test_resample_rate() {
+        let mut r = Resampler::new((44100, 1), (48000, 1));
+        let mut out = vec![];
+        for _ in 0..10 {
+            r.process(&vec![0.5; 441], &mut out);
+        }
+        // the position accumulates rounding errors, allow a frame either way
+        assert!((4799..=4801).contains(&out.len()), "{}", out.len());
+        assert!(out[10..].iter().all(|s| (s - 0.5).abs() < 1e-6));
+
+        let mut r = Resampler::new((16000, 1), (48000, 1));
+        let mut out = vec![];
+        r.process(&[0.0, 0.3], &mut out);
+        let expected = [0.0, 0.0, 0.0, 0.0, 0.1, 0.2];
+        assert!(
+            out.iter().zip(expected).all(|(a, b)| (a - b).abs() < 1e-6),
+            "{out:?}"
+        );
+    }
+
+    #[test]
+    fn test_resample_anti_alias() {
+        let sine = |freq: f32, rate: f32, len: usize| -> Vec<f32> {
+            (0..len)
+                .map(|i| 0.5 * (std::f32::consts::TAU * freq * i as f32 / rate).sin())
+                .collect()
+        };
+        let peak = |pcm: &[f32]| pcm.iter().fold(0f32, |m, s| m.max(s.abs()));
+
+        // speech passes through
+        let mut r = Resampler::new((192000, 1), (48000, 1));
+        let mut out = vec![];
+        r.process(&sine(1000.0, 192000.0, 19200), &mut out);
+        assert!(peak(&out[480..]) > 0.45, "{}", peak(&out[480..]));
+
+        // 40 kHz would fold back to 8 kHz
+        let mut r = Resampler::new((192000, 1), (48000, 1));
+        let mut out = vec![];
+        r.process(&sine(40000.0, 192000.0, 19200), &mut out);
+        assert!(peak(&out[480..]) < 0.05, "{}", peak(&out[480..]));
+    }
+
+    #[test]
+    fn test_resample_channels() {
+        let mut r = Resampler::new((48000, 2), (48000, 1));
+        let mut out = vec![];
+        r.process(&[0.2, 0.4, 0.6, 0.8], &mut out);
+        // output lags one frame behind
+        assert!((out[1] - 0.3).abs() < 1e-6);
+
+        let mut r = Resampler::new((48000, 1), (48000, 2));
+        let mut out = vec![];
+        r.process(&[0.2, 0.4], &mut out);
+        assert_eq!(out, [0.0, 0.0, 0.2, 0.2]);
+    }
+
+    #[test]
+    fn test_noise_gate() {
+        let mut p = processor(InputProcessing {
+            gain: 0.0,
+            noise_gate: Some(NoiseGate { threshold: -40.0 }),
+            agc: false,
+        });
+        let mut frame = vec![0.0; 480];
+
+        // about -60 dBFS
+        p.push(&tone(0.001, 480));
+        assert!(p.next_frame(&mut frame, FRAME));
+        assert!(frame.iter().all(|s| *s == 0.0), "quiet input is gated");
+        assert!(!p.next_frame(&mut frame, FRAME), "waits for a full frame");
+
+        // about -20 dBFS
+        p.push(&tone(0.1, 480 * 2));
+        assert!(p.next_frame(&mut frame, FRAME));
+        assert!(p.next_frame(&mut frame, FRAME));
+        assert!(frame.iter().all(|s| s.abs() > 0.09));
+
+        // the gate is held open before closing
+        let held = (GATE_HOLD.as_millis() / FRAME.as_millis()) as usize;
+        p.push(&tone(0.001, 480 * (held + 1)));
+        for _ in 0..held - 1 {
+            assert!(p.next_frame(&mut frame, FRAME));
+            assert!(frame.iter().any(|s| *s != 0.0));
+        }
+        assert!(p.next_frame(&mut frame, FRAME));
+        assert!(p.next_frame(&mut frame, FRAME));
+        assert!(frame.iter().all(|s| *s == 0.0));
+    }
+
+    #[test]
+    fn test_agc() {
+        let mut p = processor(InputProcessing {
+            gain: 0.0,
+            noise_gate: None,
+            agc: true,
+        });
+        let mut frame = vec![0.0; 480];
+
+        // quiet speech at -40 dBFS is raised slowly
+        for _ in 0..100 {
+            p.push(&tone(0.01, 480));
+            assert!(p.next_frame(&mut frame, FRAME));
+        }
+        let raised = level(&frame);
+        assert!((-35.0..-33.0).contains(&raised), "level {raised}");
+
+        // loud speech is brought down quickly
+        for _ in 0..50 {
+            p.push(&tone(0.9, 480));
+            assert!(p.next_frame(&mut frame, FRAME));
+        }
+        let lowered = level(&frame);
+        assert!(
+            (AGC_TARGET - 0.5..AGC_TARGET + 0.5).contains(&lowered),
+            "level {lowered}"
+        );
+    }
+
+    #[test]
+    fn test_gain() {
+        let mut p = processor(InputProcessing {
+            gain: 6.0,
+            noise_gate: None,
+            agc: false,
+        });
+        let mut frame = vec![0.0; 480];
+        p.push(&tone(0.25, 480 * 2));
+        assert!(p.next_frame(&mut frame, FRAME));
+        assert!(p.next_frame(&mut frame, FRAME));
+        assert!(frame[1..].iter().all(|s| (s.abs() - 0.4988).abs() < 1e-3));
+    }
+}
@@ -1,5 +1,9 @@
 pub mod commands {
+    use std::collections::HashSet;
+
     use crate::audio;
+    use speakez::common::events::VoiceMessage;
+    use speakez::mumble::session::Session;
 
     #[derive(Debug)]
     pub struct SetDevice {
@@ -11,20 +15,53 @@ pub mod commands {
         SetDevice(SetDevice),
         Pause,
         Play,
-        PlayOpusAudio(Vec<u8>),
+        PlayOpusAudio(VoiceMessage),
+        /// Everyone else is ducked while one of these speakers talks.
+        SetPrioritySpeakers(HashSet<Session>),
+        SetTransmitMode(audio::TransmitMode),
+        SetProcessing(audio::InputProcessing),
+        PushToTalk(bool),
+        /// Stop transmitting, ending the current utterance.
+        Mute(bool),
+        /// Send microphone audio back as `Event::Monitor`.
+        Monitor(bool),
+        /// Play microphone audio with `channels` interleaved channels.
+        PlayMonitor {
+            pcm: Vec<f32>,
+            channels: u8,
+        },
     }
 }
 
 pub mod events {
-    #[derive(Debug, PartialEq, Eq)]
+    #[derive(Debug, PartialEq)]
     pub enum Event {
         Error(String),
-        Data(Vec<u8>),
+        Data {
+            data: Vec<u8>,
+            /// Set on the last frame before transmitting stops.
+            is_terminator: bool,
+        },
+        /// Captured microphone audio, sent while monitoring.
+        Monitor {
+            pcm: Vec<f32>,
+            channels: u8,
+        },
     }
 }
 
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use speakez::common::events::VoiceMessage;
+use speakez::mumble::session::Session;
 
+use super::jitter::{Frame, JitterBuffer, FRAME_DURATION};
+use super::mixer::{Mixer, Source};
+use super::process::Processor;
+use super::transmit::Transmitter;
 use super::Stream;
 use crate::audio;
 
@@ -89,8 +126,13 @@ struct Input {
     device: audio::Input<Stream>,
     encoder: libopus::Encoder,
     consumer: audio::Consumer,
+    processor: Processor,
+    /// Audio as captured by the device, before processing.
+    captured: Vec<f32>,
     pcm_chunk: Vec<f32>,
     encoded_pcm: Vec<u8>,
+    frame_duration: Duration,
+    channels: u8,
 }
 
 pub type Sender = Box<dyn FnMut(Event) + Send>;
@@ -98,10 +140,12 @@ pub type Sender = Box<dyn FnMut(Event) + Send>;
 fn audio_input_thread(mut sender: Sender, receiver: mpsc::Receiver<Command>) {
     use cpal::traits::StreamTrait as _;
     use ringbuf::traits::Consumer as _;
-    use ringbuf::traits::Observer as _;
 
     let timeout = std::time::Duration::from_millis(5);
     let mut input: Option<Input> = None;
+    let mut transmitter = Transmitter::new(audio::TransmitMode::default());
+    let mut processing = audio::InputProcessing::default();
+    let mut monitor = false;
 
     loop {
         let mut should_sleep = true;
@@ -113,7 +157,11 @@ fn audio_input_thread(mut sender: Sender, receiver: mpsc::Receiver<Command>) {
                     Command::SetDevice(SetDevice { config }) => {
                         let sample_rate = config.sample_rate;
                         let channels = config.channels;
-                        let frame_size = libopus::calc_frame_size(sample_rate, 10);
+                        let frame_duration = Duration::from_millis(10);
+                        let frame_size = libopus::calc_frame_size(
+                            sample_rate,
+                            frame_duration.as_millis() as u32,
+                        );
 
                         let encoder = libopus::Encoder::new(
                             sample_rate,
@@ -143,14 +191,23 @@ fn audio_input_thread(mut sender: Sender, receiver: mpsc::Receiver<Command>) {
                         let host = cpal::default_host();
                         let ring = ringbuf::HeapRb::<f32>::new(4096 * 2);
 
-                        let (device, consumer) = audio::get_input(&host, &config, ring);
+                        let (device, consumer, stream) = audio::get_input(&host, &config, ring);
+                        let processor = Processor::new(
+                            processing,
+                            (stream.sample_rate.0, stream.channels as u8),
+                            (sample_rate, channels),
+                        );
 
                         input = Some(Input {
                             encoded_pcm,
                             pcm_chunk,
                             encoder,
                             consumer,
+                            processor,
+                            captured: vec![0.0; 4096],
                             device,
+                            frame_duration,
+                            channels,
                         })
                     }
                     Command::Pause => {
@@ -163,7 +220,20 @@ fn audio_input_thread(mut sender: Sender, receiver: mpsc::Receiver<Command>) {
                             input.device.stream().play().unwrap();
                         }
                     }
-                    Command::PlayOpusAudio(_) => panic!("audio input can not play opus audio"),
+                    Command::PlayOpusAudio(..) => panic!("audio input can not play opus audio"),
+                    Command::SetTransmitMode(mode) => transmitter.set_mode(mode),
+                    Command::SetProcessing(config) => {
+                        processing = config;
+                        if let Some(input) = input.as_mut() {
+                            input.processor.set_config(config);
+                        }
+                    }
+                    Command::PushToTalk(active) => transmitter.set_push_to_talk(active),
+                    Command::Mute(muted) => transmitter.set_muted(muted),
+                    Command::Monitor(enabled) => monitor = enabled,
+                    Command::PlayMonitor { .. } | Command::SetPrioritySpeakers(..) => {
+                        panic!("audio input can not play audio")
+                    }
                 }
             }
             Err(mpsc::TryRecvError::Empty) => {}
@@ -171,23 +241,36 @@ fn audio_input_thread(mut sender: Sender, receiver: mpsc::Receiver<Command>) {
         }
 
         if let Some(input) = input.as_mut() {
-            let has_chunk = input.consumer.occupied_len() >= input.pcm_chunk.len();
+            let size = input.consumer.pop_slice(&mut input.captured);
+            input.processor.push(&input.captured[..size]);
 
-            if has_chunk {
+            while input
+                .processor
+                .next_frame(&mut input.pcm_chunk, input.frame_duration)
+            {
                 should_sleep = false;
-                let size = input.consumer.pop_slice(&mut input.pcm_chunk);
-                if size != input.pcm_chunk.len() {
-                    // TODO: did not have room to write
-                    panic!("did not have enough data to fill buffer from input")
+
+                if monitor {
+                    (sender)(Event::Monitor {
+                        pcm: input.pcm_chunk.clone(),
+                        channels: input.channels,
+                    })
                 }
 
-                let size = input
-                    .encoder
-                    .encode_f32(&input.pcm_chunk, &mut input.encoded_pcm)
-                    .unwrap();
+                if let Some(is_terminator) =
+                    transmitter.process(&input.pcm_chunk, input.frame_duration)
+                {
+                    let size = input
+                        .encoder
+                        .encode_f32(&input.pcm_chunk, &mut input.encoded_pcm)
+                        .unwrap();
 
-                let data = input.encoded_pcm[..size].to_vec();
-                (sender)(Event::Data(data))
+                    let data = input.encoded_pcm[..size].to_vec();
+                    (sender)(Event::Data {
+                        data,
+                        is_terminator,
+                    })
+                }
             }
         }
 
@@ -197,42 +280,210 @@ fn audio_input_thread(mut sender: Sender, receiver: mpsc::Receiver<Command>) {
     }
 }
 
+/// Speakers without audio for this long have their decoder dropped.
+const SPEAKER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Mixed audio kept queued for the output device.
+const OUTPUT_BUFFER: Duration = Duration::from_millis(30);
+
+struct Speaker {
+    decoder: libopus::Decoder,
+    jitter: JitterBuffer,
+    /// Samples in the last decoded frame, concealed frames are the same length.
+    last_size: usize,
+    last_audio: Instant,
+}
+
+impl Speaker {
+    /// Decode the next frame from the jitter buffer, returns the number of samples.
+    fn decode_next(&mut self, pcm: &mut [f32], frame_size: usize, starving: bool) -> Option<usize> {
+        let frame = self.jitter.pop(starving)?;
+        let concealed = self.last_size.min(pcm.len());
+        let decoded = match &frame {
+            Frame::Audio(data) => self.decoder.decode_f32(data, pcm, false),
+            Frame::Lost { fec: Some(data) } => {
+                self.decoder.decode_f32(data, &mut pcm[..concealed], true)
+            }
+            Frame::Lost { fec: None } => self.decoder.decode_f32(&[], &mut pcm[..concealed], false),
+        };
+
+        let size = decoded.unwrap_or_else(|e| {
+            tracing::debug!("failed to decode audio: {}", e);
+            0
+        });
+        if let Frame::Audio(_) = frame {
+            if size > 0 {
+                self.last_size = size;
+            }
+        }
+        self.jitter.advance((size / frame_size) as u64);
+        Some(size)
+    }
+}
+
 struct Output {
     device: audio::Output<Stream>,
-    decoder: libopus::Decoder,
+    config: audio::DeviceConfig,
     producer: audio::Producer,
+    speakers: HashMap<Session, Speaker>,
+    mixer: Mixer,
     decoded_pcm: Vec<f32>,
+    mixed_pcm: Vec<f32>,
+}
+
+impl Output {
+    /// Samples in one frame number of audio.
+    fn frame_size(&self) -> usize {
+        let per_channel =
+            self.config.sample_rate as usize * FRAME_DURATION.as_millis() as usize / 1000;
+        per_channel * self.config.channels as usize
+    }
+
+    /// Audio the mix keeps queued for the device.
+    fn target(&self) -> usize {
+        self.frame_size() * (OUTPUT_BUFFER.as_millis() / FRAME_DURATION.as_millis()) as usize
+    }
+
+    /// Queue the local microphone, converting it to the output's channels. Audio is
+    /// dropped while a full `OUTPUT_BUFFER` is waiting so monitoring does not fall behind.
+    fn push_monitor(&mut self, pcm: &[f32], channels: u8) {
+        if self.mixer.buffered(Source::Monitor) >= self.target() {
+            return;
+        }
+
+        let out_channels = self.config.channels as usize;
+        if channels as usize == out_channels {
+            self.mixer.push(Source::Monitor, pcm);
+            return;
+        }
+        let pcm: Vec<f32> = pcm
+            .chunks(channels.max(1) as usize)
+            .flat_map(|frame| {
+                let sample = frame.iter().sum::<f32>() / frame.len() as f32;
+                std::iter::repeat_n(sample, out_channels)
+            })
+            .collect();
+        self.mixer.push(Source::Monitor, &pcm);
+    }
+
+    /// Queue audio in the sender's jitter buffer, each speaker has their own decoder so
+    /// they do not share decoder state.
+    fn push(&mut self, msg: VoiceMessage, now: Instant) {
+        let frame_size = self.frame_size();
+        let speaker = match self.speakers.entry(msg.sender) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let decoder =
+                    match libopus::Decoder::new(self.config.sample_rate, self.config.channels) {
+                        Ok(d) => d,
+                        Err(e) => {
+                            tracing::warn!("failed to create decoder: {}", e);
+                            return;
+                        }
+                    };
+                e.insert(Speaker {
+                    decoder,
+                    jitter: JitterBuffer::new(),
+                    last_size: frame_size,
+                    last_audio: now,
+                })
+            }
+        };
+        speaker.last_audio = now;
+        speaker
+            .jitter
+            .push(msg.frame_number, msg.data, msg.is_terminator, now);
+    }
+
+    /// Decode and mix audio until the device has `OUTPUT_BUFFER` queued. Speakers that are
+    /// playing hold back the mix until their next frame arrives, or until the device is
+    /// about to run out and the frame is concealed.
+    fn mix(&mut self) {
+        use ringbuf::traits::{Observer as _, Producer as _};
+
+        let frame_size = self.frame_size();
+        let target = self.target();
+        let queued = self.producer.occupied_len();
+        let room = target
+            .saturating_sub(queued)
+            .min(self.producer.vacant_len())
+            .min(self.mixed_pcm.len());
+        if room == 0 {
+            return;
+        }
+
+        let mut size = room;
+        let mut playing = false;
+        for (session, speaker) in self.speakers.iter_mut() {
+            let source = Source::Speaker(*session);
+            while self.mixer.buffered(source) < room {
+                let starving = queued + self.mixer.buffered(source) < frame_size;
+                match speaker.decode_next(&mut self.decoded_pcm, frame_size, starving) {
+                    Some(n) => self.mixer.push(source, &self.decoded_pcm[..n]),
+                    None => break,
+                }
+            }
+
+            let buffered = self.mixer.buffered(source);
+            if speaker.jitter.is_playing() || buffered > 0 {
+                playing = true;
+                size = size.min(buffered);
+            }
+        }
+        if !playing {
+            // the monitor is padded while speakers play, alone it plays what it has
+            size = size.min(self.mixer.buffered(Source::Monitor));
+        }
+        if size == 0 {
+            return;
+        }
+
+        let size = self.mixer.mix(&mut self.mixed_pcm[..size]);
+        self.producer.push_slice(&self.mixed_pcm[..size]);
+    }
+
+    fn remove_idle_speakers(&mut self, now: Instant) {
+        let mixer = &mut self.mixer;
+        self.speakers.retain(|session, speaker| {
+            let idle = now.duration_since(speaker.last_audio) > SPEAKER_TIMEOUT;
+            if idle {
+                mixer.remove(Source::Speaker(*session));
+            }
+            !idle
+        });
+    }
 }
 
 fn audio_output_thread(mut sender: Sender, receiver: mpsc::Receiver<Command>) {
     use cpal::traits::StreamTrait as _;
-    use ringbuf::traits::Producer as _;
 
     let mut output: Option<Output> = None;
+    let mut priority_speakers = HashSet::new();
     let timeout = std::time::Duration::from_millis(5);
 
     loop {
-        match receiver.recv() {
+        match receiver.recv_timeout(timeout) {
             Ok(cmd) => match cmd {
                 Command::SetDevice(SetDevice { config }) => {
-                    let decoder =
-                        libopus::Decoder::new(config.sample_rate, config.channels).unwrap();
-
                     let host = cpal::default_host();
                     let ring = ringbuf::HeapRb::<f32>::new(4096 * 2);
 
                     let (device, producer) = audio::get_output(&host, &config, ring);
 
-                    // reuse buffer if it exists
-                    let decoded_pcm = output
-                        .map(|o| o.decoded_pcm)
-                        .unwrap_or_else(|| vec![0.0; 4096]);
+                    // reuse buffers if they exist, decoders are recreated for the new config
+                    let (decoded_pcm, mixed_pcm) = output
+                        .map(|o| (o.decoded_pcm, o.mixed_pcm))
+                        .unwrap_or_else(|| (vec![0.0; 4096], vec![0.0; 4096]));
+                    let mut mixer = Mixer::new();
+                    mixer.set_priority(priority_speakers.iter().map(|s| Source::Speaker(*s)));
 
                     output = Some(Output {
-                        decoder,
-                        producer,
                         device,
+                        config,
+                        producer,
+                        speakers: HashMap::new(),
+                        mixer,
                         decoded_pcm,
+                        mixed_pcm,
                     })
                 }
                 Command::Pause => {
@@ -245,21 +496,38 @@ fn audio_output_thread(mut sender: Sender, receiver: mpsc::Receiver<Command>) {
                         output.device.stream().play().unwrap();
                     }
                 }
-                Command::PlayOpusAudio(data) => {
+                Command::PlayOpusAudio(msg) => {
                     if let Some(output) = output.as_mut() {
-                        let size = output
-                            .decoder
-                            .decode_f32(&data, &mut output.decoded_pcm, false)
-                            .unwrap();
-
-                        let pushed_count = output.producer.push_slice(&output.decoded_pcm[..size]);
-                        if pushed_count != size {
-                            // did not push all of the items
-                        }
+                        output.push(msg, Instant::now());
+                    }
+                }
+                Command::PlayMonitor { pcm, channels } => {
+                    if let Some(output) = output.as_mut() {
+                        output.push_monitor(&pcm, channels);
+                    }
+                }
+                Command::SetPrioritySpeakers(speakers) => {
+                    if let Some(output) = output.as_mut() {
+                        let sources = speakers.iter().map(|s| Source::Speaker(*s));
+                        output.mixer.set_priority(sources);
                     }
+                    priority_speakers = speakers;
+                }
+                Command::SetTransmitMode(..)
+                | Command::SetProcessing(..)
+                | Command::PushToTalk(..)
+                | Command::Mute(..)
+                | Command::Monitor(..) => {
+                    panic!("audio output can not transmit")
                 }
             },
-            Err(mpsc::RecvError) => return,
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+
+        if let Some(output) = output.as_mut() {
+            output.mix();
+            output.remove_idle_speakers(Instant::now());
         }
     }
 }
@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+/// Quietest level in dBFS, silence is treated as this.
+const MIN_LEVEL: f32 = -96.0;
+/// How fast the noise floor rises in dB per second when the input is louder than it.
+const NOISE_FLOOR_RISE: f32 = 3.0;
+/// Speech keeps transmitting until it drops this many dB below the threshold.
+const HYSTERESIS: f32 = 6.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TransmitMode {
+    /// Send every captured frame.
+    #[default]
+    Continuous,
+    /// Send while the input is louder than the background noise.
+    VoiceActivity {
+        /// Signal to noise ratio in dB that starts transmitting.
+        threshold: f32,
+        /// Keep transmitting for this long after speech stops.
+        hold: Duration,
+    },
+    /// Send while push to talk is held.
+    PushToTalk,
+}
+
+/// Decides which captured frames are sent.
+#[derive(Debug)]
+pub struct Transmitter {
+    mode: TransmitMode,
+    push_to_talk: bool,
+    muted: bool,
+    transmitting: bool,
+    /// Estimated level of the background noise in dBFS.
+    noise_floor: f32,
+    /// Time since speech was last detected.
+    silent_for: Duration,
+}
+
+impl Transmitter {
+    pub fn new(mode: TransmitMode) -> Self {
+        Self {
+            mode,
+            push_to_talk: false,
+            muted: false,
+            transmitting: false,
+            // starts at full scale so the first frame sets it
+            noise_floor: 0.0,
+            silent_for: Duration::ZERO,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: TransmitMode) {
+        self.mode = mode;
+    }
+
+    pub fn set_push_to_talk(&mut self, active: bool) {
+        self.push_to_talk = active;
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    /// Returns None when the frame should not be sent, otherwise whether it is the last
+    /// frame of the utterance.
+    pub fn process(&mut self, pcm: &[f32], duration: Duration) -> Option<bool> {
+        let active = match self.mode {
+            TransmitMode::Continuous => true,
+            TransmitMode::PushToTalk => self.push_to_talk,
+            TransmitMode::VoiceActivity { threshold, hold } => {
+                self.detect(pcm, duration, threshold, hold)
+            }
+        };
+
+        match (self.transmitting, active && !self.muted) {
+            (_, true) => {
+                self.transmitting = true;
+                Some(false)
+            }
+            (true, false) => {
+                self.transmitting = false;
+                Some(true)
+            }
+            (false, false) => None,
+        }
+    }
+
+    fn detect(&mut self, pcm: &[f32], duration: Duration, threshold: f32, hold: Duration) -> bool {
+        let level = level(pcm);
+        // follow quiet input right away and rise slowly, so speech does not become the floor
+        let rise = NOISE_FLOOR_RISE * duration.as_secs_f32();
+        self.noise_floor = (self.noise_floor + rise).min(level);
+
+        let snr = level - self.noise_floor;
+        let speech = if self.transmitting {
+            snr > threshold - HYSTERESIS
+        } else {
+            snr > threshold
+        };
+        if speech {
+            self.silent_for = Duration::ZERO;
+            return true;
+        }
+
+        self.silent_for += duration;
+        self.transmitting && self.silent_for < hold
+    }
+}
+
+/// RMS level of `pcm` in dBFS.
+pub(super) fn level(pcm: &[f32]) -> f32 {
+    if pcm.is_empty() {
+        return MIN_LEVEL;
+    }
+    let power = pcm.iter().map(|s| s * s).sum::<f32>() / pcm.len() as f32;
+    (10.0 * power.log10()).max(MIN_LEVEL)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(10);
+
+    fn frame(amplitude: f32) -> Vec<f32> {
+        (0..480)
+            .map(|i| if i % 2 == 0 { amplitude } else { -amplitude })
+            .collect()
+    }
+
+    #[test]
+    fn test_push_to_talk() {
+        let mut t = Transmitter::new(TransmitMode::PushToTalk);
+        let pcm = frame(0.5);
+        assert_eq!(t.process(&pcm, FRAME), None);
+
+        t.set_push_to_talk(true);
+        assert_eq!(t.process(&pcm, FRAME), Some(false));
+        assert_eq!(t.process(&pcm, FRAME), Some(false));
+
+        t.set_push_to_talk(false);
+        assert_eq!(t.process(&pcm, FRAME), Some(true));
+        assert_eq!(t.process(&pcm, FRAME), None);
+    }
+
+    #[test]
+    fn test_voice_activity() {
+        let hold = Duration::from_millis(50);
+        let mut t = Transmitter::new(TransmitMode::VoiceActivity {
+            threshold: 12.0,
+            hold,
+        });
+
+        // background noise around -60 dBFS
+        let noise = frame(0.001);
+        for _ in 0..100 {
+            assert_eq!(t.process(&noise, FRAME), None);
+        }
+
+        assert_eq!(t.process(&frame(0.1), FRAME), Some(false));
+        // 10 dB above the noise is under the threshold but inside the hysteresis
+        assert_eq!(t.process(&frame(0.00316), FRAME), Some(false));
+
+        let mut sent = vec![];
+        for _ in 0..10 {
+            sent.push(t.process(&noise, FRAME));
+        }
+        let held = (hold.as_millis() / FRAME.as_millis()) as usize - 1;
+        assert_eq!(sent[..held], vec![Some(false); held]);
+        assert_eq!(sent[held], Some(true), "the last frame is a terminator");
+        assert!(sent[held + 1..].iter().all(|s| s.is_none()));
+
+        // starting again needs the full threshold
+        assert_eq!(t.process(&frame(0.00316), FRAME), None);
+    }
+
+    #[test]
+    fn test_continuous() {
+        let mut t = Transmitter::new(TransmitMode::Continuous);
+        assert_eq!(t.process(&[], FRAME), Some(false));
+
+        t.set_mode(TransmitMode::PushToTalk);
+        assert_eq!(t.process(&[], FRAME), Some(true));
+    }
+
+    #[test]
+    fn test_mute() {
+        let mut t = Transmitter::new(TransmitMode::Continuous);
+        assert_eq!(t.process(&[], FRAME), Some(false));
+
+        t.set_muted(true);
+        assert_eq!(
+            t.process(&[], FRAME),
+            Some(true),
+            "muting ends the utterance"
+        );
+        assert_eq!(t.process(&[], FRAME), None);
+
+        t.set_muted(false);
+        assert_eq!(t.process(&[], FRAME), Some(false));
+    }
+}
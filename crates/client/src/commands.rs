@@ -16,6 +16,13 @@ pub mod response {
     pub enum Response {
         Connect(Connect),
         Disconnect(Disconnect),
+        SendMessage(SendMessage),
+        MuteMic(MuteMic),
+        MonitorMic(MonitorMic),
+        AcceptCertificate(AcceptCertificate),
+        ImportIdentity(ImportIdentity),
+        ExportIdentity(ExportIdentity),
+        Stats(Stats),
     }
 
     impl Response {
@@ -23,6 +30,13 @@ pub mod response {
             match self {
                 Response::Connect(r) => r.cmd(),
                 Response::Disconnect(r) => r.cmd(),
+                Response::SendMessage(r) => r.cmd(),
+                Response::MuteMic(r) => r.cmd(),
+                Response::MonitorMic(r) => r.cmd(),
+                Response::AcceptCertificate(r) => r.cmd(),
+                Response::ImportIdentity(r) => r.cmd(),
+                Response::ExportIdentity(r) => r.cmd(),
+                Response::Stats(r) => r.cmd(),
             }
         }
     }
@@ -61,6 +75,125 @@ pub mod response {
             }
         }
     }
+
+    impl From<SendMessage> for Response {
+        fn from(val: SendMessage) -> Self {
+            Response::SendMessage(val)
+        }
+    }
+
+    impl TryFrom<Response> for SendMessage {
+        type Error = ();
+
+        fn try_from(value: Response) -> Result<Self, Self::Error> {
+            match value {
+                Response::SendMessage(val) => Ok(val),
+                _ => Err(()),
+            }
+        }
+    }
+
+    impl From<MuteMic> for Response {
+        fn from(val: MuteMic) -> Self {
+            Response::MuteMic(val)
+        }
+    }
+
+    impl TryFrom<Response> for MuteMic {
+        type Error = ();
+
+        fn try_from(value: Response) -> Result<Self, Self::Error> {
+            match value {
+                Response::MuteMic(val) => Ok(val),
+                _ => Err(()),
+            }
+        }
+    }
+
+    impl From<MonitorMic> for Response {
+        fn from(val: MonitorMic) -> Self {
+            Response::MonitorMic(val)
+        }
+    }
+
+    impl TryFrom<Response> for MonitorMic {
+        type Error = ();
+
+        fn try_from(value: Response) -> Result<Self, Self::Error> {
+            match value {
+                Response::MonitorMic(val) => Ok(val),
+                _ => Err(()),
+            }
+        }
+    }
+
+    impl From<AcceptCertificate> for Response {
+        fn from(val: AcceptCertificate) -> Self {
+            Response::AcceptCertificate(val)
+        }
+    }
+
+    impl TryFrom<Response> for AcceptCertificate {
+        type Error = ();
+
+        fn try_from(value: Response) -> Result<Self, Self::Error> {
+            match value {
+                Response::AcceptCertificate(val) => Ok(val),
+                _ => Err(()),
+            }
+        }
+    }
+
+    impl From<ImportIdentity> for Response {
+        fn from(val: ImportIdentity) -> Self {
+            Response::ImportIdentity(val)
+        }
+    }
+
+    impl TryFrom<Response> for ImportIdentity {
+        type Error = ();
+
+        fn try_from(value: Response) -> Result<Self, Self::Error> {
+            match value {
+                Response::ImportIdentity(val) => Ok(val),
+                _ => Err(()),
+            }
+        }
+    }
+
+    impl From<ExportIdentity> for Response {
+        fn from(val: ExportIdentity) -> Self {
+            Response::ExportIdentity(val)
+        }
+    }
+
+    impl TryFrom<Response> for ExportIdentity {
+        type Error = ();
+
+        fn try_from(value: Response) -> Result<Self, Self::Error> {
+            match value {
+                Response::ExportIdentity(val) => Ok(val),
+                _ => Err(()),
+            }
+        }
+    }
+
+    impl From<Stats> for Response {
+        fn from(val: Stats) -> Self {
+            Response::Stats(val)
+        }
+    }
+
+    impl TryFrom<Response> for Stats {
+        type Error = ();
+
+        fn try_from(value: Response) -> Result<Self, Self::Error> {
+            match value {
+                Response::Stats(val) => Ok(val),
+                _ => Err(()),
+            }
+        }
+    }
     // endregion:Response::from
 
     #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -107,8 +240,82 @@ pub mod response {
     impl ResponseFor for Disconnect {
         type Command = super::Disconnect;
     }
+
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+    #[derive(Clone, Debug)]
+    pub struct SendMessage;
+
+    impl ResponseFor for SendMessage {
+        type Command = super::SendMessage;
+    }
+
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+    #[derive(Clone, Debug)]
+    pub struct MuteMic {
+        pub mute: bool,
+    }
+
+    impl ResponseFor for MuteMic {
+        type Command = super::MuteMic;
+    }
+
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+    #[derive(Clone, Debug)]
+    pub struct MonitorMic {
+        pub monitor: bool,
+    }
+
+    impl ResponseFor for MonitorMic {
+        type Command = super::MonitorMic;
+    }
+
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+    #[derive(Clone, Debug)]
+    pub struct AcceptCertificate;
+
+    impl ResponseFor for AcceptCertificate {
+        type Command = super::AcceptCertificate;
+    }
+
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+    #[derive(Clone, Debug)]
+    pub struct ImportIdentity {
+        /// Fingerprint of the imported certificate.
+        pub fingerprint: String,
+    }
+
+    impl ResponseFor for ImportIdentity {
+        type Command = super::ImportIdentity;
+    }
+
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+    #[derive(Clone, Debug)]
+    pub struct ExportIdentity;
+
+    impl ResponseFor for ExportIdentity {
+        type Command = super::ExportIdentity;
+    }
+
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+    #[derive(Clone, Debug)]
+    pub struct Stats {
+        pub stats: crate::stats::ConnectionStats,
+    }
+
+    impl ResponseFor for Stats {
+        type Command = super::Stats;
+    }
 }
 
+use std::path::PathBuf;
+
 use crate::audio;
 pub use response::Response;
 
@@ -144,7 +351,17 @@ impl Cmd for SwitchChannel {
 }
 
 #[derive(Debug)]
-pub struct SendMessage {}
+pub struct SendMessage {
+    /// Users who should receive the message.
+    pub recipients: Vec<speakez::mumble::session::Session>,
+    /// Channels that should receive the message.
+    pub channels: Vec<speakez::common::ChannelID>,
+    pub message: String,
+}
+
+impl Cmd for SendMessage {
+    const NAME: &'static str = "send-message";
+}
 
 #[derive(Debug)]
 pub struct ChangeInput {
@@ -170,9 +387,88 @@ pub struct MuteMic {
     pub mute: bool,
 }
 
+impl Cmd for MuteMic {
+    const NAME: &'static str = "mute-mic";
+}
+
+/// Play the microphone through the output device.
 #[derive(Debug)]
 pub struct MonitorMic {
-    monitor: bool,
+    pub monitor: bool,
+}
+
+impl Cmd for MonitorMic {
+    const NAME: &'static str = "monitor-mic";
+}
+
+#[derive(Debug)]
+pub struct SetTransmitMode {
+    pub mode: audio::TransmitMode,
+}
+
+impl Cmd for SetTransmitMode {
+    const NAME: &'static str = "set-transmit-mode";
+}
+
+/// Gain, noise gate and automatic gain control applied to the microphone.
+#[derive(Debug)]
+pub struct SetInputProcessing {
+    pub processing: audio::InputProcessing,
+}
+
+impl Cmd for SetInputProcessing {
+    const NAME: &'static str = "set-input-processing";
+}
+
+/// Transmits while `active` when the transmit mode is push to talk.
+#[derive(Debug)]
+pub struct PushToTalk {
+    pub active: bool,
+}
+
+impl Cmd for PushToTalk {
+    const NAME: &'static str = "push-to-talk";
+}
+
+/// Trust a server certificate that changed since it was first seen.
+#[derive(Debug)]
+pub struct AcceptCertificate {
+    pub host: String,
+    pub fingerprint: String,
+}
+
+impl Cmd for AcceptCertificate {
+    const NAME: &'static str = "accept-certificate";
+}
+
+/// Replace the identity with one from a PKCS#12 file, it is used from the next connect.
+#[derive(Debug)]
+pub struct ImportIdentity {
+    pub path: PathBuf,
+    pub password: String,
+}
+
+impl Cmd for ImportIdentity {
+    const NAME: &'static str = "import-identity";
+}
+
+/// Write the identity to a PKCS#12 file protected by `password`.
+#[derive(Debug)]
+pub struct ExportIdentity {
+    pub path: PathBuf,
+    pub password: String,
+}
+
+impl Cmd for ExportIdentity {
+    const NAME: &'static str = "export-identity";
+}
+
+/// Latency and packet statistics of the connection.
+#[derive(Debug)]
+pub struct Stats;
+
+impl Cmd for Stats {
+    const NAME: &'static str = "stats";
 }
 
 #[derive(Debug)]
@@ -203,6 +499,13 @@ pub enum Command {
     MonitorMic(MonitorMic),
     SetInputDevice(SetInput),
     SetOutuptDevice(SetOutput),
+    SetTransmitMode(SetTransmitMode),
+    SetInputProcessing(SetInputProcessing),
+    PushToTalk(PushToTalk),
+    AcceptCertificate(AcceptCertificate),
+    ImportIdentity(ImportIdentity),
+    ExportIdentity(ExportIdentity),
+    Stats(Stats),
 }
 
 impl Command {
@@ -211,6 +514,16 @@ impl Command {
             Command::Connect(cmd) => cmd.name(),
             Command::Disconnect(cmd) => cmd.name(),
             Command::SwitchChannel(cmd) => cmd.name(),
+            Command::SendMessage(cmd) => cmd.name(),
+            Command::MuteMic(cmd) => cmd.name(),
+            Command::MonitorMic(cmd) => cmd.name(),
+            Command::SetTransmitMode(cmd) => cmd.name(),
+            Command::SetInputProcessing(cmd) => cmd.name(),
+            Command::PushToTalk(cmd) => cmd.name(),
+            Command::AcceptCertificate(cmd) => cmd.name(),
+            Command::ImportIdentity(cmd) => cmd.name(),
+            Command::ExportIdentity(cmd) => cmd.name(),
+            Command::Stats(cmd) => cmd.name(),
             _ => todo!(),
         }
     }
@@ -264,6 +577,48 @@ impl From<SetOutput> for Command {
         Command::SetOutuptDevice(val)
     }
 }
+
+impl From<SetTransmitMode> for Command {
+    fn from(val: SetTransmitMode) -> Self {
+        Command::SetTransmitMode(val)
+    }
+}
+
+impl From<SetInputProcessing> for Command {
+    fn from(val: SetInputProcessing) -> Self {
+        Command::SetInputProcessing(val)
+    }
+}
+
+impl From<PushToTalk> for Command {
+    fn from(val: PushToTalk) -> Self {
+        Command::PushToTalk(val)
+    }
+}
+
+impl From<AcceptCertificate> for Command {
+    fn from(val: AcceptCertificate) -> Self {
+        Command::AcceptCertificate(val)
+    }
+}
+
+impl From<ImportIdentity> for Command {
+    fn from(val: ImportIdentity) -> Self {
+        Command::ImportIdentity(val)
+    }
+}
+
+impl From<ExportIdentity> for Command {
+    fn from(val: ExportIdentity) -> Self {
+        Command::ExportIdentity(val)
+    }
+}
+
+impl From<Stats> for Command {
+    fn from(val: Stats) -> Self {
+        Command::Stats(val)
+    }
+}
 // endregion:Command::from
 
 #[test]
@@ -0,0 +1,317 @@
+//! The certificate a client authenticates with, servers use it to recognise registered
+//! users across sessions.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write as _};
+use std::path::Path;
+use std::sync::Arc;
+
+use p12_keystore::{Certificate, KeyStore, KeyStoreEntry, PrivateKey, PrivateKeyChain};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::sign::CertifiedKey;
+
+/// The name of the key in exported PKCS#12 files.
+const ALIAS: &str = "speakez";
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    Certificate(rcgen::Error),
+    /// The data is not valid PKCS#12 or uses an algorithm that is not supported.
+    Pkcs12(p12_keystore::error::Error),
+    /// The PKCS#12 data has no private key with a certificate for it.
+    MissingKey,
+    /// The password did not match.
+    BadPassword,
+    Tls(rustls::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IO(err) => write!(f, "IO error: {err}"),
+            Error::Certificate(err) => write!(f, "certificate error: {err}"),
+            Error::Pkcs12(err) => write!(f, "invalid identity: {err}"),
+            Error::MissingKey => write!(f, "invalid identity: no private key"),
+            Error::BadPassword => write!(f, "wrong password for identity"),
+            Error::Tls(err) => write!(f, "TLS error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IO(err)
+    }
+}
+
+impl From<rcgen::Error> for Error {
+    fn from(err: rcgen::Error) -> Self {
+        Error::Certificate(err)
+    }
+}
+
+impl From<p12_keystore::error::Error> for Error {
+    fn from(err: p12_keystore::error::Error) -> Self {
+        match err {
+            p12_keystore::error::Error::MacError(_) => Error::BadPassword,
+            err => Error::Pkcs12(err),
+        }
+    }
+}
+
+impl From<rustls::Error> for Error {
+    fn from(err: rustls::Error) -> Self {
+        Error::Tls(err)
+    }
+}
+
+/// A self signed certificate and its private key.
+pub struct Identity {
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl Identity {
+    /// Generate an ECDSA P-256 key and a certificate for it with `name` as the common
+    /// name. The certificate is valid until 4096.
+    pub fn generate(name: &str) -> Result<Self, Error> {
+        let key_pair = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+        let mut params = rcgen::CertificateParams::default();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        let cert = params.self_signed(&key_pair)?;
+
+        Ok(Self {
+            cert: cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(key_pair.serialize_der()),
+        })
+    }
+
+    pub fn certificate(&self) -> &CertificateDer<'static> {
+        &self.cert
+    }
+
+    /// SHA-256 fingerprint of the certificate, formatted like server fingerprints.
+    pub fn fingerprint(&self) -> String {
+        crate::tls::tofu::fingerprint(&self.cert)
+    }
+
+    /// Export as PKCS#12 protected by `password`, encrypted with PBES2 and AES-256.
+    pub fn to_pkcs12(&self, password: &str) -> Result<Vec<u8>, Error> {
+        let key_id =
+            aws_lc_rs::digest::digest(&aws_lc_rs::digest::SHA1_FOR_LEGACY_USE_ONLY, &self.cert);
+        let chain = PrivateKeyChain::new(
+            key_id.as_ref(),
+            PrivateKey::from_der(self.key.secret_pkcs8_der())?,
+            [Certificate::from_der(&self.cert)?],
+        );
+
+        let mut keystore = KeyStore::new();
+        keystore.add_entry(ALIAS, KeyStoreEntry::PrivateKeyChain(chain));
+        Ok(keystore.writer(password).write()?)
+    }
+
+    /// Import from PKCS#12, the key has to be one rustls can sign with. Files from older
+    /// clients encrypted with 3DES and RC2 are accepted.
+    pub fn from_pkcs12(data: &[u8], password: &str) -> Result<Self, Error> {
+        let keystore =
+            KeyStore::from_pkcs12(data, password, p12_keystore::Pkcs12ImportPolicy::Strict)?;
+        let (_, chain) = keystore.private_key_chain().ok_or(Error::MissingKey)?;
+        let cert = chain.certs().first().ok_or(Error::MissingKey)?;
+
+        let identity = Self {
+            cert: CertificateDer::from(cert.as_der().to_vec()),
+            key: PrivatePkcs8KeyDer::from(chain.key().as_der().to_vec()),
+        };
+        identity.certified_key()?;
+        Ok(identity)
+    }
+
+    /// Load an identity file, which is PKCS#12 without a password.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::from_pkcs12(&std::fs::read(path)?, "")
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        self.export(path, "")
+    }
+
+    /// Write as PKCS#12 protected by `password`. Only the current user can read the file.
+    pub fn export(&self, path: impl AsRef<Path>, password: &str) -> Result<(), Error> {
+        write_private(path.as_ref(), &self.to_pkcs12(password)?)?;
+        Ok(())
+    }
+
+    /// Load the identity file at `path`, generating and saving a new identity for
+    /// `name` when it does not exist.
+    pub fn load_or_generate(path: impl AsRef<Path>, name: &str) -> Result<Self, Error> {
+        let path = path.as_ref();
+        match Self::load(path) {
+            Err(Error::IO(e)) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Self::generate(name)?;
+                identity.save(path)?;
+                Ok(identity)
+            }
+            res => res,
+        }
+    }
+
+    /// The certificate and signing key for TLS client authentication.
+    pub fn certified_key(&self) -> Result<Arc<CertifiedKey>, Error> {
+        let key = PrivateKeyDer::Pkcs8(self.key.clone_key());
+        let key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&key)?;
+        Ok(Arc::new(CertifiedKey::new(vec![self.cert.clone()], key)))
+    }
+}
+
+impl Clone for Identity {
+    fn clone(&self) -> Self {
+        Self {
+            cert: self.cert.clone(),
+            key: self.key.clone_key(),
+        }
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("fingerprint", &self.fingerprint())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Write `data` to a new file only the current user can read, which then replaces
+/// `path`. A crash leaves either the old file or the new one, never part of a key.
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+    // a leftover file could have been created with other permissions
+    match std::fs::remove_file(tmp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A P-256 key exported by `openssl pkcs12 -export` with the password `speakez`.
+    const OPENSSL_P12: &[u8] = include_bytes!("testdata/openssl.p12");
+    /// An RSA key exported by `openssl pkcs12 -export -legacy` without a password, the
+    /// 3DES and RC2 encryption older Mumble clients use.
+    const LEGACY_P12: &[u8] = include_bytes!("testdata/legacy.p12");
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("speakez-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_generate() {
+        let identity = Identity::generate("alice").unwrap();
+
+        let cert = Certificate::from_der(identity.certificate()).unwrap();
+        assert_eq!(cert.subject(), "CN=alice");
+        assert_eq!(cert.issuer(), "CN=alice");
+        assert!(identity.certified_key().is_ok());
+    }
+
+    #[test]
+    fn test_pkcs12() {
+        let identity = Identity::generate("bob").unwrap();
+        let data = identity.to_pkcs12("secret").unwrap();
+
+        let imported = Identity::from_pkcs12(&data, "secret").unwrap();
+        assert_eq!(imported.certificate(), identity.certificate());
+        assert_eq!(imported.fingerprint(), identity.fingerprint());
+        assert!(matches!(
+            Identity::from_pkcs12(&data, "wrong"),
+            Err(Error::BadPassword)
+        ));
+    }
+
+    #[test]
+    fn test_import_openssl() {
+        let identity = Identity::from_pkcs12(OPENSSL_P12, "speakez").unwrap();
+        assert_eq!(
+            identity.fingerprint(),
+            "ea7a1f8b2d10e74ceb769a48051c4cf953c358212d0da426e3528c9f2d7e0c47"
+        );
+        assert!(matches!(
+            Identity::from_pkcs12(OPENSSL_P12, ""),
+            Err(Error::BadPassword)
+        ));
+    }
+
+    #[test]
+    fn test_import_legacy() {
+        let identity = Identity::from_pkcs12(LEGACY_P12, "").unwrap();
+        assert_eq!(
+            identity.fingerprint(),
+            "eed6677eb1e40b20ce3b614fabb84f9dbc5a1ba09d32424a80e1bf43ccf98fc1"
+        );
+    }
+
+    #[test]
+    fn test_export_openssl() {
+        let path = temp_path("export");
+        let identity = Identity::generate("erin").unwrap();
+        identity.export(&path, "secret").unwrap();
+
+        let output = std::process::Command::new("openssl")
+            .args(["pkcs12", "-nodes", "-passin", "pass:secret", "-in"])
+            .arg(&path)
+            .output();
+        std::fs::remove_file(&path).unwrap();
+        let Ok(output) = output else {
+            eprintln!("openssl is not installed, skipping");
+            return;
+        };
+
+        assert!(output.status.success(), "{output:?}");
+        let pem = String::from_utf8(output.stdout).unwrap();
+        assert!(pem.contains("BEGIN CERTIFICATE"));
+        assert!(pem.contains("BEGIN PRIVATE KEY"));
+    }
+
+    #[test]
+    fn test_load_or_generate() {
+        let path = temp_path("identity");
+        let _ = std::fs::remove_file(&path);
+
+        let generated = Identity::load_or_generate(&path, "carol").unwrap();
+        let loaded = Identity::load_or_generate(&path, "dave").unwrap();
+        assert_eq!(loaded.fingerprint(), generated.fingerprint());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
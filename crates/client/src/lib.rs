@@ -1,19 +1,35 @@
 pub mod audio;
 pub mod commands;
+pub mod identity;
 pub mod mumble;
 pub mod network;
+mod reconnect;
+pub mod stats;
 pub mod tls;
+pub mod voice;
 
 pub use speakez;
 
-use std::{collections::VecDeque, sync::Arc};
+use std::{
+    collections::{HashSet, VecDeque},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
 use audio::DeviceConfig;
+use prost::Message as _;
 use speakez::{
-    common::{events::UserSwitchedChannel, ChannelID},
-    mumble::control::{
-        proto::{self},
-        Message as _, MessageBuf,
+    common::{
+        events::{UserSentMessage, UserSwitchedChannel},
+        ChannelID,
+    },
+    mumble::{
+        control::{
+            proto::{self},
+            Message as _, MessageBuf,
+        },
+        session::Session,
     },
 };
 
@@ -25,12 +41,28 @@ pub mod outgoing {
     #[derive(Clone, Debug)]
     pub enum Event {
         Speakez(speakez::common::events::Event),
+        /// The connection dropped, `attempt` starts after `delay_ms` milliseconds.
+        Reconnecting {
+            attempt: u32,
+            delay_ms: u64,
+        },
+        Reconnected,
+        /// The server's certificate does not match the one stored for it, the connection
+        /// is refused until it is accepted with `AcceptCertificate`.
+        CertificateChanged {
+            host: String,
+            known: String,
+            fingerprint: String,
+        },
+        /// Sent after each TCP ping reply.
+        Stats(crate::stats::ConnectionStats),
     }
 }
 
 #[derive(Debug)]
 pub enum Event {
-    InputData(Vec<u8>),
+    InputData { data: Vec<u8>, is_terminator: bool },
+    MonitorData { pcm: Vec<f32>, channels: u8 },
     Control(network::events::Message<MessageBuf>),
     Voice(network::events::Message<Vec<u8>>),
     Tick(),
@@ -65,7 +97,7 @@ pub struct Config {
     pub latency: f32,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct Auth {
     username: String,
     password: String,
@@ -149,6 +181,7 @@ pub struct Settings {
     input: Option<String>,
     output: Option<String>,
 
+    /// Microphone gain in dB, input processing starts with it.
     input_gain: f32,
     input_monitor: bool,
 }
@@ -203,6 +236,12 @@ impl Commands {
         let msg = commands::response::Message::err(tag, err);
         self.messages.push(msg);
     }
+
+    fn push_command_error(&mut self, cmd: &'static str, err: String) {
+        if let Some(tag) = self.get_next_tag(cmd) {
+            self.push_error_response(tag, err);
+        }
+    }
 }
 
 fn complete_response(state: &speakez::client::State) -> commands::Response {
@@ -229,6 +268,22 @@ pub struct Client {
     commands: Commands,
     network: network::State,
     audio: audio::State,
+    /// Set after the handshake when the server sent keys for UDP voice.
+    udp: Option<voice::Udp>,
+    /// The server and credentials from the last `Connect`, used to reconnect.
+    connection: Option<(String, Auth)>,
+    /// Set while trying to restore a dropped connection.
+    reconnect: Option<reconnect::Reconnect>,
+    outgoing: Vec<outgoing::Event>,
+    /// Shared with the `TofuVerifier` in the TLS config.
+    known_hosts: Option<Arc<Mutex<tls::tofu::KnownHosts>>>,
+    /// The file the identity is stored in, imported identities replace it.
+    identity_path: Option<PathBuf>,
+    /// The certificate used for TLS client auth.
+    identity: Option<identity::Identity>,
+    stats: stats::Stats,
+    /// Users whose audio ducks everyone else, from their `UserState`.
+    priority_speakers: HashSet<Session>,
 }
 
 impl Client {
@@ -249,26 +304,89 @@ impl Client {
         let input = move |event| {
             let event = match event {
                 audio::state::Event::Error(e) => panic!("audio input error: {}", e),
-                audio::state::Event::Data(d) => Event::InputData(d),
+                audio::state::Event::Data {
+                    data,
+                    is_terminator,
+                } => Event::InputData {
+                    data,
+                    is_terminator,
+                },
+                audio::state::Event::Monitor { pcm, channels } => {
+                    Event::MonitorData { pcm, channels }
+                }
             };
             s(Message::Event(event)).unwrap();
         };
 
         let output = move |event| match event {
             audio::state::Event::Error(e) => panic!("audio output error: {}", e),
-            audio::state::Event::Data(d) => panic!("output should not be sending data"),
+            audio::state::Event::Data { .. } | audio::state::Event::Monitor { .. } => {
+                panic!("output should not be sending data")
+            }
         };
 
+        let settings = Settings::default();
+        let audio_state = audio::State::new(Box::new(input), Box::new(output));
+        let processing = audio::InputProcessing {
+            gain: settings.input_gain,
+            ..Default::default()
+        };
+        audio_state
+            .input
+            .send(audio::state::Command::SetProcessing(processing));
+
         Self {
             sender,
-            settings: Settings::default(),
+            settings,
             state: State::NotConnected,
             commands: Commands::new(),
-            audio: audio::State::new(Box::new(input), Box::new(output)),
+            audio: audio_state,
             network: network::State::new(cfg, Box::new(control), Box::new(voice)),
+            udp: None,
+            connection: None,
+            reconnect: None,
+            outgoing: Vec::new(),
+            known_hosts: None,
+            identity_path: None,
+            identity: None,
+            stats: stats::Stats::new(Instant::now()),
+            priority_speakers: HashSet::new(),
         }
     }
 
+    /// Use `known_hosts` for `AcceptCertificate`, it should be the one given to the
+    /// `TofuVerifier` in the TLS config.
+    pub fn set_known_hosts(&mut self, known_hosts: Arc<Mutex<tls::tofu::KnownHosts>>) {
+        self.known_hosts = Some(known_hosts);
+    }
+
+    /// Authenticate with the identity stored at `path`, a new one is generated for
+    /// `name` when there is none yet. Imported identities replace the file, even when
+    /// loading it failed.
+    pub fn load_identity(
+        &mut self,
+        path: impl Into<PathBuf>,
+        name: &str,
+    ) -> Result<(), identity::Error> {
+        let path = self.identity_path.insert(path.into());
+        let identity = identity::Identity::load_or_generate(path, name)?;
+        self.set_identity(identity)
+    }
+
+    pub fn identity(&self) -> Option<&identity::Identity> {
+        self.identity.as_ref()
+    }
+
+    fn set_identity(&mut self, identity: identity::Identity) -> Result<(), identity::Error> {
+        let key = identity.certified_key()?;
+        tracing::info!("using identity {}", identity.fingerprint());
+        self.network
+            .control
+            .send(network::Command::SetIdentity(Some(key)), None);
+        self.identity = Some(identity);
+        Ok(())
+    }
+
     fn network_disconnected(&mut self) {
         if self.network.all_disconnected() {
             let resp = commands::Response::Disconnect(commands::response::Disconnect);
@@ -294,10 +412,13 @@ impl Client {
     }
 
     fn connect(&mut self, cmd: commands::Connect, tag: Option<commands::Tag>) {
-        self.state = State::WaitingForConnection(Auth {
+        let auth = Auth {
             username: cmd.user,
             password: cmd.pass,
-        });
+        };
+        self.network.server_addr = Some(cmd.addr.clone());
+        self.connection = Some((cmd.addr.clone(), auth.clone()));
+        self.state = State::WaitingForConnection(auth);
 
         let network_cmd = network::commands::Connect { addr: cmd.addr };
         let cmd = network::Command::Connect(network_cmd);
@@ -305,15 +426,216 @@ impl Client {
     }
 
     fn disconnect(&mut self) {
+        self.connection = None;
         if let State::NotConnected = self.state {
+            if self.reconnect.take().is_some() {
+                // nothing is connected while waiting for the next attempt
+                let resp = commands::Response::Disconnect(commands::response::Disconnect);
+                self.commands.push_response(resp);
+            }
             return;
         }
+        self.reconnect = None;
 
         let cmd = network::Command::Disconnect;
         self.network.control.send(cmd.clone(), None);
         self.network.voice.send(cmd, None);
 
         self.state = State::NotConnected;
+        self.udp = None;
+        self.network.tunnel_voice = true;
+    }
+
+    /// The control connection dropped without being asked to. Connections that finished
+    /// the handshake, or are being restored, are retried with a growing delay.
+    fn connection_lost(&mut self, now: Instant) {
+        let was_connected = matches!(self.state, State::Connected { .. });
+        let channel = self.get_state_mut().map(|s| s.get_self().channel);
+        self.state = State::NotConnected;
+
+        if (!was_connected && self.reconnect.is_none()) || self.connection.is_none() {
+            self.network_disconnected();
+            return;
+        }
+
+        let reconnect = self
+            .reconnect
+            .get_or_insert_with(|| reconnect::Reconnect::new(channel, now));
+        let delay = reconnect.schedule(now);
+        let attempt = reconnect.attempt() + 1;
+        tracing::info!(
+            "connection lost, reconnect attempt {} in {:?}",
+            attempt,
+            delay
+        );
+        self.outgoing.push(outgoing::Event::Reconnecting {
+            attempt,
+            delay_ms: delay.as_millis() as u64,
+        });
+    }
+
+    /// Start a reconnect attempt once its delay has passed.
+    fn poll_reconnect(&mut self, now: Instant) {
+        let Some(reconnect) = &mut self.reconnect else {
+            return;
+        };
+        if !reconnect.poll(now) {
+            return;
+        }
+        let Some((addr, auth)) = self.connection.clone() else {
+            return;
+        };
+
+        tracing::info!("reconnecting to {}, attempt {}", addr, reconnect.attempt());
+        self.state = State::WaitingForConnection(auth);
+        let cmd = network::Command::Connect(network::commands::Connect { addr });
+        self.network.control.send(cmd, None);
+    }
+
+    /// Rejoin the channel we were in before the connection dropped.
+    fn handshake_complete(&mut self) {
+        let Some(reconnect) = self.reconnect.take() else {
+            return;
+        };
+        let current = self.get_state_mut().map(|s| s.get_self().channel);
+        if let Some(channel) = reconnect.channel.filter(|c| Some(*c) != current) {
+            self.switch_channel(channel);
+        }
+        tracing::info!("reconnected after {} attempts", reconnect.attempt());
+        self.outgoing.push(outgoing::Event::Reconnected);
+    }
+
+    /// Open the UDP socket once the handshake has finished.
+    fn start_udp(&mut self, setup: proto::CryptSetup) {
+        let Some(udp) = voice::Udp::new(&setup) else {
+            tracing::warn!("invalid CryptSetup from server, voice will be tunneled");
+            return;
+        };
+        self.udp = Some(udp);
+
+        if let Some(addr) = self.network.server_addr.clone() {
+            let cmd = network::Command::Connect(network::commands::Connect { addr });
+            self.network.voice.send(cmd, None);
+        }
+    }
+
+    fn handle_crypt_setup(&mut self, m: MessageBuf) {
+        let Some(udp) = &mut self.udp else { return };
+        let setup = match proto::CryptSetup::decode(m.body()) {
+            Ok(setup) => setup,
+            Err(e) => {
+                tracing::warn!("invalid CryptSetup: {}", e);
+                return;
+            }
+        };
+        if let Some(reply) = udp.handle_crypt_setup(setup) {
+            let cmd = network::Command::Send(reply.as_vec());
+            self.network.control.send(cmd, None);
+        }
+    }
+
+    fn handle_voice_data(&mut self, data: Vec<u8>) {
+        let Some(udp) = &mut self.udp else { return };
+        match udp.decrypt(&data) {
+            Some(speakez::mumble::voice::Message::Audio(audio)) => {
+                self.stats.udp_packets += 1;
+                let Some(state) = self.get_state_mut() else {
+                    return;
+                };
+                if let Some(msg) = speakez::common::events::mumble_voice_to_event(audio) {
+                    let event = speakez::common::events::Event::UserSentAudio(msg);
+                    state.outbox.push(event);
+                }
+            }
+            Some(speakez::mumble::voice::Message::Ping(ping)) => {
+                if let Some(rtt) = udp.round_trip(&ping, Instant::now()) {
+                    self.stats.handle_udp_ping(rtt);
+                }
+                self.update_voice_transport();
+            }
+            None => {}
+        }
+    }
+
+    /// Tunnel voice over the control connection unless UDP pings are being answered.
+    fn update_voice_transport(&mut self) {
+        let tunnel = !self.udp.as_ref().is_some_and(|udp| udp.is_working());
+        if tunnel != self.network.tunnel_voice {
+            tracing::info!("tunneling voice over TCP: {}", tunnel);
+            self.network.tunnel_voice = tunnel;
+        }
+    }
+
+    fn handle_tick(&mut self, now: Instant) {
+        self.poll_reconnect(now);
+        if self.get_state_mut().is_some() {
+            let crypt = self.udp.as_ref().map(|udp| udp.crypt_stats());
+            if let Some(ping) = self.stats.ping(now, crypt) {
+                let cmd = network::Command::Send(ping.as_vec());
+                self.network.control.send(cmd, None);
+            }
+        }
+        if !self.network.voice_connected {
+            return;
+        }
+        let Some(udp) = &mut self.udp else { return };
+        if let Some(ping) = udp.ping(now) {
+            self.network.voice.send(network::Command::Send(ping), None);
+        }
+        self.update_voice_transport();
+    }
+
+    fn handle_tcp_ping(&mut self, m: MessageBuf) {
+        let ping = match proto::Ping::decode(m.body()) {
+            Ok(ping) => ping,
+            Err(e) => {
+                tracing::warn!("invalid Ping: {}", e);
+                return;
+            }
+        };
+        self.stats.handle_tcp_ping(&ping, Instant::now());
+        self.outgoing
+            .push(outgoing::Event::Stats(self.connection_stats()));
+    }
+
+    /// Follow priority speakers in `UserState` and `UserRemove` messages, the output ducks
+    /// everyone else while they talk.
+    fn track_priority_speakers(&mut self, m: &MessageBuf) {
+        use speakez::mumble::control::MessageType;
+
+        let changed = match m.typ {
+            MessageType::UserState => {
+                let Ok(state) = proto::UserState::decode(m.body()) else {
+                    return;
+                };
+                let session = state.session.and_then(Session::new);
+                match (session, state.priority_speaker) {
+                    (Some(session), Some(true)) => self.priority_speakers.insert(session),
+                    (Some(session), Some(false)) => self.priority_speakers.remove(&session),
+                    _ => false,
+                }
+            }
+            MessageType::UserRemove => {
+                let Ok(remove) = proto::UserRemove::decode(m.body()) else {
+                    return;
+                };
+                Session::new(remove.session).is_some_and(|s| self.priority_speakers.remove(&s))
+            }
+            _ => false,
+        };
+        if changed {
+            self.send_priority_speakers();
+        }
+    }
+
+    fn send_priority_speakers(&self) {
+        let cmd = audio::state::Command::SetPrioritySpeakers(self.priority_speakers.clone());
+        self.audio.output.send(cmd);
+    }
+
+    fn connection_stats(&self) -> stats::ConnectionStats {
+        let crypt = self.udp.as_ref().map(|udp| udp.crypt_stats());
+        self.stats.snapshot(crypt, !self.network.tunnel_voice)
     }
 
     fn switch_channel(&mut self, to_channel: ChannelID) {
@@ -334,6 +656,143 @@ impl Client {
         self.network.control.send(cmd, None);
     }
 
+    fn send_message(&mut self, cmd: commands::SendMessage) {
+        use commands::Cmd as _;
+
+        let Some(state) = self.get_state_mut() else {
+            let err = "not connected".to_string();
+            self.commands.push_command_error(cmd.name(), err);
+            return;
+        };
+        if cmd.recipients.is_empty() && cmd.channels.is_empty() {
+            let err = "message has no recipients or channels".to_string();
+            self.commands.push_command_error(cmd.name(), err);
+            return;
+        }
+
+        let m = UserSentMessage {
+            user: state.session,
+            recipients: cmd.recipients,
+            channels: cmd.channels,
+            message: cmd.message,
+        }
+        .into_mumble();
+
+        let cmd = network::Command::Send(m.as_vec());
+        self.network.control.send(cmd, None);
+        self.commands
+            .push_response(commands::response::SendMessage.into());
+    }
+
+    fn accept_certificate(&mut self, cmd: commands::AcceptCertificate) {
+        use commands::Cmd as _;
+
+        let Some(known_hosts) = &self.known_hosts else {
+            let err = "known hosts are not configured".to_string();
+            self.commands.push_command_error(cmd.name(), err);
+            return;
+        };
+        let name = cmd.name();
+        let res = known_hosts
+            .lock()
+            .unwrap()
+            .insert(cmd.host, cmd.fingerprint);
+        match res {
+            Ok(()) => self
+                .commands
+                .push_response(commands::response::AcceptCertificate.into()),
+            Err(e) => self.commands.push_command_error(name, e.to_string()),
+        }
+    }
+
+    fn import_identity(&mut self, cmd: commands::ImportIdentity) {
+        use commands::Cmd as _;
+
+        let name = cmd.name();
+        let Some(path) = self.identity_path.clone() else {
+            let err = "identity file is not configured".to_string();
+            self.commands.push_command_error(name, err);
+            return;
+        };
+        let res = std::fs::read(&cmd.path)
+            .map_err(identity::Error::from)
+            .and_then(|data| identity::Identity::from_pkcs12(&data, &cmd.password))
+            .and_then(|identity| {
+                identity.save(&path)?;
+                let fingerprint = identity.fingerprint();
+                self.set_identity(identity)?;
+                Ok(fingerprint)
+            });
+        match res {
+            Ok(fingerprint) => self
+                .commands
+                .push_response(commands::response::ImportIdentity { fingerprint }.into()),
+            Err(e) => self.commands.push_command_error(name, e.to_string()),
+        }
+    }
+
+    fn export_identity(&mut self, cmd: commands::ExportIdentity) {
+        use commands::Cmd as _;
+
+        let name = cmd.name();
+        let Some(identity) = self.identity() else {
+            let err = "no identity to export".to_string();
+            self.commands.push_command_error(name, err);
+            return;
+        };
+        let res = identity.export(&cmd.path, &cmd.password);
+        match res {
+            Ok(()) => self
+                .commands
+                .push_response(commands::response::ExportIdentity.into()),
+            Err(e) => self.commands.push_command_error(name, e.to_string()),
+        }
+    }
+
+    /// A certificate changed since it was first seen, stop reconnecting and let the
+    /// user decide whether to trust it.
+    fn certificate_changed(&mut self, changed: &tls::tofu::FingerprintChanged) {
+        use commands::Cmd as _;
+
+        tracing::warn!("{}", changed);
+        self.reconnect = None;
+        self.commands
+            .push_command_error(commands::Connect::NAME, changed.to_string());
+        self.outgoing.push(outgoing::Event::CertificateChanged {
+            host: changed.host.clone(),
+            known: changed.known.clone(),
+            fingerprint: changed.fingerprint.clone(),
+        });
+    }
+
+    /// Muting stops encoding and tells the server we muted ourselves.
+    fn mute_mic(&mut self, mute: bool) {
+        self.audio.input_muted = mute;
+        self.audio.input.send(audio::state::Command::Mute(mute));
+
+        if let Some(session) = self.get_state_mut().map(|s| s.session) {
+            let m = proto::UserState {
+                session: Some(session.into()),
+                actor: Some(session.into()),
+                self_mute: Some(mute),
+                ..Default::default()
+            };
+            let cmd = network::Command::Send(m.as_vec());
+            self.network.control.send(cmd, None);
+        }
+        self.commands
+            .push_response(commands::response::MuteMic { mute }.into());
+    }
+
+    fn monitor_mic(&mut self, monitor: bool) {
+        self.settings.input_monitor = monitor;
+        self.audio
+            .input
+            .send(audio::state::Command::Monitor(monitor));
+        self.commands
+            .push_response(commands::response::MonitorMic { monitor }.into());
+    }
+
     fn handle_command(&mut self, tag: Option<commands::Tag>, cmd: Command) {
         if let Some(tag) = tag {
             self.commands.push_tag(cmd.name(), tag);
@@ -346,9 +805,9 @@ impl Client {
             }
             Command::Disconnect(..) => self.disconnect(),
             Command::SwitchChannel(cmd) => self.switch_channel(cmd.channel_id),
-            Command::SendMessage(send_message) => todo!(),
-            Command::MuteMic(mute_mic) => todo!(),
-            Command::MonitorMic(monitor_mic) => todo!(),
+            Command::SendMessage(cmd) => self.send_message(cmd),
+            Command::MuteMic(cmd) => self.mute_mic(cmd.mute),
+            Command::MonitorMic(cmd) => self.monitor_mic(cmd.monitor),
             Command::SetInputDevice(d) => {
                 let cmd = audio::state::Command::SetDevice(d.cfg);
                 self.audio.input.send(cmd);
@@ -357,10 +816,31 @@ impl Client {
                 let cmd = audio::state::Command::SetDevice(d.cfg);
                 self.audio.output.send(cmd);
             }
+            Command::SetTransmitMode(cmd) => {
+                let cmd = audio::state::Command::SetTransmitMode(cmd.mode);
+                self.audio.input.send(cmd);
+            }
+            Command::SetInputProcessing(cmd) => {
+                self.settings.input_gain = cmd.processing.gain;
+                let cmd = audio::state::Command::SetProcessing(cmd.processing);
+                self.audio.input.send(cmd);
+            }
+            Command::PushToTalk(cmd) => {
+                let cmd = audio::state::Command::PushToTalk(cmd.active);
+                self.audio.input.send(cmd);
+            }
+            Command::AcceptCertificate(cmd) => self.accept_certificate(cmd),
+            Command::ImportIdentity(cmd) => self.import_identity(cmd),
+            Command::ExportIdentity(cmd) => self.export_identity(cmd),
+            Command::Stats(..) => {
+                let stats = self.connection_stats();
+                self.commands
+                    .push_response(commands::response::Stats { stats }.into());
+            }
         }
     }
 
-    fn handle_audio_input(&mut self, encoded_pcm: Vec<u8>) {
+    fn handle_audio_input(&mut self, encoded_pcm: Vec<u8>, is_terminator: bool) {
         let state = match self.state {
             State::Connected { ref mut state, .. } => state,
             _ => return,
@@ -371,6 +851,8 @@ impl Client {
             frame_number: self.audio.frame_number,
             data: encoded_pcm,
             sender: state.session,
+            target: 0,
+            is_terminator,
         }
         .into();
 
@@ -390,15 +872,24 @@ impl Client {
 
             let cmd = network::Command::Send(buf);
             self.network.control.send(cmd, None);
-        } else {
-            // encrypt data
-            // send via voice
+        } else if let Some(udp) = &mut self.udp {
+            let packet = udp.encrypt(speakez::mumble::voice::Message::Audio(msg));
+            self.network
+                .voice
+                .send(network::Command::Send(packet), None);
         }
     }
 
     fn handle_event(&mut self, e: Event) {
         match e {
-            Event::InputData(data) => self.handle_audio_input(data),
+            Event::InputData {
+                data,
+                is_terminator,
+            } => self.handle_audio_input(data, is_terminator),
+            Event::MonitorData { pcm, channels } => {
+                let cmd = audio::state::Command::PlayMonitor { pcm, channels };
+                self.audio.output.send(cmd);
+            }
             Event::Control(event) => match event.data {
                 Ok(event) => match event {
                     network::Event::Connected => {
@@ -406,43 +897,99 @@ impl Client {
                     }
                     network::Event::Disconnected => {
                         self.network.control_connected = false;
-                        self.network_disconnected()
+                        if self.udp.take().is_some() {
+                            self.network.voice.send(network::Command::Disconnect, None);
+                        }
+                        self.network.tunnel_voice = true;
+                        // sessions are assigned again when reconnecting
+                        if !self.priority_speakers.is_empty() {
+                            self.priority_speakers.clear();
+                            self.send_priority_speakers();
+                        }
+                        if let State::NotConnected = self.state {
+                            self.network_disconnected()
+                        } else {
+                            self.connection_lost(Instant::now());
+                        }
+                    }
+                    network::Event::Data(m)
+                        if m.typ == speakez::mumble::control::MessageType::CryptSetup
+                            && self.udp.is_some() =>
+                    {
+                        self.handle_crypt_setup(m);
+                    }
+                    network::Event::Data(m)
+                        if m.typ == speakez::mumble::control::MessageType::Ping
+                            && self.get_state_mut().is_some() =>
+                    {
+                        self.handle_tcp_ping(m);
                     }
                     network::Event::Data(m) => {
-                        let msg = self.state.handle_control(m, |state| {
-                            let resp = complete_response(state);
-                            // dbg!(&resp);
-                            self.commands.push_response(resp);
-                        });
+                        if m.typ == speakez::mumble::control::MessageType::UDPTunnel {
+                            self.stats.tcp_packets += 1;
+                        }
+                        self.track_priority_speakers(&m);
+                        let mut crypt_setup = None;
+                        let msg = self
+                            .state
+                            .handle_control(m, |state| {
+                                let resp = complete_response(state);
+                                // dbg!(&resp);
+                                self.commands.push_response(resp);
+                                crypt_setup = Some(state.crypt_setup.clone());
+                            })
+                            .map(|msg| msg.as_vec());
                         if let Some(msg) = msg {
-                            let cmd = network::Command::Send(msg.as_vec());
+                            let cmd = network::Command::Send(msg);
                             self.network.control.send(cmd, None);
                         }
+                        if let Some(setup) = crypt_setup {
+                            self.stats = stats::Stats::new(Instant::now());
+                            self.start_udp(setup);
+                            self.handshake_complete();
+                        }
                     }
                 },
                 Err(e) => {
+                    let changed = match &e {
+                        network::Error::IO(e) => tls::tofu::FingerprintChanged::from_io(e),
+                        _ => None,
+                    };
+                    if let Some(changed) = changed {
+                        self.certificate_changed(changed);
+                        return;
+                    }
+
                     let tag = event.tag.and_then(|tag| self.commands.find_tag(tag));
                     if let Some(tag) = tag {
                         self.commands.push_error_response(tag, e.to_string());
                         return;
                     }
-                    panic!("network: control error: {e:?}");
+                    tracing::warn!("network: control error: {e}");
+                    if !self.network.control_connected && self.reconnect.is_some() {
+                        // the attempt could not connect
+                        self.connection_lost(Instant::now());
+                    }
                 }
             },
             Event::Voice(event) => match event.data {
                 Ok(event) => match event {
-                    network::Event::Connected => todo!(),
+                    network::Event::Connected => {
+                        self.network.voice_connected = true;
+                        self.handle_tick(Instant::now());
+                    }
                     network::Event::Disconnected => {
                         self.network.voice_connected = false;
                         self.network_disconnected()
                     }
-                    network::Event::Data(_) => {
-                        // decrypt data
-                    }
+                    network::Event::Data(data) => self.handle_voice_data(data),
                 },
-                Err(e) => panic!("network: voice error: {e:?}"),
+                Err(e) => {
+                    tracing::warn!("network: voice error, tunneling voice: {e}");
+                    self.network.tunnel_voice = true;
+                }
             },
-            Event::Tick() => {}
+            Event::Tick() => self.handle_tick(Instant::now()),
         }
 
         self.check_for_audio_events();
@@ -463,7 +1010,7 @@ impl Client {
         });
 
         for event in events {
-            let cmd = audio::state::Command::PlayOpusAudio(event.data);
+            let cmd = audio::state::Command::PlayOpusAudio(event);
             self.audio.output.send(cmd);
         }
     }
@@ -486,6 +1033,11 @@ impl Client {
         self.get_state_mut().map(|s| s.outbox.drain(..))
     }
 
+    /// Events from the client itself, such as reconnecting.
+    pub fn outgoing_events(&mut self) -> impl Iterator<Item = outgoing::Event> + '_ {
+        self.outgoing.drain(..)
+    }
+
     pub fn responses(&mut self) -> impl Iterator<Item = commands::response::Message> + '_ {
         self.commands.messages.drain(..)
     }
@@ -575,4 +1127,42 @@ impl ClientRef {
     pub fn output_set_device_state(&self) {
         todo!()
     }
+
+    pub fn send_message(&self, m: commands::SendMessage) {
+        self.send_command(m);
+    }
+
+    pub fn mute_mic(&self, mute: bool) {
+        self.send_command(commands::MuteMic { mute });
+    }
+
+    pub fn monitor_mic(&self, monitor: bool) {
+        self.send_command(commands::MonitorMic { monitor });
+    }
+
+    pub fn set_transmit_mode(&self, mode: audio::TransmitMode) {
+        self.send_command(commands::SetTransmitMode { mode });
+    }
+
+    pub fn set_input_processing(&self, processing: audio::InputProcessing) {
+        self.send_command(commands::SetInputProcessing { processing });
+    }
+
+    pub fn push_to_talk(&self, active: bool) {
+        self.send_command(commands::PushToTalk { active });
+    }
+
+    pub fn import_identity(&self, path: impl Into<PathBuf>, password: String) {
+        let path = path.into();
+        self.send_command(commands::ImportIdentity { path, password });
+    }
+
+    pub fn export_identity(&self, path: impl Into<PathBuf>, password: String) {
+        let path = path.into();
+        self.send_command(commands::ExportIdentity { path, password });
+    }
+
+    pub fn stats(&self) {
+        self.send_command(commands::Stats);
+    }
 }
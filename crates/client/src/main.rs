@@ -1,5 +1,6 @@
+use speakez_client::tls::tofu::{KnownHosts, TofuVerifier};
 use speakez_client::{audio::DeviceConfig, commands, Client, ClientRef, Config};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 fn init_subscriber() {
     use tracing::Level;
@@ -20,7 +21,7 @@ fn main() {
     tracing::info!("speakez client shutting down");
 }
 
-fn get_tls_config() -> rustls::ClientConfig {
+fn get_tls_config(known_hosts: Arc<Mutex<KnownHosts>>) -> rustls::ClientConfig {
     let provider = rustls::crypto::aws_lc_rs::default_provider();
     provider
         .install_default()
@@ -31,8 +32,9 @@ fn get_tls_config() -> rustls::ClientConfig {
         .with_platform_verifier()
         .with_no_client_auth();
 
-    let verifier = speakez_client::tls::danger::NoCertificateVerification::new(
-        rustls::crypto::aws_lc_rs::default_provider(),
+    let verifier = TofuVerifier::new(
+        Arc::new(rustls_platform_verifier::Verifier::new()),
+        known_hosts,
     );
 
     config
@@ -65,8 +67,24 @@ fn run() {
     let delay = std::time::Duration::from_secs(60 * 10);
     println!("Playing for {delay:?} seconds... ");
 
-    let config = get_tls_config();
+    let known_hosts = std::env::var("SPEAKEZ_KNOWN_HOSTS").unwrap_or("known_hosts".to_string());
+    let known_hosts = KnownHosts::load(known_hosts).expect("failed to load known hosts");
+    let known_hosts = Arc::new(Mutex::new(known_hosts));
+
+    let config = get_tls_config(known_hosts.clone());
     let (sender, receiver) = std::sync::mpsc::channel();
+    let ticker = sender.clone();
+    std::thread::Builder::new()
+        .name("tick".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            let msg = speakez_client::Message::Event(speakez_client::Event::Tick());
+            if ticker.send(msg).is_err() {
+                return;
+            }
+        })
+        .unwrap();
+
     let mut c = Client::new(
         config,
         Arc::new(move |msg| {
@@ -74,6 +92,12 @@ fn run() {
             Ok(())
         }),
     );
+    c.set_known_hosts(known_hosts);
+
+    let identity = std::env::var("SPEAKEZ_IDENTITY").unwrap_or("identity.p12".to_string());
+    if let Err(e) = c.load_identity(identity, "cli_test_user") {
+        tracing::error!("failed to load identity, connecting without one: {}", e);
+    }
 
     let client = ClientRef::from(&c);
     std::thread::Builder::new()
@@ -88,6 +112,10 @@ fn run() {
                     }
                 }
 
+                for event in c.outgoing_events() {
+                    dbg!(event);
+                }
+
                 for resp in c.responses() {
                     dbg!(resp);
                 }
@@ -1,13 +1,14 @@
 use std::{
     fmt,
     io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
-    net::{AddrParseError, TcpStream},
+    net::{AddrParseError, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
     sync::{mpsc, Arc},
     time::Duration,
 };
 
 pub use commands::Command;
 pub use events::Event;
+use rustls::{client::ResolvesClientCert, sign::CertifiedKey, SignatureScheme};
 use speakez::mumble::control::MessageBuf;
 
 use crate::commands::Tag;
@@ -19,6 +20,10 @@ pub struct Message<D> {
 }
 
 pub mod commands {
+    use std::sync::Arc;
+
+    use rustls::sign::CertifiedKey;
+
     pub type Message = super::Message<Command>;
 
     #[derive(Clone, Debug)]
@@ -31,6 +36,9 @@ pub mod commands {
         Connect(Connect),
         Disconnect,
         Send(Vec<u8>),
+        /// The certificate to authenticate with from the next connect on, only used for
+        /// the control connection.
+        SetIdentity(Option<Arc<CertifiedKey>>),
     }
 }
 
@@ -95,6 +103,8 @@ pub struct State {
     pub control: Handler,
     pub voice: Handler,
     pub tunnel_voice: bool,
+    /// Address of the server for the last connect, voice uses the same address over UDP.
+    pub server_addr: Option<String>,
 
     pub control_connected: bool,
     pub voice_connected: bool,
@@ -125,6 +135,7 @@ impl State {
             control,
             voice,
             tunnel_voice: true,
+            server_addr: None,
             control_connected: false,
             voice_connected: false,
         }
@@ -135,9 +146,21 @@ impl State {
     }
 }
 
+/// Bind a local socket and only exchange packets with `addr`.
+fn udp_connect(addr: &str) -> Result<UdpSocket, Error> {
+    let addr: SocketAddr = addr.parse().map_err(Error::AddrParse)?;
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let sock = UdpSocket::bind(local).map_err(Error::IO)?;
+    sock.connect(addr).map_err(Error::IO)?;
+    Ok(sock)
+}
+
 fn udp_receiver_thread(receiver: mpsc::Receiver<commands::Message>, mut sender: VoiceSender) {
-    let mut socket: Option<std::net::UdpSocket> = None;
-    let mut buffer = [0; 1024];
+    let mut socket: Option<UdpSocket> = None;
+    let mut buffer = [0; speakez::mumble::voice::MAX_UDP_PACKET_SIZE];
     let timeout = Duration::from_millis(5);
 
     loop {
@@ -148,7 +171,7 @@ fn udp_receiver_thread(receiver: mpsc::Receiver<commands::Message>, mut sender:
                 should_sleep = false;
                 match cmd {
                     Command::Connect(commands::Connect { addr }) => {
-                        match std::net::UdpSocket::bind(addr) {
+                        match udp_connect(&addr) {
                             Ok(sock) => {
                                 sock.set_read_timeout(Some(timeout)).unwrap();
                                 socket = Some(sock);
@@ -159,10 +182,7 @@ fn udp_receiver_thread(receiver: mpsc::Receiver<commands::Message>, mut sender:
                                 (sender)(msg);
                             }
                             Err(e) => {
-                                let msg = events::Message {
-                                    tag,
-                                    data: Err(Error::IO(e)),
-                                };
+                                let msg = events::Message { tag, data: Err(e) };
                                 (sender)(msg);
                             }
                         };
@@ -179,10 +199,12 @@ fn udp_receiver_thread(receiver: mpsc::Receiver<commands::Message>, mut sender:
                             (sender)(msg);
                         }
                     }
+                    Command::SetIdentity(_) => {}
                     Command::Send(data) => {
                         if let Some(ref sock) = socket {
+                            // datagrams are sent whole or not at all
                             match sock.send(&data) {
-                                Ok(_) => todo!("check for all data being written"),
+                                Ok(_) => {}
                                 Err(e) => {
                                     let msg = events::Message {
                                         tag,
@@ -203,7 +225,7 @@ fn udp_receiver_thread(receiver: mpsc::Receiver<commands::Message>, mut sender:
         if let Some(ref sock) = socket {
             // read has a timeout set on it, no need to sleep if calling this func
             // TODO: handle errors: ignore timeout
-            if let Ok((size, _)) = sock.recv_from(&mut buffer) {
+            if let Ok(size) = sock.recv(&mut buffer) {
                 let data = buffer[..size].to_vec();
                 let msg = events::Message {
                     tag: None,
@@ -267,6 +289,28 @@ impl BufferedTlsStream {
     }
 }
 
+/// Offers the identity to any server that asks for a client certificate.
+#[derive(Debug)]
+struct ClientIdentity(Option<Arc<CertifiedKey>>);
+
+impl ResolvesClientCert for ClientIdentity {
+    fn resolve(
+        &self,
+        _root_hint_subjects: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        let key = self.0.as_ref()?;
+        key.key
+            .choose_scheme(sigschemes)
+            .is_some()
+            .then(|| key.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
 struct TcpReceiver {
     cfg: Arc<rustls::ClientConfig>,
     sender: ControlSender,
@@ -344,9 +388,16 @@ impl TcpReceiver {
             Command::Connect(commands::Connect { addr }) => self.handle_connect(tag, addr),
             Command::Disconnect => self.handle_disconnect(tag),
             Command::Send(data) => self.handle_send(tag, data),
+            Command::SetIdentity(key) => self.handle_set_identity(key),
         }
     }
 
+    fn handle_set_identity(&mut self, key: Option<Arc<CertifiedKey>>) {
+        let mut cfg = (*self.cfg).clone();
+        cfg.client_auth_cert_resolver = Arc::new(ClientIdentity(key));
+        self.cfg = Arc::new(cfg);
+    }
+
     fn handle_connect(&mut self, tag: Option<Tag>, addr: String) {
         let parsed_addr = match addr.parse().map_err(Error::AddrParse) {
             Ok(addr) => addr,
@@ -433,6 +484,7 @@ impl TcpReceiver {
             Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => false,
             Err(e) => {
                 self.send_error(None, Error::IO(e));
+                self.handle_disconnect(None);
                 return;
             }
         };
@@ -441,8 +493,19 @@ impl TcpReceiver {
             return;
         }
 
-        let (typ, size) = speakez::mumble::control::parse_prefix(header);
+        let (typ, size) = match speakez::mumble::control::parse_prefix(header) {
+            Ok(v) => v,
+            Err(e) => {
+                let e = std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+                self.send_error(None, Error::IO(e));
+                self.handle_disconnect(None);
+                return;
+            }
+        };
         let total_size = PREFIX_TOTAL_SIZE + size;
+        if self.buffer.len() < total_size {
+            self.buffer.resize(total_size, 0);
+        }
 
         // read the body
         let remaining = &mut self.buffer[PREFIX_TOTAL_SIZE..total_size];
@@ -453,8 +516,8 @@ impl TcpReceiver {
                 self.send_msg(None, Event::Data(msg_buf));
             }
             Err(e) => {
-                self.conn = None;
                 self.send_error(None, Error::IO(e));
+                self.handle_disconnect(None);
             }
         }
     }
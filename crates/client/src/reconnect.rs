@@ -0,0 +1,92 @@
+//! Reconnecting after the control connection drops.
+
+use std::time::{Duration, Instant};
+
+use speakez::common::ChannelID;
+
+/// Delay before the first attempt, doubled after each failed attempt.
+const INITIAL_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct Reconnect {
+    /// Attempts started so far.
+    attempt: u32,
+    next_attempt: Instant,
+    /// Set while an attempt has not connected or failed yet.
+    in_progress: bool,
+    /// The channel to rejoin once connected.
+    pub channel: Option<ChannelID>,
+}
+
+impl Reconnect {
+    pub fn new(channel: Option<ChannelID>, now: Instant) -> Self {
+        Self {
+            attempt: 0,
+            next_attempt: now,
+            in_progress: false,
+            channel,
+        }
+    }
+
+    /// The delay before the attempt after `attempt` failed ones.
+    pub fn delay(attempt: u32) -> Duration {
+        INITIAL_DELAY
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_DELAY)
+    }
+
+    /// Attempts started so far.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Schedule the next attempt, returns how long until it starts.
+    pub fn schedule(&mut self, now: Instant) -> Duration {
+        let delay = Self::delay(self.attempt);
+        self.next_attempt = now + delay;
+        self.in_progress = false;
+        delay
+    }
+
+    /// Returns true when an attempt should start.
+    pub fn poll(&mut self, now: Instant) -> bool {
+        if self.in_progress || now < self.next_attempt {
+            return false;
+        }
+        self.in_progress = true;
+        self.attempt += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let delays: Vec<_> = (0..8).map(Reconnect::delay).collect();
+        assert_eq!(delays[0], INITIAL_DELAY);
+        assert_eq!(delays[1], INITIAL_DELAY * 2);
+        assert_eq!(delays[3], INITIAL_DELAY * 8);
+        assert_eq!(delays[7], MAX_DELAY);
+        assert_eq!(Reconnect::delay(u32::MAX), MAX_DELAY);
+    }
+
+    #[test]
+    fn test_poll() {
+        let now = Instant::now();
+        let mut r = Reconnect::new(None, now);
+        let delay = r.schedule(now);
+        assert!(!r.poll(now));
+        assert!(r.poll(now + delay));
+        assert!(!r.poll(now + delay), "only one attempt at a time");
+        assert_eq!(r.attempt(), 1);
+
+        let now = now + delay;
+        assert_eq!(r.schedule(now), INITIAL_DELAY * 2);
+        assert!(r.poll(now + INITIAL_DELAY * 2));
+        assert_eq!(r.attempt(), 2);
+    }
+}
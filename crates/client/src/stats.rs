@@ -0,0 +1,227 @@
+//! Latency and packet statistics, reported to the server in TCP pings.
+
+use std::time::{Duration, Instant};
+
+use speakez::mumble::control::proto;
+
+/// Time between TCP pings.
+pub const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Packet counters kept by a `CryptState`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CryptStats {
+    pub good: u32,
+    pub late: u32,
+    pub lost: u32,
+    pub resync: u32,
+}
+
+/// Round trip times of one transport.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PingStats {
+    /// Pings answered.
+    pub packets: u32,
+    /// Average round trip in milliseconds.
+    pub avg: f32,
+    /// Variance of the round trip in milliseconds squared.
+    pub var: f32,
+}
+
+/// A snapshot of the connection quality.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConnectionStats {
+    pub tcp: PingStats,
+    pub udp: PingStats,
+    /// Voice is sent over UDP, otherwise it is tunneled over the control connection.
+    pub udp_working: bool,
+    /// Voice packets received from the server.
+    pub from_server: CryptStats,
+    /// Voice packets the server received from us, as of its last ping reply.
+    pub to_server: CryptStats,
+    /// Voice packets received over UDP.
+    pub udp_packets: u32,
+    /// Voice packets received tunneled over the control connection.
+    pub tcp_packets: u32,
+}
+
+/// Running average and variance, using Welford's algorithm.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RoundTrip {
+    count: u32,
+    mean: f64,
+    m2: f64,
+}
+
+impl RoundTrip {
+    pub fn push(&mut self, rtt: Duration) {
+        let ms = rtt.as_secs_f64() * 1000.0;
+        self.count += 1;
+        let delta = ms - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (ms - self.mean);
+    }
+
+    pub fn stats(&self) -> PingStats {
+        let var = match self.count {
+            0 => 0.0,
+            n => self.m2 / n as f64,
+        };
+        PingStats {
+            packets: self.count,
+            avg: self.mean as f32,
+            var: var as f32,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Stats {
+    /// Ping timestamps are milliseconds since this time.
+    started: Instant,
+    last_ping: Option<Instant>,
+    tcp: RoundTrip,
+    udp: RoundTrip,
+    to_server: CryptStats,
+    pub udp_packets: u32,
+    pub tcp_packets: u32,
+}
+
+impl Stats {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            last_ping: None,
+            tcp: RoundTrip::default(),
+            udp: RoundTrip::default(),
+            to_server: CryptStats::default(),
+            udp_packets: 0,
+            tcp_packets: 0,
+        }
+    }
+
+    /// Returns a TCP ping when one is due, `crypt` are the counters of the UDP
+    /// connection.
+    pub fn ping(&mut self, now: Instant, crypt: Option<CryptStats>) -> Option<proto::Ping> {
+        if self
+            .last_ping
+            .is_some_and(|at| now.duration_since(at) < PING_INTERVAL)
+        {
+            return None;
+        }
+        self.last_ping = Some(now);
+
+        let tcp = self.tcp.stats();
+        let udp = self.udp.stats();
+        let crypt = crypt.unwrap_or_default();
+        Some(proto::Ping {
+            timestamp: Some(self.timestamp(now)),
+            good: Some(crypt.good),
+            late: Some(crypt.late),
+            lost: Some(crypt.lost),
+            resync: Some(crypt.resync),
+            udp_packets: Some(self.udp_packets),
+            tcp_packets: Some(self.tcp_packets),
+            udp_ping_avg: Some(udp.avg),
+            udp_ping_var: Some(udp.var),
+            tcp_ping_avg: Some(tcp.avg),
+            tcp_ping_var: Some(tcp.var),
+        })
+    }
+
+    /// Handle the server's reply to a TCP ping.
+    pub fn handle_tcp_ping(&mut self, ping: &proto::Ping, now: Instant) {
+        if let Some(rtt) = ping.timestamp.and_then(|t| self.round_trip(t, now)) {
+            self.tcp.push(rtt);
+        }
+        self.to_server = CryptStats {
+            good: ping.good(),
+            late: ping.late(),
+            lost: ping.lost(),
+            resync: ping.resync(),
+        };
+    }
+
+    /// Handle the server's reply to a UDP ping.
+    pub fn handle_udp_ping(&mut self, rtt: Duration) {
+        self.udp.push(rtt);
+    }
+
+    /// Milliseconds since the stats started, used as the ping timestamp.
+    fn timestamp(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_millis() as u64
+    }
+
+    /// The time since the ping with `timestamp` was sent, None for timestamps from the
+    /// future.
+    fn round_trip(&self, timestamp: u64, now: Instant) -> Option<Duration> {
+        let sent = self.started.checked_add(Duration::from_millis(timestamp))?;
+        now.checked_duration_since(sent)
+    }
+
+    pub fn snapshot(&self, crypt: Option<CryptStats>, udp_working: bool) -> ConnectionStats {
+        ConnectionStats {
+            tcp: self.tcp.stats(),
+            udp: self.udp.stats(),
+            udp_working,
+            from_server: crypt.unwrap_or_default(),
+            to_server: self.to_server,
+            udp_packets: self.udp_packets,
+            tcp_packets: self.tcp_packets,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut rtt = RoundTrip::default();
+        assert_eq!(rtt.stats(), PingStats::default());
+
+        for ms in [10, 20, 30, 40] {
+            rtt.push(Duration::from_millis(ms));
+        }
+        let stats = rtt.stats();
+        assert_eq!(stats.packets, 4);
+        assert!((stats.avg - 25.0).abs() < 1e-3, "avg {}", stats.avg);
+        assert!((stats.var - 125.0).abs() < 1e-3, "var {}", stats.var);
+    }
+
+    #[test]
+    fn test_ping() {
+        let now = Instant::now();
+        let mut stats = Stats::new(now);
+
+        let ping = stats.ping(now, None).unwrap();
+        assert!(stats.ping(now + PING_INTERVAL / 2, None).is_none());
+
+        let reply = proto::Ping {
+            timestamp: ping.timestamp,
+            good: Some(7),
+            ..Default::default()
+        };
+        stats.handle_tcp_ping(&reply, now + Duration::from_millis(40));
+
+        let crypt = CryptStats {
+            good: 3,
+            ..Default::default()
+        };
+        let ping = stats.ping(now + PING_INTERVAL, Some(crypt)).unwrap();
+        assert_eq!(ping.timestamp, Some(PING_INTERVAL.as_millis() as u64));
+        assert_eq!(ping.good, Some(3));
+        assert_eq!(ping.tcp_ping_avg, Some(40.0));
+
+        let snapshot = stats.snapshot(Some(crypt), false);
+        assert_eq!(snapshot.tcp.packets, 1);
+        assert_eq!(snapshot.from_server.good, 3);
+        assert_eq!(snapshot.to_server.good, 7);
+    }
+}
@@ -58,3 +58,272 @@ pub mod danger {
         }
     }
 }
+
+/// Trust on first use for servers with certificates the platform does not trust, such
+/// as the self signed certificates most servers use.
+pub mod tofu {
+    use std::collections::BTreeMap;
+    use std::fmt;
+    use std::io;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    use rustls::{CertificateError, DigitallySignedStruct, OtherError};
+
+    /// SHA-256 of a DER encoded certificate as lowercase hex.
+    pub fn fingerprint(cert: &[u8]) -> String {
+        let digest = aws_lc_rs::digest::digest(&aws_lc_rs::digest::SHA256, cert);
+        digest.as_ref().iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// The server presented a different certificate than the one stored for it.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct FingerprintChanged {
+        pub host: String,
+        /// The fingerprint stored for the host.
+        pub known: String,
+        /// The fingerprint of the certificate the server presented.
+        pub fingerprint: String,
+    }
+
+    impl FingerprintChanged {
+        /// Find the error inside one returned by a TLS stream.
+        pub fn from_io(e: &io::Error) -> Option<&Self> {
+            match e.get_ref()?.downcast_ref::<rustls::Error>()? {
+                rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(e))) => {
+                    e.downcast_ref()
+                }
+                _ => None,
+            }
+        }
+    }
+
+    impl fmt::Display for FingerprintChanged {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "certificate for {} changed, expected fingerprint {} but got {}",
+                self.host, self.known, self.fingerprint
+            )
+        }
+    }
+
+    impl std::error::Error for FingerprintChanged {}
+
+    /// Certificate fingerprints by host, stored as one `host fingerprint` pair per line.
+    #[derive(Debug, Default)]
+    pub struct KnownHosts {
+        path: Option<PathBuf>,
+        hosts: BTreeMap<String, String>,
+    }
+
+    impl KnownHosts {
+        /// Known hosts that are only kept in memory.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Load known hosts from `path`, a missing file is treated as empty. Changes are
+        /// written back to it.
+        pub fn load(path: impl Into<PathBuf>) -> io::Result<Self> {
+            let path = path.into();
+            let hosts = match std::fs::read_to_string(&path) {
+                Ok(s) => Self::parse(&s),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => return Err(e),
+            };
+            Ok(Self {
+                path: Some(path),
+                hosts,
+            })
+        }
+
+        fn parse(s: &str) -> BTreeMap<String, String> {
+            s.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .filter_map(|line| {
+                    let (host, fingerprint) = line.split_once(char::is_whitespace)?;
+                    Some((host.to_string(), fingerprint.trim().to_string()))
+                })
+                .collect()
+        }
+
+        fn save(&self) -> io::Result<()> {
+            let Some(path) = &self.path else {
+                return Ok(());
+            };
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let contents: String = self
+                .hosts
+                .iter()
+                .map(|(host, fingerprint)| format!("{host} {fingerprint}\n"))
+                .collect();
+            std::fs::write(path, contents)
+        }
+
+        pub fn get(&self, host: &str) -> Option<&str> {
+            self.hosts.get(host).map(String::as_str)
+        }
+
+        /// Trust `fingerprint` for `host`, replacing the one stored before.
+        pub fn insert(&mut self, host: String, fingerprint: String) -> io::Result<()> {
+            self.hosts.insert(host, fingerprint);
+            self.save()
+        }
+
+        /// Unknown hosts are trusted and stored, known hosts have to match.
+        pub fn check(&mut self, host: &str, fingerprint: &str) -> Result<(), FingerprintChanged> {
+            match self.get(host) {
+                Some(known) if known == fingerprint => Ok(()),
+                Some(known) => Err(FingerprintChanged {
+                    host: host.to_string(),
+                    known: known.to_string(),
+                    fingerprint: fingerprint.to_string(),
+                }),
+                None => {
+                    tracing::info!("trusting certificate {} for {}", fingerprint, host);
+                    if let Err(e) = self.insert(host.to_string(), fingerprint.to_string()) {
+                        tracing::warn!("failed to save known hosts: {}", e);
+                    }
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Accepts certificates that `inner` verifies, any other certificate has to match
+    /// the one first seen for the host.
+    #[derive(Debug)]
+    pub struct TofuVerifier {
+        inner: Arc<dyn ServerCertVerifier>,
+        known_hosts: Arc<Mutex<KnownHosts>>,
+    }
+
+    impl TofuVerifier {
+        pub fn new(
+            inner: Arc<dyn ServerCertVerifier>,
+            known_hosts: Arc<Mutex<KnownHosts>>,
+        ) -> Self {
+            Self { inner, known_hosts }
+        }
+    }
+
+    impl ServerCertVerifier for TofuVerifier {
+        fn verify_server_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            intermediates: &[CertificateDer<'_>],
+            server_name: &ServerName<'_>,
+            ocsp: &[u8],
+            now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            match self
+                .inner
+                .verify_server_cert(end_entity, intermediates, server_name, ocsp, now)
+            {
+                Ok(verified) => return Ok(verified),
+                Err(e) => tracing::debug!("certificate not verified, checking known hosts: {}", e),
+            }
+
+            let host = server_name.to_str();
+            let fingerprint = fingerprint(end_entity);
+            self.known_hosts
+                .lock()
+                .unwrap()
+                .check(&host, &fingerprint)
+                .map(|_| ServerCertVerified::assertion())
+                .map_err(|e| {
+                    rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(
+                        Arc::new(e),
+                    )))
+                })
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            self.inner.verify_tls12_signature(message, cert, dss)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            self.inner.verify_tls13_signature(message, cert, dss)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+            self.inner.supported_verify_schemes()
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_check() {
+            let mut hosts = KnownHosts::new();
+            assert_eq!(
+                hosts.check("127.0.0.1", "aa"),
+                Ok(()),
+                "first use is trusted"
+            );
+            assert_eq!(hosts.check("127.0.0.1", "aa"), Ok(()));
+
+            let err = hosts.check("127.0.0.1", "bb").unwrap_err();
+            assert_eq!(err.known, "aa");
+            assert_eq!(err.fingerprint, "bb");
+
+            hosts
+                .insert("127.0.0.1".to_string(), "bb".to_string())
+                .unwrap();
+            assert_eq!(hosts.check("127.0.0.1", "bb"), Ok(()));
+        }
+
+        #[test]
+        fn test_load_save() {
+            let path = std::env::temp_dir()
+                .join(format!("speakez-known-hosts-{}", std::process::id()))
+                .join("known_hosts");
+            let _ = std::fs::remove_file(&path);
+
+            let mut hosts = KnownHosts::load(&path).unwrap();
+            assert_eq!(hosts.get("example.com"), None);
+            hosts.check("example.com", "aa").unwrap();
+            hosts.check("10.0.0.1", "bb").unwrap();
+
+            let hosts = KnownHosts::load(&path).unwrap();
+            assert_eq!(hosts.get("example.com"), Some("aa"));
+            assert_eq!(hosts.get("10.0.0.1"), Some("bb"));
+            std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        }
+
+        #[test]
+        fn test_error_from_io() {
+            let changed = FingerprintChanged {
+                host: "example.com".to_string(),
+                known: "aa".to_string(),
+                fingerprint: "bb".to_string(),
+            };
+            let err = rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(
+                Arc::new(changed.clone()),
+            )));
+            let err = io::Error::new(io::ErrorKind::InvalidData, err);
+            assert_eq!(FingerprintChanged::from_io(&err), Some(&changed));
+
+            let err = io::Error::new(io::ErrorKind::InvalidData, rustls::Error::DecryptError);
+            assert_eq!(FingerprintChanged::from_io(&err), None);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Encrypted UDP voice, voice is tunneled over the control connection while UDP is not working.
+
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use speakez::mumble::control::proto;
+use speakez::mumble::voice;
+
+use crate::mumble::crypt::{CryptState, BLOCK_SIZE, KEY_SIZE};
+use crate::stats::CryptStats;
+
+/// Time between UDP pings.
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// Pings without a reply before voice falls back to the control connection.
+pub const MAX_LOST_PINGS: u32 = 3;
+
+/// Size of the header added by `CryptState::encrypt`.
+const HEADER_SIZE: usize = 4;
+
+pub struct Udp {
+    crypt: CryptState,
+    /// Ping timestamps are milliseconds since this time.
+    started: Instant,
+    last_ping: Option<Instant>,
+    /// Pings sent since the last reply.
+    unanswered: u32,
+    /// Set once the server has answered a ping.
+    working: bool,
+    /// Times the server sent a new nonce.
+    resync: u32,
+}
+
+impl Udp {
+    /// Returns None if the CryptSetup from the handshake is missing the key or nonces.
+    pub fn new(setup: &proto::CryptSetup) -> Option<Self> {
+        let key: [u8; KEY_SIZE] = setup.key.as_deref()?.try_into().ok()?;
+        let encrypt_nonce: [u8; BLOCK_SIZE] = setup.client_nonce.as_deref()?.try_into().ok()?;
+        let decrypt_nonce: [u8; BLOCK_SIZE] = setup.server_nonce.as_deref()?.try_into().ok()?;
+
+        Some(Self {
+            crypt: CryptState::new_from(key, encrypt_nonce, decrypt_nonce),
+            started: Instant::now(),
+            last_ping: None,
+            unanswered: 0,
+            working: false,
+            resync: 0,
+        })
+    }
+
+    /// Whether voice should be sent over UDP instead of tunneled.
+    pub fn is_working(&self) -> bool {
+        self.working && self.unanswered < MAX_LOST_PINGS
+    }
+
+    pub fn crypt_stats(&self) -> CryptStats {
+        CryptStats {
+            good: self.crypt.get_good(),
+            late: self.crypt.get_late(),
+            lost: self.crypt.get_lost(),
+            resync: self.resync,
+        }
+    }
+
+    pub fn encrypt(&mut self, m: voice::Message) -> Vec<u8> {
+        let mut buf = vec![0u8; voice::MAX_UDP_PACKET_SIZE];
+        let size = m.encode(&mut buf[HEADER_SIZE..]).unwrap();
+        buf.truncate(HEADER_SIZE + size);
+
+        let mut packet = BytesMut::from(&buf[..]);
+        self.crypt.encrypt(&mut packet);
+        packet.to_vec()
+    }
+
+    /// Returns None when the packet can not be decrypted or decoded. Pings are handled here.
+    pub fn decrypt(&mut self, data: &[u8]) -> Option<voice::Message> {
+        let mut packet = BytesMut::from(data);
+        if let Err(e) = self.crypt.decrypt(&mut packet) {
+            tracing::debug!("failed to decrypt voice packet: {}", e.to_string());
+            return None;
+        }
+
+        let m = match voice::Message::decode(&packet) {
+            Ok(m) => m,
+            Err(e) => {
+                tracing::debug!("failed to decode voice packet: {}", e);
+                return None;
+            }
+        };
+        if let voice::Message::Ping(_) = m {
+            self.working = true;
+            self.unanswered = 0;
+        }
+        Some(m)
+    }
+
+    /// Returns an encrypted ping when one is due.
+    pub fn ping(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self
+            .last_ping
+            .is_some_and(|at| now.duration_since(at) < PING_INTERVAL)
+        {
+            return None;
+        }
+        if self.last_ping.is_some() {
+            self.unanswered += 1;
+        }
+        self.last_ping = Some(now);
+
+        let ping = voice::Ping {
+            timestamp: now.saturating_duration_since(self.started).as_millis() as u64,
+            ..Default::default()
+        };
+        Some(self.encrypt(voice::Message::Ping(ping)))
+    }
+
+    /// The round trip time of a ping reply.
+    pub fn round_trip(&self, ping: &voice::Ping, now: Instant) -> Option<Duration> {
+        let sent = self
+            .started
+            .checked_add(Duration::from_millis(ping.timestamp))?;
+        now.checked_duration_since(sent)
+    }
+
+    /// Handle a CryptSetup received after the handshake. An empty message is the server
+    /// asking for our nonce, which is returned to send back.
+    pub fn handle_crypt_setup(&mut self, setup: proto::CryptSetup) -> Option<proto::CryptSetup> {
+        match setup
+            .server_nonce
+            .as_deref()
+            .map(<[u8; BLOCK_SIZE]>::try_from)
+        {
+            Some(Ok(nonce)) => {
+                self.crypt.set_decrypt_nonce(&nonce);
+                self.resync += 1;
+                None
+            }
+            Some(Err(_)) => {
+                tracing::warn!("ignoring CryptSetup with an invalid nonce");
+                None
+            }
+            None => Some(proto::CryptSetup {
+                client_nonce: Some(self.crypt.get_encrypt_nonce().to_vec()),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn setup(server: &CryptState) -> proto::CryptSetup {
+        proto::CryptSetup {
+            key: Some(server.get_key().to_vec()),
+            client_nonce: Some(server.get_decrypt_nonce().to_vec()),
+            server_nonce: Some(server.get_encrypt_nonce().to_vec()),
+        }
+    }
+
+    fn server_encrypt(server: &mut CryptState, m: voice::Message) -> Vec<u8> {
+        let mut buf = vec![0u8; voice::MAX_UDP_PACKET_SIZE];
+        let size = m.encode(&mut buf[HEADER_SIZE..]).unwrap();
+        let mut packet = BytesMut::from(&buf[..HEADER_SIZE + size]);
+        server.encrypt(&mut packet);
+        packet.to_vec()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut server = CryptState::generate_new();
+        let mut udp = Udp::new(&setup(&server)).unwrap();
+
+        let audio = voice::Audio {
+            opus_data: vec![1, 2, 3],
+            ..Default::default()
+        };
+        let mut packet = BytesMut::from(&udp.encrypt(voice::Message::Audio(audio.clone()))[..]);
+        server.decrypt(&mut packet).unwrap().unwrap();
+        assert_eq!(
+            voice::Message::decode(&packet).unwrap(),
+            voice::Message::Audio(audio.clone())
+        );
+
+        let packet = server_encrypt(&mut server, voice::Message::Audio(audio.clone()));
+        assert_eq!(udp.decrypt(&packet), Some(voice::Message::Audio(audio)));
+        assert_eq!(udp.decrypt(&packet), None, "repeated packets are dropped");
+    }
+
+    #[test]
+    fn test_ping_fallback() {
+        let mut server = CryptState::generate_new();
+        let mut udp = Udp::new(&setup(&server)).unwrap();
+        let now = Instant::now();
+        assert!(
+            !udp.is_working(),
+            "voice is tunneled until a ping is answered"
+        );
+
+        assert!(udp.ping(now).is_some());
+        assert!(udp.ping(now).is_none());
+        let pong = voice::Message::Ping(voice::Ping::default());
+        udp.decrypt(&server_encrypt(&mut server, pong));
+        assert!(udp.is_working());
+
+        for i in 1..=MAX_LOST_PINGS {
+            assert!(udp.is_working());
+            udp.ping(now + PING_INTERVAL * i).unwrap();
+        }
+        assert!(!udp.is_working());
+    }
+}
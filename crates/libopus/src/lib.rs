@@ -101,7 +101,7 @@ impl Decoder {
                 input.as_ptr(),
                 input.len() as i32,
                 output.as_mut_ptr(),
-                output.len() as i32,
+                output.len() as i32 / self.channels as i32,
                 fec as i32,
             )
         };
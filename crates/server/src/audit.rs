@@ -0,0 +1,216 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use speakez::server::audit::Entry;
+
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// Number of rotated files kept next to the current one.
+pub const DEFAULT_KEEP: usize = 5;
+
+/// A line in the audit log.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Milliseconds since the unix epoch.
+    pub time: u64,
+    #[serde(flatten)]
+    pub entry: Entry,
+}
+
+impl Record {
+    pub fn new(entry: Entry) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        Record { time, entry }
+    }
+}
+
+/// Appends records to a file as JSON lines, rotating it once it grows past `max_size`.
+/// Rotated files are named `<path>.1` (newest) to `<path>.<keep>` (oldest).
+pub struct Writer {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl Writer {
+    pub fn open(path: impl Into<PathBuf>, max_size: u64, keep: usize) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Writer {
+            path,
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                match fs::rename(rotated(&self.path, n), rotated(&self.path, n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+/// The audit log files that exist, oldest first.
+pub fn files(path: &Path, keep: usize) -> Vec<PathBuf> {
+    (1..=keep)
+        .rev()
+        .map(|n| rotated(path, n))
+        .chain(std::iter::once(path.to_path_buf()))
+        .filter(|p| p.exists())
+        .collect()
+}
+
+/// Write records until all senders have been dropped.
+pub fn run_writer(mut writer: Writer, records: mpsc::Receiver<Record>) {
+    for record in records {
+        if let Err(e) = writer.write(&record) {
+            tracing::error!("failed to write audit log: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub username: Option<String>,
+    pub ip: Option<IpAddr>,
+    /// Milliseconds since the unix epoch, inclusive.
+    pub since: Option<u64>,
+    /// Milliseconds since the unix epoch, exclusive.
+    pub until: Option<u64>,
+}
+
+impl Filter {
+    pub fn matches(&self, r: &Record) -> bool {
+        let entry = &r.entry;
+        self.username
+            .as_ref()
+            .is_none_or(|u| entry.username.as_ref() == Some(u))
+            && self
+                .ip
+                .is_none_or(|ip| entry.addr.is_some_and(|a| a.ip() == ip))
+            && self.since.is_none_or(|t| r.time >= t)
+            && self.until.is_none_or(|t| r.time < t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use speakez::mumble::session::Session;
+    use speakez::server::audit::Action;
+
+    use super::*;
+
+    fn record(time: u64, username: &str, addr: &str) -> Record {
+        Record {
+            time,
+            entry: Entry {
+                session: Session::new(1).unwrap(),
+                username: Some(username.to_string()),
+                addr: Some(addr.parse().unwrap()),
+                cert_hash: None,
+                action: Action::Left,
+            },
+        }
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("speakez-audit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+
+        let r = record(1, "user", "127.0.0.1:1234");
+        let line_len = serde_json::to_vec(&r).unwrap().len() as u64 + 1;
+
+        let mut w = Writer::open(&path, line_len * 2, 2).unwrap();
+        for _ in 0..7 {
+            w.write(&r).unwrap();
+        }
+
+        let files = files(&path, 2);
+        assert_eq!(
+            files,
+            vec![rotated(&path, 2), rotated(&path, 1), path.clone()]
+        );
+        let lines: Vec<_> = files
+            .iter()
+            .map(|p| fs::read_to_string(p).unwrap().lines().count())
+            .collect();
+        assert_eq!(lines, vec![2, 2, 1]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_filter() {
+        let r = record(1_000, "user", "[::1]:1234");
+        let line = serde_json::to_string(&r).unwrap();
+        assert_eq!(serde_json::from_str::<Record>(&line).unwrap(), r);
+
+        let filter = |f: Filter| f.matches(&r);
+        assert!(filter(Filter::default()));
+        assert!(filter(Filter {
+            username: Some("user".to_string()),
+            ip: Some("::1".parse().unwrap()),
+            since: Some(1_000),
+            until: Some(1_001),
+        }));
+        assert!(!filter(Filter {
+            username: Some("other".to_string()),
+            ..Default::default()
+        }));
+        assert!(!filter(Filter {
+            ip: Some("127.0.0.1".parse().unwrap()),
+            ..Default::default()
+        }));
+        assert!(!filter(Filter {
+            until: Some(1_000),
+            ..Default::default()
+        }));
+    }
+}
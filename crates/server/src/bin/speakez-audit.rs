@@ -0,0 +1,93 @@
+//! Print audit log entries matching the given filters.
+//!
+//! speakez-audit [--user NAME] [--ip IP] [--since SECS] [--until SECS] [PATH]
+//!
+//! Times are seconds since the unix epoch. PATH defaults to ./audit.log, rotated files
+//! next to it are read as well.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use speakez_server::audit::{self, Filter, Record};
+
+const USAGE: &str =
+    "usage: speakez-audit [--user NAME] [--ip IP] [--since SECS] [--until SECS] [PATH]";
+
+fn parse_args() -> Result<(Filter, PathBuf), String> {
+    let mut filter = Filter::default();
+    let mut path = PathBuf::from("./audit.log");
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("missing value for {}", arg));
+        let secs = |v: String| {
+            let secs = v
+                .parse::<u64>()
+                .map_err(|e| format!("invalid time {:?}: {}", v, e))?;
+            secs.checked_mul(1000)
+                .ok_or_else(|| format!("invalid time {:?}: out of range", v))
+        };
+
+        match arg.as_str() {
+            "--user" => filter.username = Some(value()?),
+            "--ip" => {
+                let v = value()?;
+                let ip = v
+                    .parse()
+                    .map_err(|e| format!("invalid ip {:?}: {}", v, e))?;
+                filter.ip = Some(ip);
+            }
+            "--since" => filter.since = Some(secs(value()?)?),
+            "--until" => filter.until = Some(secs(value()?)?),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if arg.starts_with('-') => return Err(format!("unknown flag {}\n{}", arg, USAGE)),
+            _ => path = PathBuf::from(arg),
+        }
+    }
+
+    Ok((filter, path))
+}
+
+fn main() -> ExitCode {
+    let (filter, path) = match parse_args() {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let files = audit::files(&path, audit::DEFAULT_KEEP);
+    if files.is_empty() {
+        eprintln!("no audit log found at {}", path.display());
+        return ExitCode::FAILURE;
+    }
+
+    let mut stdout = io::stdout().lock();
+    for file in files {
+        let reader = match File::open(&file) {
+            Ok(f) => BufReader::new(f),
+            Err(e) => {
+                eprintln!("failed to open {}: {}", file.display(), e);
+                continue;
+            }
+        };
+
+        for line in reader.lines() {
+            let Ok(line) = line else { break };
+            match serde_json::from_str::<Record>(&line) {
+                Ok(r) if filter.matches(&r) => {
+                    if writeln!(stdout, "{}", line).is_err() {
+                        return ExitCode::SUCCESS;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("skipping invalid line in {}: {}", file.display(), e),
+            }
+        }
+    }
+
+    ExitCode::SUCCESS
+}
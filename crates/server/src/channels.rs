@@ -0,0 +1,72 @@
+//! The channel configuration, saved as JSON whenever channels change so links survive
+//! a restart.
+
+use std::io;
+use std::path::Path;
+
+use speakez::common::Channel;
+
+/// Load the saved channels, None when nothing has been saved yet.
+pub fn load(path: impl AsRef<Path>) -> io::Result<Option<Vec<Channel>>> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Save `channels`, temporary channels are left out along with links to them. The file
+/// is replaced in one step so a crash never leaves it half written.
+pub fn save(path: impl AsRef<Path>, channels: &[Channel]) -> io::Result<()> {
+    let is_kept = |id| channels.iter().any(|c| c.id == id && !c.temporary);
+    let channels: Vec<_> = channels
+        .iter()
+        .filter(|c| !c.temporary)
+        .map(|c| Channel {
+            links: c.links.iter().copied().filter(|l| is_kept(*l)).collect(),
+            ..c.clone()
+        })
+        .collect();
+
+    let path = path.as_ref();
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(&channels)?)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use speakez::common::ChannelID;
+
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let path = std::env::temp_dir().join(format!("speakez-channels-{}", std::process::id()));
+        assert!(load(&path).unwrap().is_none());
+
+        let channel = |id, temporary, links: Vec<u32>| Channel {
+            links: links.into_iter().map(ChannelID::new).collect(),
+            ..Channel::new(
+                ChannelID::new(id),
+                format!("Channel{}", id),
+                String::new(),
+                temporary,
+                None,
+            )
+        };
+        let channels = [
+            channel(0, false, vec![1, 2]),
+            channel(1, false, vec![0]),
+            channel(2, true, vec![0]),
+        ];
+        save(&path, &channels).unwrap();
+
+        let loaded = load(&path).unwrap().unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].links, vec![ChannelID::new(1)]);
+        assert_eq!(loaded[1].links, vec![ChannelID::new(0)]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
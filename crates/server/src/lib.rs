@@ -1,2 +1,5 @@
+pub mod audit;
+pub mod channels;
 pub mod mumble;
+pub mod recorder;
 pub mod server;
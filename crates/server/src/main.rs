@@ -1,18 +1,45 @@
-use std::fs::File;
-use std::io::{self, BufReader};
+use std::io;
+use std::net::SocketAddr;
 use std::num::NonZeroI32;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use speakez::common::{Channel, ChannelID};
+use speakez::server::snapshot;
 use speakez::server::state::{State, VoiceCrypter};
 
-use rustls_pemfile::{certs, private_key};
-use speakez_server::server;
+use socket2::{Domain, Protocol, Socket, Type};
+use speakez_server::server::tokio::tls::Certificates;
 use speakez_server::server::tokio::ActorMessage;
+use speakez_server::{audit, channels, recorder, server};
 use tokio::net::{TcpListener, UdpSocket, UnixListener};
-use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use tokio_rustls::TlsAcceptor;
+
+/// Comma separated list of addresses to accept TCP and UDP traffic on.
+const LISTEN_ENV: &str = "SPEAKEZ_LISTEN";
+const DEFAULT_LISTEN: &str = "0.0.0.0:64738";
+const AUDIT_LOG_ENV: &str = "SPEAKEZ_AUDIT_LOG";
+const DEFAULT_AUDIT_LOG: &str = "./audit.log";
+/// Comma separated list of channel ids to record from startup.
+const RECORD_CHANNELS_ENV: &str = "SPEAKEZ_RECORD_CHANNELS";
+const RECORDINGS_ENV: &str = "SPEAKEZ_RECORDINGS";
+const DEFAULT_RECORDINGS: &str = "./recordings";
+/// `true` lets users start recording the channel they are in, admins always can.
+const ALLOW_RECORDING_ENV: &str = "SPEAKEZ_ALLOW_RECORDING";
+/// Where a snapshot of the server state is written when receiving SIGUSR1.
+const SNAPSHOT_ENV: &str = "SPEAKEZ_SNAPSHOT";
+const DEFAULT_SNAPSHOT: &str = "./snapshot.json";
+/// Start from a snapshot instead of the default channels.
+const RESTORE_ENV: &str = "SPEAKEZ_RESTORE";
+/// Set to also restore the users in the snapshot. They have no connection and stay until
+/// an admin kicks them.
+const RESTORE_USERS_ENV: &str = "SPEAKEZ_RESTORE_USERS";
+/// Channels are loaded from and saved to this file, links included.
+const CHANNELS_ENV: &str = "SPEAKEZ_CHANNELS";
+const DEFAULT_CHANNELS: &str = "./channels.json";
+/// Directory holding the admin socket, only the user running the server may enter it.
+const SOCKET_DIR: &str = "/tmp/speakez";
+const SOCKET_NAME: &str = "speakez.sock";
 
 fn main() {
     init_subscriber();
@@ -37,15 +64,121 @@ fn init_subscriber() {
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 }
 
+fn listen_addrs() -> Vec<SocketAddr> {
+    let addrs = std::env::var(LISTEN_ENV).unwrap_or_else(|_| DEFAULT_LISTEN.to_string());
+    addrs
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(|addr| {
+            addr.parse()
+                .unwrap_or_else(|e| panic!("invalid {} address {:?}: {}", LISTEN_ENV, addr, e))
+        })
+        .collect()
+}
+
+/// Create a socket for `addr`. IPv6 sockets are dual-stack unless an IPv4 address is
+/// also being listened on with the same port, in which case that address handles IPv4.
+fn new_socket(
+    addr: SocketAddr,
+    typ: Type,
+    protocol: Protocol,
+    all: &[SocketAddr],
+) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), typ, Some(protocol))?;
+    if addr.is_ipv6() {
+        let only_v6 = all.iter().any(|a| a.is_ipv4() && a.port() == addr.port());
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+fn bind_tcp(addr: SocketAddr, all: &[SocketAddr]) -> io::Result<TcpListener> {
+    let socket = new_socket(addr, Type::STREAM, Protocol::TCP, all)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn bind_udp(addr: SocketAddr, all: &[SocketAddr]) -> io::Result<UdpSocket> {
+    let socket = new_socket(addr, Type::DGRAM, Protocol::UDP, all)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Bind the admin socket inside `SOCKET_DIR`. Connections over it are admins, the
+/// directory keeps other users out from the moment the socket is created.
+fn bind_admin_socket() -> io::Result<UnixListener> {
+    let dir = Path::new(SOCKET_DIR);
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    // Another user could have created the directory first, it is only safe to use if
+    // nobody else can enter it.
+    let meta = std::fs::symlink_metadata(dir)?;
+    if !meta.is_dir() || meta.permissions().mode() & 0o777 != 0o700 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} must be a directory with mode 0700", dir.display()),
+        ));
+    }
+
+    let path = dir.join(SOCKET_NAME);
+    if path.exists() {
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
 fn run() {
-    let acceptor = new_acceptor();
+    let addrs = listen_addrs();
+    assert!(!addrs.is_empty(), "{} must contain an address", LISTEN_ENV);
+
+    let certs = Certificates {
+        cert: PathBuf::from("./keys/cert.pem"),
+        key: PathBuf::from("./keys/key.pem"),
+        poll_interval: Duration::from_secs(60),
+    };
+    let acceptor = certs.load().unwrap();
 
     let (sender, reciever) = tokio::sync::mpsc::channel::<ActorMessage>(100);
     let (udp_sender, udp_reciever) = tokio::sync::mpsc::channel(100);
 
+    let audit_path = std::env::var(AUDIT_LOG_ENV).unwrap_or_else(|_| DEFAULT_AUDIT_LOG.to_string());
+    let audit_writer =
+        audit::Writer::open(&audit_path, audit::DEFAULT_MAX_SIZE, audit::DEFAULT_KEEP).unwrap();
+    let (audit_sender, audit_reciever) = std::sync::mpsc::channel();
+    let audit_thread = std::thread::spawn(move || audit::run_writer(audit_writer, audit_reciever));
+
+    let recordings_dir =
+        std::env::var(RECORDINGS_ENV).unwrap_or_else(|_| DEFAULT_RECORDINGS.to_string());
+    let (recorder_sender, recorder_reciever) = std::sync::mpsc::channel();
+    let recorder_thread = std::thread::spawn(move || {
+        recorder::run_recorder(recorder::Recorder::new(recordings_dir), recorder_reciever)
+    });
+
+    let snapshot_path =
+        PathBuf::from(std::env::var(SNAPSHOT_ENV).unwrap_or_else(|_| DEFAULT_SNAPSHOT.to_string()));
+
+    let channels_path =
+        PathBuf::from(std::env::var(CHANNELS_ENV).unwrap_or_else(|_| DEFAULT_CHANNELS.to_string()));
+
     let state_thread = std::thread::spawn(move || {
-        let state = load_state();
-        server::tokio::run(state, reciever, udp_sender);
+        let state = load_state(&channels_path);
+        server::tokio::run(
+            state,
+            reciever,
+            udp_sender,
+            audit_sender,
+            recorder_sender,
+            snapshot_path,
+            channels_path,
+        );
         tracing::info!("server state shutdown");
     });
 
@@ -56,20 +189,19 @@ fn run() {
         .unwrap();
 
     rt.block_on(async {
-        let tcp_listener = TcpListener::bind("0.0.0.0:64738").await.unwrap();
-        let udp_socket = UdpSocket::bind("0.0.0.0:64738").await.unwrap();
+        let mut tcp_listeners = Vec::with_capacity(addrs.len());
+        let mut udp_sockets = Vec::with_capacity(addrs.len());
+        for &addr in &addrs {
+            tcp_listeners.push(bind_tcp(addr, &addrs).unwrap());
+            udp_sockets.push(bind_udp(addr, &addrs).unwrap());
+            tracing::info!("listening on {}", addr);
+        }
 
-        let unix_socket = {
-            let socket_path = "/tmp/speakez.sock";
-            if Path::new(socket_path).exists() {
-                std::fs::remove_file(socket_path).unwrap();
-            }
-            UnixListener::bind(socket_path).unwrap()
-        };
+        let unix_socket = bind_admin_socket().unwrap();
 
         server::tokio::run_io(
-            tcp_listener,
-            udp_socket,
+            tcp_listeners,
+            udp_sockets,
             unix_socket,
             acceptor,
             sender,
@@ -81,6 +213,32 @@ fn run() {
     });
 
     state_thread.join().unwrap();
+    audit_thread.join().unwrap();
+    recorder_thread.join().unwrap();
+}
+
+fn allow_recording() -> Option<bool> {
+    let allow = std::env::var(ALLOW_RECORDING_ENV).ok()?;
+    let allow = allow
+        .trim()
+        .parse()
+        .unwrap_or_else(|e| panic!("invalid {} {:?}: {}", ALLOW_RECORDING_ENV, allow, e));
+    Some(allow)
+}
+
+fn record_channels() -> Vec<ChannelID> {
+    let channels = std::env::var(RECORD_CHANNELS_ENV).unwrap_or_default();
+    channels
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            let id = id.parse().unwrap_or_else(|e| {
+                panic!("invalid {} channel {:?}: {}", RECORD_CHANNELS_ENV, id, e)
+            });
+            ChannelID::new(id)
+        })
+        .collect()
 }
 
 fn new_crypter() -> Box<dyn VoiceCrypter> {
@@ -91,7 +249,17 @@ fn new_crypter() -> Box<dyn VoiceCrypter> {
     Box::new(crypt::CryptState::new_from_key(key))
 }
 
-fn load_state() -> State {
+fn restore_state(path: &str) -> State {
+    let data = std::fs::read(path)
+        .unwrap_or_else(|e| panic!("failed to read {} {}: {}", RESTORE_ENV, path, e));
+    let snapshot = serde_json::from_slice(&data)
+        .unwrap_or_else(|e| panic!("invalid snapshot {}: {}", path, e));
+    tracing::info!("restoring state from {}", path);
+    let users = std::env::var_os(RESTORE_USERS_ENV).is_some();
+    snapshot::restore(snapshot, new_crypter, Instant::now(), users)
+}
+
+fn default_state() -> State {
     let mut s = State::new(100, new_crypter);
 
     let root = ChannelID::new(0);
@@ -111,25 +279,39 @@ fn load_state() -> State {
         max_users: None,
         position: Some(NonZeroI32::new(-1).unwrap()),
         parent: Some(root),
+        links: vec![],
     });
     s
 }
 
-fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
-    certs(&mut BufReader::new(File::open(path)?)).collect()
-}
+/// Start from the saved channels, the default ones are used until channels are saved.
+fn configured_state(path: &Path) -> State {
+    let channels = channels::load(path)
+        .unwrap_or_else(|e| panic!("failed to load channels from {}: {}", path.display(), e));
+    let Some(channels) = channels else {
+        return default_state();
+    };
 
-fn load_keys(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
-    Ok(private_key(&mut BufReader::new(File::open(path)?))?.unwrap())
+    tracing::info!("loaded channels from {}", path.display());
+    let mut s = State::new(100, new_crypter);
+    for channel in channels {
+        s.new_channel(channel);
+    }
+    s
 }
 
-fn new_acceptor() -> TlsAcceptor {
-    let certs = load_certs(&PathBuf::from("./keys/cert.pem")).unwrap();
-    let key = load_keys(&PathBuf::from("./keys/key.pem")).unwrap();
-    let config = rustls::ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)
-        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
-        .unwrap();
-    TlsAcceptor::from(Arc::new(config))
+fn load_state(channels_path: &Path) -> State {
+    let mut s = match std::env::var(RESTORE_ENV) {
+        Ok(path) => restore_state(&path),
+        Err(_) => configured_state(channels_path),
+    };
+
+    if let Some(allow) = allow_recording() {
+        s.config.allow_recording = allow;
+    }
+    let now = Instant::now();
+    for channel in record_channels() {
+        s.start_recording(channel, now);
+    }
+    s
 }
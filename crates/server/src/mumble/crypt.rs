@@ -44,7 +44,7 @@ use std::convert::TryInto;
 use std::io;
 use std::sync::LazyLock;
 
-use speakez::server::state::{MumbleCryptSetup, VoiceCrypter};
+use speakez::server::state::{CryptStats, MumbleCryptSetup, VoiceCrypter};
 
 // NOTE: static items do not call [`Drop`] on program termination, so this won't be deallocated.
 // this is fine, as the OS can deallocate the terminated program faster than we can free memory
@@ -395,6 +395,15 @@ impl VoiceCrypter for CryptState {
             server_nonce: self.get_encrypt_nonce().to_vec(),
         }
     }
+
+    fn stats(&self) -> CryptStats {
+        CryptStats {
+            good: self.good,
+            late: self.late,
+            lost: self.lost,
+            resync: 0,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,312 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use speakez::common::ChannelID;
+use speakez::mumble::session::Session;
+use speakez::server::recording::{Event, Frame};
+
+const SAMPLE_RATE: u64 = 48_000;
+/// Samples in a 20ms frame at 48kHz.
+const SILENCE_SAMPLES: u64 = 960;
+/// TOC byte for a 20ms CELT frame without any data, decoders treat it as silence.
+const SILENCE: [u8; 1] = [0xF8];
+/// Gaps shorter than this are left alone so network jitter does not add silence.
+const MAX_GAP_SAMPLES: u64 = 3 * SILENCE_SAMPLES;
+
+/// Number of 48kHz samples in an Opus packet, see RFC 6716 section 3.1.
+pub fn packet_samples(packet: &[u8]) -> Option<u64> {
+    let toc = *packet.first()?;
+    let config = toc >> 3;
+    let frame_samples = match config {
+        0..=11 => [480, 960, 1920, 2880][config as usize % 4],
+        12..=15 => [480, 960][config as usize % 2],
+        _ => [120, 240, 480, 960][config as usize % 4],
+    };
+    let frames = match toc & 0x3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => u64::from(*packet.get(1)? & 0x3F),
+    };
+    Some(frame_samples * frames)
+}
+
+fn opus_head() -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(1); // channels
+    head.extend_from_slice(&0u16.to_le_bytes()); // pre-skip
+    head.extend_from_slice(&(SAMPLE_RATE as u32).to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family
+    head
+}
+
+fn opus_tags(username: &str) -> Vec<u8> {
+    let vendor = b"speakez";
+    let comment = format!("ARTIST={}", username);
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&1u32.to_le_bytes());
+    tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+    tags.extend_from_slice(comment.as_bytes());
+    tags
+}
+
+/// A single speaker written to its own Ogg Opus file.
+///
+/// Packets are held back by one so the last one can be marked as the end of the stream.
+/// Silence is added for the time the speaker is quiet, keeping every track of a
+/// recording aligned to when it started.
+pub struct Track {
+    writer: PacketWriter<BufWriter<File>>,
+    serial: u32,
+    granule: u64,
+    pending: Option<Vec<u8>>,
+}
+
+impl Track {
+    pub fn create(path: PathBuf, serial: u32, username: &str) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut writer = PacketWriter::new(file);
+        writer.write_packet(
+            opus_head().into_boxed_slice(),
+            serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+        writer.write_packet(
+            opus_tags(username).into_boxed_slice(),
+            serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+        Ok(Track {
+            writer,
+            serial,
+            granule: 0,
+            pending: None,
+        })
+    }
+
+    fn push(&mut self, packet: Vec<u8>, samples: u64) -> io::Result<()> {
+        if let Some(prev) = self.pending.take() {
+            self.writer.write_packet(
+                prev.into_boxed_slice(),
+                self.serial,
+                PacketWriteEndInfo::NormalPacket,
+                self.granule,
+            )?;
+        }
+        self.granule += samples;
+        self.pending = Some(packet);
+        Ok(())
+    }
+
+    /// Write `packet` heard `offset` samples after the recording started.
+    pub fn write(&mut self, packet: Vec<u8>, offset: u64) -> io::Result<()> {
+        let Some(samples) = packet_samples(&packet) else {
+            return Ok(());
+        };
+        if offset > self.granule + MAX_GAP_SAMPLES {
+            let gap = (offset - self.granule) / SILENCE_SAMPLES;
+            for _ in 0..gap {
+                self.push(SILENCE.to_vec(), SILENCE_SAMPLES)?;
+            }
+        }
+        self.push(packet, samples)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        let last = self.pending.take().unwrap_or_else(|| SILENCE.to_vec());
+        self.writer.write_packet(
+            last.into_boxed_slice(),
+            self.serial,
+            PacketWriteEndInfo::EndStream,
+            self.granule,
+        )?;
+        io::Write::flush(self.writer.inner_mut())
+    }
+}
+
+struct Recording {
+    started: Instant,
+    dir: PathBuf,
+    tracks: HashMap<(Session, String), Track>,
+}
+
+/// Writes recorded channels to `<dir>/channel-<id>-<unix time>/`, one file per speaker.
+pub struct Recorder {
+    dir: PathBuf,
+    recordings: HashMap<ChannelID, Recording>,
+    next_serial: u32,
+}
+
+impl Recorder {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Recorder {
+            dir: dir.into(),
+            recordings: HashMap::new(),
+            next_serial: 1,
+        }
+    }
+
+    pub fn handle(&mut self, event: Event) -> io::Result<()> {
+        match event {
+            Event::Started { channel, at } => {
+                let unix = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                let dir = self
+                    .dir
+                    .join(format!("channel-{}-{}", channel.as_u32(), unix));
+                fs::create_dir_all(&dir)?;
+                tracing::info!(
+                    "recording channel {} to {}",
+                    channel.as_u32(),
+                    dir.display()
+                );
+
+                let recording = Recording {
+                    started: at,
+                    dir,
+                    tracks: HashMap::new(),
+                };
+                if let Some(old) = self.recordings.insert(channel, recording) {
+                    finish(old)?;
+                }
+            }
+            Event::Frame(frame) => self.write_frame(frame)?,
+            Event::Stopped { channel } => {
+                if let Some(recording) = self.recordings.remove(&channel) {
+                    tracing::info!("stopped recording channel {}", channel.as_u32());
+                    finish(recording)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, frame: Frame) -> io::Result<()> {
+        let Some(recording) = self.recordings.get_mut(&frame.channel) else {
+            return Ok(());
+        };
+        let offset = frame.at.saturating_duration_since(recording.started);
+        let offset = offset.as_micros() as u64 * SAMPLE_RATE / 1_000_000;
+
+        let key = (frame.speaker, frame.username);
+        let track = match recording.tracks.entry(key) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let (session, username) = e.key();
+                let name = format!("{}-{}.opus", u32::from(*session), file_name(username));
+                let track = Track::create(recording.dir.join(name), self.next_serial, username)?;
+                self.next_serial += 1;
+                e.insert(track)
+            }
+        };
+        track.write(frame.data, offset)
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.recordings.into_values().try_for_each(finish)
+    }
+}
+
+fn finish(recording: Recording) -> io::Result<()> {
+    recording.tracks.into_values().try_for_each(Track::finish)
+}
+
+/// Usernames are chosen by clients, keep only characters that are safe in a file name.
+fn file_name(username: &str) -> String {
+    username
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect()
+}
+
+/// Write recordings until all senders have been dropped.
+pub fn run_recorder(mut recorder: Recorder, events: mpsc::Receiver<Event>) {
+    for event in events {
+        if let Err(e) = recorder.handle(event) {
+            tracing::error!("failed to write recording: {}", e);
+        }
+    }
+    if let Err(e) = recorder.finish() {
+        tracing::error!("failed to finish recordings: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ogg::reading::PacketReader;
+
+    use super::*;
+
+    #[test]
+    fn test_packet_samples() {
+        // SILK 20ms, one frame
+        assert_eq!(packet_samples(&[0x08]), Some(960));
+        // CELT 20ms, two frames
+        assert_eq!(packet_samples(&[0xF9]), Some(1920));
+        // CELT 10ms, arbitrary number of frames
+        assert_eq!(packet_samples(&[0xF3, 0x03]), Some(1440));
+        assert_eq!(packet_samples(&[]), None);
+    }
+
+    #[test]
+    fn test_recording() {
+        let dir = std::env::temp_dir().join(format!("speakez-recording-{}", std::process::id()));
+        let mut recorder = Recorder::new(&dir);
+
+        let channel = ChannelID::new(0);
+        let speaker = Session::new(1).unwrap();
+        let start = Instant::now();
+        let frame = |ms: u64| {
+            Event::Frame(Frame {
+                channel,
+                speaker,
+                username: "user/../name".to_string(),
+                at: start + Duration::from_millis(ms),
+                data: vec![0xF8, 1, 2, 3],
+            })
+        };
+
+        recorder
+            .handle(Event::Started { channel, at: start })
+            .unwrap();
+        // starts after 100ms of silence
+        recorder.handle(frame(100)).unwrap();
+        recorder.handle(frame(120)).unwrap();
+        recorder.handle(Event::Stopped { channel }).unwrap();
+
+        let recording = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let path = recording.join("1-username.opus");
+        let mut reader = PacketReader::new(File::open(&path).unwrap());
+
+        let head = reader.read_packet().unwrap().unwrap();
+        assert!(head.data.starts_with(b"OpusHead"));
+        let tags = reader.read_packet().unwrap().unwrap();
+        assert!(tags.data.starts_with(b"OpusTags"));
+
+        let mut packets = vec![];
+        while let Some(p) = reader.read_packet().unwrap() {
+            packets.push(p);
+        }
+        assert_eq!(packets.len(), 5 + 2);
+        assert!(packets[..5].iter().all(|p| p.data == SILENCE));
+        let last = packets.last().unwrap();
+        assert!(last.last_in_stream());
+        assert_eq!(last.absgp_page(), 7 * SILENCE_SAMPLES);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
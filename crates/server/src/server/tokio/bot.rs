@@ -0,0 +1,125 @@
+use tokio::sync::{mpsc, oneshot};
+
+use speakez::common::events::VoiceMessage;
+use speakez::mumble::session::Session;
+use speakez::server::{self, bot};
+
+use super::ActorMessage;
+
+/// Audio queued for a bot before older frames are dropped.
+pub const AUDIO_BUFFER: usize = 64;
+
+/// A bot connected to the server actor. The bot leaves the server when dropped.
+pub struct Bot {
+    session: Session,
+    frame_number: u64,
+    actor: mpsc::Sender<ActorMessage>,
+    audio: mpsc::Receiver<VoiceMessage>,
+}
+
+impl Bot {
+    /// Join the server, returns None if the channel does not exist, the server is full or
+    /// it has shut down.
+    pub async fn join(actor: mpsc::Sender<ActorMessage>, bot: bot::Bot) -> Option<Self> {
+        let (audio_tx, audio) = mpsc::channel(AUDIO_BUFFER);
+        let (resp_tx, resp) = oneshot::channel();
+        actor
+            .send(ActorMessage::CreateBot(bot, audio_tx, resp_tx))
+            .await
+            .ok()?;
+        let session = resp.await.ok()??;
+
+        Some(Bot {
+            session,
+            frame_number: 0,
+            actor,
+            audio,
+        })
+    }
+
+    pub fn session(&self) -> Session {
+        self.session
+    }
+
+    /// Speak a single Opus frame in the bot's channel.
+    pub async fn send(
+        &mut self,
+        opus: Vec<u8>,
+    ) -> Result<(), mpsc::error::SendError<ActorMessage>> {
+        let msg = VoiceMessage {
+            data: opus,
+            frame_number: self.frame_number,
+            sender: self.session,
+            target: 0,
+            is_terminator: false,
+        };
+        self.frame_number += 1;
+        self.actor
+            .send(ActorMessage::Message(server::Message::BotAudio(
+                self.session,
+                msg,
+            )))
+            .await
+    }
+
+    /// Wait for audio heard by the bot, returns None once the bot has been removed.
+    pub async fn recv(&mut self) -> Option<VoiceMessage> {
+        self.audio.recv().await
+    }
+}
+
+impl Drop for Bot {
+    fn drop(&mut self) {
+        let msg = server::Message::SessionDisconnect(self.session);
+        if self.actor.try_send(ActorMessage::Message(msg)).is_err() {
+            tracing::warn!("failed to remove bot {:?}", self.session);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use speakez::common::{Channel, ChannelID, ROOT_CHANNEL};
+    use speakez::server::state::State;
+
+    use super::*;
+    use crate::mumble::crypt::CryptState;
+
+    #[tokio::test]
+    async fn test_join_unknown_channel() {
+        let (actor, mailbox) = mpsc::channel(16);
+        let (udp, _udp_rx) = mpsc::channel(16);
+        let (audit, _audit_rx) = std::sync::mpsc::channel();
+        let (recorder, _recorder_rx) = std::sync::mpsc::channel();
+        let state = std::thread::spawn(move || {
+            let mut s = State::new(10, || Box::new(CryptState::new_from_key([0; 16])));
+            s.new_channel(Channel::new(
+                ROOT_CHANNEL,
+                "Root".to_string(),
+                String::new(),
+                false,
+                None,
+            ));
+            let unused = PathBuf::from("/nonexistent");
+            super::super::run(s, mailbox, udp, audit, recorder, unused.clone(), unused);
+        });
+
+        let bot = |channel| bot::Bot {
+            name: "bot".to_string(),
+            channel,
+        };
+        assert!(Bot::join(actor.clone(), bot(ChannelID::new(5)))
+            .await
+            .is_none());
+
+        // the failed join did not use up a session
+        let joined = Bot::join(actor.clone(), bot(ROOT_CHANNEL)).await.unwrap();
+        assert_eq!(u32::from(joined.session()), 1);
+
+        drop(joined);
+        drop(actor);
+        state.join().unwrap();
+    }
+}
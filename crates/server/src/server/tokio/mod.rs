@@ -1,31 +1,49 @@
+pub mod bot;
 mod shutdown;
 mod tcp;
+pub mod tls;
 mod udp;
 mod unix_socket;
 
 use std::collections::HashMap;
 use std::future::Future;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use tokio::net::{TcpListener, UdpSocket, UnixListener};
 use tokio::sync::mpsc::{self};
-use tokio::sync::{broadcast, oneshot};
+use tokio::sync::{broadcast, oneshot, watch};
 use tokio::task::JoinHandle;
-use tokio_rustls::TlsAcceptor;
 
+use speakez::common::events::VoiceMessage;
 use speakez::mumble;
 use speakez::mumble::session::Session;
-use speakez::server::state::State;
+use speakez::server::recording;
+use speakez::server::snapshot::{self, Snapshot};
+use speakez::server::state::{Peer, State};
 use speakez::server::{self, state};
 
+use crate::{audit, channels};
+
 use self::shutdown::Shutdown;
 use self::udp::UdpListener;
 
 pub enum ActorMessage {
-    CreateSession(mpsc::Sender<Vec<u8>>, oneshot::Sender<Option<Session>>),
+    CreateSession(
+        mpsc::Sender<Vec<u8>>,
+        Peer,
+        oneshot::Sender<Option<Session>>,
+    ),
     Message(server::Message),
+    CreateBot(
+        server::bot::Bot,
+        mpsc::Sender<VoiceMessage>,
+        oneshot::Sender<Option<Session>>,
+    ),
+    /// Write a snapshot of the server state to disk.
+    Snapshot,
 }
 
 fn drain_messages(
@@ -47,11 +65,11 @@ fn drain_messages(
                     .iter()
                     .filter(move |(session, _mailbox)| sessions.contains(session)),
                 server::Destination::Single(ref session) => {
-                    let found = mailboxes
-                        .iter()
-                        .find(|(s, _mailbox)| *s == session)
-                        .unwrap();
-                    &mut std::iter::once(found)
+                    match mailboxes.iter().find(|(s, _mailbox)| *s == session) {
+                        Some(found) => &mut std::iter::once(found),
+                        // The connection has already been closed.
+                        None => continue,
+                    }
                 }
             },
             state::OutboxDestination::SocketAddr(addr) => {
@@ -68,6 +86,11 @@ fn drain_messages(
                         to_remove.push(*session)
                     }
                 }
+                state::OutboxType::Disconnect => {
+                    // Dropping the mailbox closes the connection once the queued messages
+                    // have been written.
+                    to_remove.push(*session);
+                }
                 state::OutboxType::Voice => {
                     // Handle session not existing anymore
                     let info = match s.session_info.get_mut(session) {
@@ -95,6 +118,8 @@ fn drain_messages(
                             info.voice_crypter.encrypt(&mut b);
                             udp_mailbox.blocking_send((b.to_vec(), addr)).unwrap();
                         }
+                        // bots hear audio through the bot outbox
+                        state::VoiceTransport::Bot => continue,
                     }
                 }
             }
@@ -106,40 +131,136 @@ fn drain_messages(
     }
 }
 
+fn drain_bots(bots: &mut HashMap<Session, mpsc::Sender<VoiceMessage>>, s: &mut State) {
+    for (session, msg) in s.bot_outbox.drain(..) {
+        let Some(mailbox) = bots.get(&session) else {
+            continue;
+        };
+        match mailbox.try_send(msg) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                tracing::warn!("dropping audio for slow bot {:?}", session);
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                bots.remove(&session);
+            }
+        }
+    }
+    // bots that were disconnected stop receiving audio
+    bots.retain(|session, _| s.session_info.contains_key(session));
+}
+
+fn drain_audit(audit_log: &std::sync::mpsc::Sender<audit::Record>, s: &mut State) {
+    for entry in s.audit.drain(..) {
+        if audit_log.send(audit::Record::new(entry)).is_err() {
+            tracing::error!("audit log writer has stopped");
+        }
+    }
+}
+
+fn drain_recordings(recorder: &std::sync::mpsc::Sender<recording::Event>, s: &mut State) {
+    for event in s.recorder.drain(..) {
+        if recorder.send(event).is_err() {
+            tracing::error!("recorder has stopped");
+        }
+    }
+}
+
+/// Snapshots are written on their own thread so the server is not blocked on disk.
+fn write_snapshot(path: PathBuf, snapshot: Snapshot) {
+    std::thread::spawn(move || {
+        let written = serde_json::to_vec_pretty(&snapshot)
+            .map_err(std::io::Error::from)
+            .and_then(|data| std::fs::write(&path, data));
+        match written {
+            Ok(()) => tracing::info!("wrote snapshot to {}", path.display()),
+            Err(e) => tracing::error!("failed to write snapshot to {}: {}", path.display(), e),
+        }
+    });
+}
+
+/// Channel changes are rare, they are saved right away so they are written in order.
+fn save_channels(path: &Path, s: &mut State) {
+    if !std::mem::take(&mut s.channels_changed) {
+        return;
+    }
+    if let Err(e) = channels::save(path, s.channels()) {
+        tracing::error!("failed to save channels to {}: {}", path.display(), e);
+    }
+}
+
 pub fn run(
     mut s: state::State,
     mut recv: mpsc::Receiver<ActorMessage>,
     mut udp_mailbox: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    audit_log: std::sync::mpsc::Sender<audit::Record>,
+    recorder: std::sync::mpsc::Sender<recording::Event>,
+    snapshot_path: PathBuf,
+    channels_path: PathBuf,
 ) {
+    // recordings enabled in the configuration are started before the loop
+    drain_recordings(&recorder, &mut s);
+
     let mut mailboxes = HashMap::with_capacity(s.config.max_users.into());
+    let mut bots = HashMap::new();
 
     while let Some(message) = recv.blocking_recv() {
         let msg = match message {
-            ActorMessage::CreateSession(mailbox, resp) => {
+            ActorMessage::CreateSession(mailbox, peer, resp) => {
                 let session = s.new_session();
                 resp.send(session).unwrap();
 
                 if let Some(session) = session {
                     mailboxes.insert(session, mailbox);
-                    server::Message::SessionCreated(session)
+                    server::Message::SessionCreated(session, peer)
                 } else {
                     todo!("no session available");
                 }
             }
             ActorMessage::Message(m) => m,
+            ActorMessage::CreateBot(bot, audio, resp) => {
+                if !server::bot::can_join(&s, &bot) {
+                    tracing::warn!("bot {} can not join channel {:?}", bot.name, bot.channel);
+                    let _ = resp.send(None);
+                    continue;
+                }
+                let Some(session) = s.new_session() else {
+                    tracing::warn!("no session available for bot {}", bot.name);
+                    let _ = resp.send(None);
+                    continue;
+                };
+                if resp.send(Some(session)).is_err() {
+                    // the bot gave up waiting, nobody would remove it
+                    s.delete_session(session);
+                    continue;
+                }
+                bots.insert(session, audio);
+                server::Message::BotCreated(session, bot)
+            }
+            ActorMessage::Snapshot => {
+                write_snapshot(
+                    snapshot_path.clone(),
+                    snapshot::take(&mut s, Instant::now()),
+                );
+                continue;
+            }
         };
 
         let now = Instant::now();
         s = server::handle_message(s, msg, now);
         drain_messages(&mut mailboxes, &mut udp_mailbox, &mut s);
+        drain_bots(&mut bots, &mut s);
+        drain_audit(&audit_log, &mut s);
+        drain_recordings(&recorder, &mut s);
+        save_channels(&channels_path, &mut s);
     }
 }
 
 pub async fn run_io(
-    tcp_listener: TcpListener,
-    udp_socket: UdpSocket,
+    tcp_listeners: Vec<TcpListener>,
+    udp_sockets: Vec<UdpSocket>,
     unix_socket: UnixListener,
-    acceptor: TlsAcceptor,
+    acceptor: tls::Acceptor,
     actor_mailbox: mpsc::Sender<ActorMessage>,
     udp_mailbox: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
     shutdown: impl Future,
@@ -162,7 +283,7 @@ pub async fn run_io(
     let sender = actor_mailbox.clone();
 
     let udp_listener = UdpListener {
-        udp_socket: Arc::new(udp_socket),
+        udp_sockets: udp_sockets.into_iter().map(Arc::new).collect(),
         mailbox: udp_mailbox,
         sender,
         shutdown: udp_shutdown,
@@ -179,9 +300,24 @@ pub async fn run_io(
         shutdown_waiter,
     );
 
+    let snapshot_shutdown = Shutdown::new(notify_shutdown.subscribe());
+    let shutdown_waiter = shutdown_complete_tx.clone();
+    let mailbox = actor_mailbox.clone();
+    run_snapshot_signal(mailbox, snapshot_shutdown, shutdown_waiter);
+
+    let (acceptor_tx, acceptor_rx) = watch::channel(acceptor.current);
+    let reloader_shutdown = Shutdown::new(notify_shutdown.subscribe());
+    let shutdown_waiter = shutdown_complete_tx.clone();
+    tls::run_reloader(
+        acceptor.certs,
+        acceptor_tx,
+        reloader_shutdown,
+        shutdown_waiter,
+    );
+
     let mut server = tcp::Listener {
-        tcp_listener,
-        acceptor,
+        tcp_listeners,
+        acceptor: acceptor_rx,
         actor_mailbox,
         notify_shutdown,
         shutdown_complete_tx,
@@ -258,3 +394,29 @@ fn run_ticker(
         drop(waiter);
     })
 }
+
+/// Spawn a new tokio task that asks for a snapshot of the server state every time the
+/// process receives SIGUSR1.
+fn run_snapshot_signal(
+    mailbox: mpsc::Sender<ActorMessage>,
+    mut shutdown: Shutdown,
+    waiter: mpsc::Sender<()>,
+) -> JoinHandle<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut signals = signal(SignalKind::user_defined1()).unwrap();
+        loop {
+            tokio::select! {
+                _ = signals.recv() => {},
+                _ = shutdown.recv() => break,
+            };
+
+            if mailbox.send(ActorMessage::Snapshot).await.is_err() {
+                break;
+            }
+        }
+
+        drop(waiter);
+    })
+}
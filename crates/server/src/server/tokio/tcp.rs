@@ -1,21 +1,26 @@
+use std::future::poll_fn;
 use std::io;
+use std::net::SocketAddr;
+use std::task::Poll;
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpListener;
-use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 use tokio_rustls::TlsAcceptor;
 use tracing::{Instrument, Span};
 
+use speakez::server::state::Peer;
 use speakez::{mumble, server};
 
 use super::shutdown::Shutdown;
-use super::ActorMessage;
+use super::{tls, ActorMessage};
 
 /// Handles TCP connections.
 pub struct Listener {
-    pub tcp_listener: TcpListener,
+    pub tcp_listeners: Vec<TcpListener>,
 
-    pub acceptor: TlsAcceptor,
+    /// Updated whenever the certificates are reloaded, only affects new connections.
+    pub acceptor: watch::Receiver<TlsAcceptor>,
     pub actor_mailbox: mpsc::Sender<ActorMessage>,
 
     /// Broadcasts a shutdown signal to all active connections.
@@ -27,14 +32,33 @@ pub struct Listener {
 }
 
 impl Listener {
+    /// Accept a connection from whichever listener has one ready. Polling starts at
+    /// `next`, which is moved past the listener that accepted so a busy listener can not
+    /// starve the others.
+    async fn accept(&self, next: &mut usize) -> io::Result<(TcpStream, SocketAddr)> {
+        let count = self.tcp_listeners.len();
+        poll_fn(|cx| {
+            for i in 0..count {
+                let index = (*next + i) % count;
+                if let Poll::Ready(res) = self.tcp_listeners[index].poll_accept(cx) {
+                    *next = (index + 1) % count;
+                    return Poll::Ready(res);
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
     pub async fn run(&mut self) -> Result<(), ()> {
-        while let Ok((stream, addr)) = self.tcp_listener.accept().await {
+        let mut next = 0;
+        while let Ok((stream, addr)) = self.accept(&mut next).await {
             // TODO: remove once tokio thread can be notified of main thread stopping
             if self.actor_mailbox.is_closed() {
                 return Ok(());
             }
 
-            let acceptor = self.acceptor.clone();
+            let acceptor = self.acceptor.borrow().clone();
             let cloned_mailbox = self.actor_mailbox.clone();
             let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
             let _shutdown_complete = self.shutdown_complete_tx.clone();
@@ -48,12 +72,21 @@ impl Listener {
                     }
                 };
 
+                let certs = stream.get_ref().1.peer_certificates().unwrap_or_default();
+                let peer = Peer {
+                    addr: Some(addr),
+                    cert_hash: certs.first().map(tls::cert_hash),
+                    certificates: certs.iter().map(|c| c.to_vec()).collect(),
+                    admin: false,
+                };
+
                 let (reader, writer) = tokio::io::split(stream);
                 let (sender, mailbox) = mpsc::channel(20);
 
                 let handler = Handler {
                     reader,
                     writer,
+                    peer,
                     sender,
                     mailbox,
                     actor_mailbox: cloned_mailbox,
@@ -86,6 +119,7 @@ impl Listener {
 pub struct Handler<R, W> {
     pub reader: R,
     pub writer: W,
+    pub peer: Peer,
     pub actor_mailbox: mpsc::Sender<ActorMessage>,
 
     pub mailbox: mpsc::Receiver<Vec<u8>>,
@@ -112,7 +146,7 @@ where
     pub async fn run(mut self) -> io::Result<()> {
         let (sender, reciever) = oneshot::channel();
         self.actor_mailbox
-            .send(ActorMessage::CreateSession(self.sender, sender))
+            .send(ActorMessage::CreateSession(self.sender, self.peer, sender))
             .await
             .unwrap();
         let session = reciever
@@ -177,8 +211,9 @@ where
     }
 }
 
+/// Read a single message into `buf`, growing it for messages larger than its length.
 pub async fn read_message<T>(
-    buf: &mut [u8],
+    buf: &mut Vec<u8>,
     mut reader: T,
 ) -> std::io::Result<(mumble::control::MessageType, usize)>
 where
@@ -188,13 +223,15 @@ where
     debug_assert_eq!(prefix.len(), mumble::control::proto::PREFIX_TOTAL_SIZE);
 
     reader.read_exact(prefix).await?;
-    let (typ, size) = mumble::control::parse_prefix(prefix);
+    let (typ, size) = mumble::control::parse_prefix(prefix)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
     let buf_size = mumble::control::proto::PREFIX_TOTAL_SIZE + size;
+    if buf.len() < buf_size {
+        buf.resize(buf_size, 0);
+    }
     let msg_body = &mut buf[mumble::control::proto::PREFIX_TOTAL_SIZE..buf_size];
-    debug_assert_eq!(msg_body.len(), size);
-
-    reader.read_exact(msg_body).await.unwrap();
+    reader.read_exact(msg_body).await?;
 
     Ok((typ, buf_size))
 }
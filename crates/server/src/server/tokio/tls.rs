@@ -0,0 +1,301 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use aws_lc_rs::digest;
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::UnixTime;
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{DigitallySignedStruct, DistinguishedName, SignatureScheme};
+use rustls_pemfile::{certs, private_key};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::TlsAcceptor;
+
+use super::shutdown::Shutdown;
+
+/// PEM encoded certificate chain and private key used for incoming TLS connections.
+#[derive(Clone, Debug)]
+pub struct Certificates {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// How often the files are checked for changes.
+    pub poll_interval: Duration,
+}
+
+/// The currently loaded acceptor along with the files it was loaded from.
+pub struct Acceptor {
+    pub(crate) certs: Certificates,
+    pub(crate) current: TlsAcceptor,
+}
+
+impl Certificates {
+    pub fn load(self) -> io::Result<Acceptor> {
+        let current = self.acceptor()?;
+        Ok(Acceptor {
+            certs: self,
+            current,
+        })
+    }
+
+    fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let certs = load_certs(&self.cert)?;
+        let key = load_key(&self.key)?;
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .and_then(|b| {
+                b.with_client_cert_verifier(Arc::new(AnyClientCert(provider)))
+                    .with_single_cert(certs, key)
+            })
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert = std::fs::metadata(&self.cert).and_then(|m| m.modified());
+        let key = std::fs::metadata(&self.key).and_then(|m| m.modified());
+        cert.ok().zip(key.ok())
+    }
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    certs(&mut BufReader::new(File::open(path)?)).collect()
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    private_key(&mut BufReader::new(File::open(path)?))?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no private key found in {}", path.display()),
+        )
+    })
+}
+
+/// Clients identify themselves with self-signed certificates, any certificate is accepted
+/// and its hash is used to recognise the client.
+#[derive(Debug)]
+struct AnyClientCert(Arc<CryptoProvider>);
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Hex encoded SHA1 hash of the certificate, the same format mumble uses.
+pub(crate) fn cert_hash(cert: &CertificateDer<'_>) -> String {
+    let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, cert);
+    hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Spawn a new tokio task that replaces the acceptor when SIGHUP is received or the
+/// certificate files change. Existing connections keep the configuration they were
+/// accepted with.
+pub(crate) fn run_reloader(
+    certs: Certificates,
+    acceptor: watch::Sender<TlsAcceptor>,
+    mut shutdown: Shutdown,
+    waiter: mpsc::Sender<()>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!("failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+
+        let mut ticker = tokio::time::interval(certs.poll_interval);
+        let mut loaded = certs.modified();
+        let mut observed = loaded;
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    tracing::info!("SIGHUP received, reloading TLS certificates");
+                },
+                _ = ticker.tick() => {
+                    // Certificate and key are usually written one after the other, wait until
+                    // both have stopped changing before loading them.
+                    let modified = certs.modified();
+                    let settled = modified == observed;
+                    observed = modified;
+                    if !settled || modified == loaded {
+                        continue;
+                    }
+                    tracing::info!("TLS certificate files changed, reloading");
+                },
+                _ = shutdown.recv() => break,
+            };
+
+            match certs.acceptor() {
+                Ok(new) => {
+                    loaded = certs.modified();
+                    acceptor.send_replace(new);
+                    tracing::info!("TLS certificates reloaded");
+                }
+                Err(e) => {
+                    tracing::error!("failed to reload TLS certificates, keeping current: {}", e);
+                }
+            }
+        }
+
+        drop(waiter);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::FileTimes;
+
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    const POLL: Duration = Duration::from_millis(10);
+
+    /// Write a new certificate and key, bumping their modification time so the change
+    /// is noticed regardless of the file system's timestamp resolution.
+    fn write_pair(certs: &Certificates, bump: u64) -> CertificateDer<'static> {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        write(&certs.cert, cert.pem().as_bytes(), bump);
+        write(&certs.key, key.serialize_pem().as_bytes(), bump);
+        cert.der().clone()
+    }
+
+    fn write(path: &Path, data: &[u8], bump: u64) {
+        std::fs::write(path, data).unwrap();
+        let time = SystemTime::now() + Duration::from_secs(bump);
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_times(FileTimes::new().set_modified(time))
+            .unwrap();
+    }
+
+    /// Whether a client that only trusts `cert` can complete a handshake with `acceptor`.
+    async fn serves(acceptor: &TlsAcceptor, cert: &CertificateDer<'static>) -> bool {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let (client, server) =
+            tokio::join!(connector.connect(name, client), acceptor.accept(server));
+        client.is_ok() && server.is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_reloader() {
+        let dir = std::env::temp_dir().join(format!("speakez-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let certs = Certificates {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            poll_interval: POLL,
+        };
+        let first = write_pair(&certs, 0);
+
+        let initial = certs.clone().load().unwrap().current;
+        let (tx, mut rx) = watch::channel(initial.clone());
+        let (notify, _) = broadcast::channel(1);
+        let (waiter, mut done) = mpsc::channel(1);
+        let handle = run_reloader(certs.clone(), tx, Shutdown::new(notify.subscribe()), waiter);
+        // let the reloader record the current modification times
+        tokio::time::sleep(POLL * 5).await;
+
+        let second = write_pair(&certs, 10);
+        tokio::time::timeout(Duration::from_secs(5), rx.changed())
+            .await
+            .expect("acceptor was not reloaded")
+            .unwrap();
+        let reloaded = rx.borrow_and_update().clone();
+        assert!(serves(&initial, &first).await);
+        assert!(serves(&reloaded, &second).await);
+        assert!(!serves(&reloaded, &first).await);
+
+        // a half written key is not loaded, the current acceptor is kept
+        let key = std::fs::read(&certs.key).unwrap();
+        write(&certs.key, &key[..key.len() / 2], 20);
+        tokio::time::sleep(POLL * 20).await;
+        assert!(!rx.has_changed().unwrap());
+
+        // once the key is complete it is picked up
+        write(&certs.key, &key, 30);
+        tokio::time::timeout(Duration::from_secs(5), rx.changed())
+            .await
+            .expect("acceptor was not reloaded")
+            .unwrap();
+        let completed = rx.borrow().clone();
+        assert!(serves(&completed, &second).await);
+
+        notify.send(()).unwrap();
+        handle.await.unwrap();
+        assert!(done.recv().await.is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
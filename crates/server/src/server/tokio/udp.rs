@@ -1,6 +1,10 @@
-use std::net::SocketAddr;
+use std::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::task::Poll;
 
+use tokio::io::ReadBuf;
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc;
 
@@ -10,16 +14,95 @@ use super::shutdown::Shutdown;
 use super::ActorMessage;
 
 pub(crate) struct UdpListener {
-    pub udp_socket: Arc<UdpSocket>,
+    pub udp_sockets: Vec<Arc<UdpSocket>>,
     pub sender: mpsc::Sender<ActorMessage>,
     pub mailbox: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
     pub shutdown: Shutdown,
     pub waiter: mpsc::Sender<()>,
 }
 
+/// A socket that packets can be sent from.
+struct Route {
+    socket: Arc<UdpSocket>,
+    local: SocketAddr,
+    /// IPv6 socket that also accepts IPv4 traffic.
+    dual_stack: bool,
+}
+
+impl Route {
+    fn new(socket: Arc<UdpSocket>) -> io::Result<Self> {
+        let local = socket.local_addr()?;
+        let dual_stack = local.is_ipv6() && !socket2::SockRef::from(&*socket).only_v6()?;
+        Ok(Route {
+            socket,
+            local,
+            dual_stack,
+        })
+    }
+}
+
+/// Pick the socket to reach `to` from, along with the address to send to.
+///
+/// Sockets of the same family are preferred, wildcard addresses first so the kernel
+/// picks the source address. IPv4 peers can otherwise be reached from a dual-stack
+/// socket with the IPv4-mapped address.
+fn route(routes: &[Route], to: SocketAddr) -> Option<(&UdpSocket, SocketAddr)> {
+    let same_family = |r: &&Route| r.local.is_ipv4() == to.is_ipv4();
+    let found = routes
+        .iter()
+        .filter(same_family)
+        .find(|r| r.local.ip().is_unspecified())
+        .or_else(|| routes.iter().find(same_family));
+    if let Some(r) = found {
+        return Some((&r.socket, to));
+    }
+
+    match to.ip() {
+        IpAddr::V4(ip) => routes.iter().find(|r| r.dual_stack).map(|r| {
+            let mapped = SocketAddr::new(ip.to_ipv6_mapped().into(), to.port());
+            (&*r.socket, mapped)
+        }),
+        IpAddr::V6(_) => None,
+    }
+}
+
+/// Receive from whichever socket has a packet ready. Polling starts at `next`, which is
+/// moved past the socket that received so a busy socket can not starve the others.
+async fn recv_from(
+    sockets: &[Arc<UdpSocket>],
+    buf: &mut [u8],
+    next: &mut usize,
+) -> io::Result<(usize, SocketAddr)> {
+    let count = sockets.len();
+    poll_fn(|cx| {
+        for i in 0..count {
+            let index = (*next + i) % count;
+            let mut read_buf = ReadBuf::new(buf);
+            if let Poll::Ready(res) = sockets[index].poll_recv_from(cx, &mut read_buf) {
+                *next = (index + 1) % count;
+                return Poll::Ready(res.map(|from| (read_buf.filled().len(), from)));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
 impl UdpListener {
     pub async fn run(mut self) {
-        let reader = self.udp_socket.clone();
+        let routes = match self
+            .udp_sockets
+            .iter()
+            .cloned()
+            .map(Route::new)
+            .collect::<io::Result<Vec<_>>>()
+        {
+            Ok(r) => r,
+            Err(e) => {
+                tracing::error!("failed to inspect udp sockets: {}", e);
+                return;
+            }
+        };
 
         let mut writer = tokio::spawn(async move {
             loop {
@@ -31,19 +114,27 @@ impl UdpListener {
                     },
                 };
 
-                self.udp_socket.send_to(&data, to).await.unwrap();
+                let Some((socket, to)) = route(&routes, to) else {
+                    tracing::warn!("no udp socket can reach {}", to);
+                    continue;
+                };
+                if let Err(e) = socket.send_to(&data, to).await {
+                    tracing::warn!("failed to send udp packet to {}: {}", to, e);
+                }
             }
             tracing::debug!("tokio udp writer server shutdown");
         });
 
         let mailbox = self.sender.clone();
+        let sockets = self.udp_sockets;
         let mut reader = tokio::spawn(async move {
             let mut buf = vec![0u8; mumble::voice::MAX_UDP_PACKET_SIZE];
+            let mut next = 0;
 
             loop {
                 let (size, from) = tokio::select! {
                     _ = self.shutdown.recv() => break,
-                    res = reader.recv_from(&mut buf) => match res {
+                    res = recv_from(&sockets, &mut buf, &mut next) => match res {
                         Ok(v) => v,
                         Err(e) => todo!("error: {}",e),
                     }
@@ -68,3 +159,33 @@ impl UdpListener {
         tracing::debug!("tokio udp server shutdown");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_recv_from_alternates() {
+        let bind = || async { Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()) };
+        let sockets = vec![bind().await, bind().await];
+        let client = bind().await;
+        for socket in &sockets {
+            for _ in 0..3 {
+                let to = socket.local_addr().unwrap();
+                client.send_to(&[0], to).await.unwrap();
+            }
+        }
+        // wait until every packet has arrived
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let mut buf = [0u8; 16];
+        let mut next = 0;
+        let mut received = vec![];
+        for _ in 0..4 {
+            recv_from(&sockets, &mut buf, &mut next).await.unwrap();
+            received.push(next);
+        }
+        // `next` points past the socket that received, both sockets take turns
+        assert_eq!(received, [1, 0, 1, 0]);
+    }
+}
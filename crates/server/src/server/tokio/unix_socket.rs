@@ -3,6 +3,8 @@ use std::io;
 use tokio::sync::{broadcast, mpsc};
 use tracing::Instrument;
 
+use speakez::server::state::Peer;
+
 use super::shutdown::Shutdown;
 use super::tcp::Handler;
 use super::ActorMessage;
@@ -36,6 +38,13 @@ impl UnixListener {
                 let handler = Handler {
                     reader,
                     writer,
+                    // The socket is only accessible to the user running the server.
+                    peer: Peer {
+                        addr: None,
+                        cert_hash: None,
+                        certificates: vec![],
+                        admin: true,
+                    },
                     sender,
                     mailbox,
                     actor_mailbox: cloned_mailbox,
@@ -0,0 +1,357 @@
+//! Drives simulated clients through `server::handle_message` without any sockets.
+//!
+//! Messages are handed to the state machine one at a time and the outbox is delivered
+//! to the clients right after, the same way the tokio actor does. The clock only moves
+//! when a test advances it, so every run sees the same sequence of events.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+
+use speakez::common::Channel;
+use speakez::mumble::control::{self, MessageBuf, MessageType};
+use speakez::mumble::session::Session;
+use speakez::mumble::voice;
+use speakez::server::state::{
+    OutboxDestination, OutboxType, Peer, State, VoiceCrypter, VoiceTransport,
+};
+use speakez::server::{self, Destination, Message};
+use speakez_server::mumble::crypt::{CryptState, BLOCK_SIZE, KEY_SIZE};
+
+thread_local! {
+    /// Sessions get keys 1, 2, 3... so runs are reproducible. Keys have to differ,
+    /// the server finds the session of a new UDP address by trying each crypt.
+    static NEXT_KEY: Cell<u8> = const { Cell::new(1) };
+}
+
+fn new_crypter() -> Box<dyn VoiceCrypter> {
+    let key = NEXT_KEY.get();
+    NEXT_KEY.set(key + 1);
+    Box::new(CryptState::new_from_key([key; KEY_SIZE]))
+}
+
+pub struct Client {
+    pub name: String,
+    pub addr: SocketAddr,
+    /// Set once the server has sent CryptSetup.
+    crypt: Option<CryptState>,
+    /// Send voice over UDP instead of tunneling it over TCP.
+    udp: bool,
+    frame_number: u64,
+    control: Vec<MessageBuf>,
+    voice: Vec<voice::Message>,
+    /// The server closed the connection.
+    pub disconnected: bool,
+}
+
+pub struct Sim {
+    state: Option<State>,
+    now: Instant,
+    /// Ordered so outbox delivery does not depend on hashing.
+    clients: BTreeMap<u32, Client>,
+    /// Sessions looked up by their UDP address, for unencrypted pings.
+    addrs: HashMap<SocketAddr, Session>,
+}
+
+fn decode<M: control::Message + Default>(buf: &MessageBuf) -> M {
+    M::decode(buf.body()).unwrap()
+}
+
+fn encode_voice(m: voice::Message) -> Vec<u8> {
+    let mut buf = vec![0u8; voice::MAX_UDP_PACKET_SIZE];
+    let size = m.encode(&mut buf).unwrap();
+    buf.truncate(size);
+    buf
+}
+
+impl Sim {
+    pub fn new(channels: Vec<Channel>) -> Self {
+        NEXT_KEY.set(1);
+        let mut s = State::new(100, new_crypter);
+        for c in channels {
+            s.new_channel(c);
+        }
+        Sim {
+            state: Some(s),
+            now: Instant::now(),
+            clients: BTreeMap::new(),
+            addrs: HashMap::new(),
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        self.state.as_ref().unwrap()
+    }
+
+    pub fn state_mut(&mut self) -> &mut State {
+        self.state.as_mut().unwrap()
+    }
+
+    pub fn advance(&mut self, d: Duration) {
+        self.now += d;
+    }
+
+    pub fn client(&self, session: Session) -> &Client {
+        &self.clients[&u32::from(session)]
+    }
+
+    fn client_mut(&mut self, session: Session) -> &mut Client {
+        self.clients.get_mut(&u32::from(session)).unwrap()
+    }
+
+    /// Handle a message at the current time and deliver the outbox.
+    pub fn handle(&mut self, msg: Message) {
+        let s = self.state.take().unwrap();
+        self.state = Some(server::handle_message(s, msg, self.now));
+        self.deliver();
+    }
+
+    pub fn tick(&mut self) {
+        self.handle(Message::Tick);
+    }
+
+    /// Open a connection, nothing is sent until `handshake` is called.
+    pub fn accept(&mut self, name: &str, peer: Peer) -> Session {
+        let session = self.state_mut().new_session().unwrap();
+        let addr = SocketAddr::from((
+            Ipv4Addr::new(10, 0, 0, 1),
+            40000 + u32::from(session) as u16,
+        ));
+        let client = Client {
+            name: name.to_string(),
+            addr,
+            crypt: None,
+            udp: false,
+            frame_number: 0,
+            control: vec![],
+            voice: vec![],
+            disconnected: false,
+        };
+        self.clients.insert(session.into(), client);
+        self.addrs.insert(addr, session);
+        self.handle(Message::SessionCreated(
+            session,
+            Peer {
+                addr: Some(addr),
+                ..peer
+            },
+        ));
+        session
+    }
+
+    pub fn handshake(&mut self, session: Session) {
+        let auth = control::proto::Authenticate {
+            username: Some(self.client(session).name.clone()),
+            password: Some(String::new()),
+            ..Default::default()
+        };
+        self.send(session, server::version());
+        self.send(session, auth);
+    }
+
+    /// Connect a client and complete the handshake.
+    pub fn connect(&mut self, name: &str) -> Session {
+        self.connect_with_peer(name, Peer::default())
+    }
+
+    pub fn connect_with_peer(&mut self, name: &str, peer: Peer) -> Session {
+        let session = self.accept(name, peer);
+        self.handshake(session);
+        session
+    }
+
+    pub fn disconnect(&mut self, session: Session) {
+        self.client_mut(session).disconnected = true;
+        self.handle(Message::SessionDisconnect(session));
+    }
+
+    pub fn send(&mut self, session: Session, m: impl control::Message) {
+        let buf = MessageBuf {
+            typ: m.message_type(),
+            data: m.as_vec(),
+        };
+        self.handle(Message::Mumble(session, buf));
+    }
+
+    /// Send an encrypted UDP ping so the server switches the client to UDP.
+    pub fn enable_udp(&mut self, session: Session) {
+        self.client_mut(session).udp = true;
+        let ping = voice::Message::Ping(voice::Ping {
+            timestamp: 1,
+            ..Default::default()
+        });
+        self.send_udp(session, ping);
+    }
+
+    /// Send an unencrypted UDP packet, like a client checking the server is reachable.
+    pub fn send_udp_plain(&mut self, session: Session, m: voice::Message) {
+        let addr = self.client(session).addr;
+        self.handle(Message::UDP(addr, encode_voice(m)));
+    }
+
+    fn send_udp(&mut self, session: Session, m: voice::Message) {
+        let client = self.client_mut(session);
+        let crypt = client
+            .crypt
+            .as_mut()
+            .expect("client has not received CryptSetup");
+
+        let mut packet = BytesMut::from(&[0u8; 4][..]);
+        packet.extend_from_slice(&encode_voice(m));
+        crypt.encrypt(&mut packet);
+
+        let addr = client.addr;
+        self.handle(Message::UDP(addr, packet.to_vec()));
+    }
+
+    /// Speak one frame of `data`, over UDP once `enable_udp` has been called.
+    pub fn speak(&mut self, session: Session, target: u32, data: Vec<u8>) {
+        let client = self.client_mut(session);
+        let audio = voice::Audio {
+            header: Some(voice::audio::Header::Target(target)),
+            frame_number: client.frame_number,
+            opus_data: data,
+            ..Default::default()
+        };
+        client.frame_number += 1;
+
+        if client.udp {
+            self.send_udp(session, voice::Message::Audio(audio));
+            return;
+        }
+        let body = encode_voice(voice::Message::Audio(audio));
+        let mut data = vec![0u8; control::proto::PREFIX_TOTAL_SIZE + body.len()];
+        control::encode_udp_tunnel(&body, &mut data);
+        self.handle(Message::Mumble(
+            session,
+            MessageBuf {
+                typ: MessageType::UDPTunnel,
+                data,
+            },
+        ));
+    }
+
+    /// Take the control messages of type `M` received by the client, in order.
+    pub fn received<M: control::Message + Default>(&mut self, session: Session) -> Vec<M> {
+        let typ = M::default().message_type();
+        let client = self.client_mut(session);
+        let (matching, rest) = client.control.drain(..).partition(|m| m.typ == typ);
+        client.control = rest;
+        matching.iter().map(decode).collect()
+    }
+
+    /// Take the audio received by the client, in order.
+    pub fn heard(&mut self, session: Session) -> Vec<voice::Audio> {
+        let client = self.client_mut(session);
+        let (audio, rest) = client
+            .voice
+            .drain(..)
+            .partition(|m| matches!(m, voice::Message::Audio(_)));
+        client.voice = rest;
+        audio
+            .into_iter()
+            .filter_map(|m| match m {
+                voice::Message::Audio(a) => Some(a),
+                voice::Message::Ping(_) => None,
+            })
+            .collect()
+    }
+
+    /// Take the UDP pings received by the client.
+    pub fn pings(&mut self, session: Session) -> Vec<voice::Ping> {
+        let client = self.client_mut(session);
+        client
+            .voice
+            .drain(..)
+            .filter_map(|m| match m {
+                voice::Message::Ping(p) => Some(p),
+                voice::Message::Audio(_) => None,
+            })
+            .collect()
+    }
+
+    /// Drop everything the client has received so far.
+    pub fn clear(&mut self, session: Session) {
+        let client = self.client_mut(session);
+        client.control.clear();
+        client.voice.clear();
+    }
+
+    fn deliver(&mut self) {
+        let outbox: Vec<_> = self.state_mut().outbox.drain(..).collect();
+        for msg in outbox {
+            let sessions: Vec<Session> = match msg.dest {
+                OutboxDestination::Session(dest) => self
+                    .clients
+                    .iter()
+                    .filter(|(_, c)| !c.disconnected)
+                    .map(|(id, _)| Session::new(*id).unwrap())
+                    .filter(|session| match &dest {
+                        Destination::All => true,
+                        Destination::AllButOne(s) => s != session,
+                        Destination::Single(s) => s == session,
+                        Destination::Group(group) => group.contains(session),
+                    })
+                    .collect(),
+                OutboxDestination::SocketAddr(addr) => {
+                    // unencrypted replies to pings from unknown addresses
+                    if let Some(&session) = self.addrs.get(&addr) {
+                        let m = voice::Message::decode(&msg.data).unwrap();
+                        self.client_mut(session).voice.push(m);
+                    }
+                    continue;
+                }
+            };
+
+            for session in sessions {
+                match msg.typ {
+                    OutboxType::Control => self.deliver_control(session, &msg.data),
+                    OutboxType::Voice => self.deliver_voice(session, &msg.data),
+                    OutboxType::Disconnect => self.client_mut(session).disconnected = true,
+                }
+            }
+        }
+    }
+
+    fn deliver_control(&mut self, session: Session, data: &[u8]) {
+        let buf = MessageBuf::parse(data.to_vec()).unwrap();
+        let typ = buf.typ;
+
+        if typ == MessageType::CryptSetup {
+            let setup: control::proto::CryptSetup = decode(&buf);
+            let nonce = |n: Option<Vec<u8>>| -> [u8; BLOCK_SIZE] { n.unwrap().try_into().unwrap() };
+            self.client_mut(session).crypt = Some(CryptState::new_from(
+                setup.key.unwrap().try_into().unwrap(),
+                nonce(setup.client_nonce),
+                nonce(setup.server_nonce),
+            ));
+        }
+        self.client_mut(session).control.push(buf);
+    }
+
+    /// Voice is encrypted with the session's server side crypt when it uses UDP.
+    fn deliver_voice(&mut self, session: Session, data: &[u8]) {
+        let s = self.state.as_mut().unwrap();
+        let Some(info) = s.session_info.get_mut(&session) else {
+            return;
+        };
+        let m = match info.voice_transport {
+            VoiceTransport::Udp(_) => {
+                let mut packet = BytesMut::from(&[0u8; 4][..]);
+                packet.extend_from_slice(data);
+                info.voice_crypter.encrypt(&mut packet);
+
+                let crypt = self.clients.get_mut(&u32::from(session)).unwrap();
+                let crypt = crypt.crypt.as_mut().unwrap();
+                crypt.decrypt(&mut packet).unwrap().unwrap();
+                voice::Message::decode(&packet).unwrap()
+            }
+            VoiceTransport::Tcp => voice::Message::decode(data).unwrap(),
+            VoiceTransport::Bot => return,
+        };
+        self.client_mut(session).voice.push(m);
+    }
+}
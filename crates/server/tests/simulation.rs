@@ -0,0 +1,193 @@
+mod sim;
+
+use std::time::Duration;
+
+use speakez::common::{Channel, ChannelID, ROOT_CHANNEL};
+use speakez::mumble::control::proto;
+use speakez::mumble::voice;
+use speakez::server::state::{Peer, VoiceTransport};
+
+use sim::Sim;
+
+fn channels() -> Vec<Channel> {
+    let mut sub = Channel::new(
+        ChannelID::new(1),
+        "Sub".to_string(),
+        String::new(),
+        false,
+        None,
+    );
+    sub.parent = Some(ROOT_CHANNEL);
+    vec![
+        Channel::new(ROOT_CHANNEL, "Root".to_string(), String::new(), false, None),
+        sub,
+    ]
+}
+
+#[test]
+fn test_voice_over_tcp() {
+    let mut sim = Sim::new(channels());
+    let alice = sim.connect("alice");
+    sim.clear(alice);
+    let bob = sim.connect("bob");
+
+    let sync: Vec<proto::ServerSync> = sim.received(bob);
+    assert_eq!(sync[0].session, Some(bob.into()));
+    let joined: Vec<proto::UserState> = sim.received(alice);
+    assert_eq!(joined[0].name.as_deref(), Some("bob"));
+
+    sim.speak(alice, 0, vec![1, 2, 3]);
+    let heard = sim.heard(bob);
+    assert_eq!(heard.len(), 1);
+    assert_eq!(heard[0].sender_session, u32::from(alice));
+    assert_eq!(heard[0].opus_data, vec![1, 2, 3]);
+    assert!(sim.heard(alice).is_empty());
+}
+
+#[test]
+fn test_voice_over_udp() {
+    let mut sim = Sim::new(channels());
+    let alice = sim.connect("alice");
+    let bob = sim.connect("bob");
+
+    sim.enable_udp(alice);
+    sim.enable_udp(bob);
+    let addr = sim.client(alice).addr;
+    assert_eq!(
+        sim.state().session_info[&alice].voice_transport,
+        VoiceTransport::Udp(addr)
+    );
+    // the encrypted ping is answered over the encrypted channel
+    assert_eq!(sim.pings(alice).len(), 1);
+
+    for frame in 0..3 {
+        sim.speak(alice, 0, vec![frame]);
+        sim.advance(Duration::from_millis(20));
+    }
+    let heard = sim.heard(bob);
+    let frames: Vec<_> = heard.iter().map(|a| a.opus_data[0]).collect();
+    assert_eq!(frames, vec![0, 1, 2]);
+}
+
+#[test]
+fn test_unencrypted_ping() {
+    let mut sim = Sim::new(channels());
+    let alice = sim.connect("alice");
+
+    let ping = voice::Ping {
+        timestamp: 42,
+        request_extended_information: true,
+        ..Default::default()
+    };
+    sim.send_udp_plain(alice, voice::Message::Ping(ping));
+    let pings = sim.pings(alice);
+    assert_eq!(pings.len(), 1);
+    assert_eq!(pings[0].timestamp, 42);
+}
+
+#[test]
+fn test_channel_switch() {
+    let mut sim = Sim::new(channels());
+    let alice = sim.connect("alice");
+    let bob = sim.connect("bob");
+    sim.clear(alice);
+
+    sim.send(
+        bob,
+        proto::UserState {
+            session: Some(bob.into()),
+            channel_id: Some(1),
+            ..Default::default()
+        },
+    );
+    let moved: Vec<proto::UserState> = sim.received(alice);
+    assert_eq!(moved.len(), 1);
+    assert_eq!(moved[0].session, Some(bob.into()));
+    assert_eq!(moved[0].channel_id, Some(1));
+
+    sim.speak(alice, 0, vec![1]);
+    assert!(sim.heard(bob).is_empty());
+
+    sim.send(
+        alice,
+        proto::UserState {
+            session: Some(alice.into()),
+            channel_id: Some(1),
+            ..Default::default()
+        },
+    );
+    sim.speak(alice, 0, vec![2]);
+    assert_eq!(sim.heard(bob).len(), 1);
+}
+
+#[test]
+fn test_message_rate_limit() {
+    let mut sim = Sim::new(channels());
+    let alice = sim.connect("alice");
+    let bob = sim.connect("bob");
+    sim.clear(bob);
+
+    let text = || proto::TextMessage {
+        channel_id: vec![0],
+        message: "hello".to_string(),
+        ..Default::default()
+    };
+    let config = sim.state().config.clone();
+    for _ in 0..10 {
+        sim.send(alice, text());
+    }
+    let burst = sim.received::<proto::TextMessage>(bob).len();
+    assert_eq!(burst, config.message_burst as usize);
+
+    // tokens come back as time passes
+    sim.advance(Duration::from_secs(2));
+    sim.tick();
+    for _ in 0..5 {
+        sim.send(alice, text());
+    }
+    let refilled = sim.received::<proto::TextMessage>(bob).len();
+    assert_eq!(refilled, 2 * config.message_limit as usize);
+}
+
+#[test]
+fn test_admin_kick() {
+    let mut sim = Sim::new(channels());
+    let admin = sim.connect_with_peer(
+        "admin",
+        Peer {
+            admin: true,
+            ..Default::default()
+        },
+    );
+    let user = sim.connect("user");
+
+    sim.send(
+        admin,
+        proto::UserRemove {
+            session: user.into(),
+            reason: Some("bye".to_string()),
+            ..Default::default()
+        },
+    );
+    assert!(sim.client(user).disconnected);
+    let removed: Vec<proto::UserRemove> = sim.received(admin);
+    assert_eq!(removed[0].session, u32::from(user));
+
+    sim.disconnect(user);
+    assert!(!sim.state().session_info.contains_key(&user));
+}
+
+#[test]
+fn test_handshake_in_progress() {
+    let mut sim = Sim::new(channels());
+    let alice = sim.connect("alice");
+    let bob = sim.accept("bob", Peer::default());
+
+    // bob is not part of the server until the handshake completes
+    sim.speak(alice, 0, vec![1]);
+    assert!(sim.heard(bob).is_empty());
+
+    sim.handshake(bob);
+    sim.speak(alice, 0, vec![2]);
+    assert_eq!(sim.heard(bob).len(), 1);
+}
@@ -1,7 +1,7 @@
 use std::time::Instant;
 
 use divan::{black_box, AllocProfiler, Bencher};
-use speakez::server::state::{MumbleCryptSetup, VoiceCrypter};
+use speakez::server::state::{MumbleCryptSetup, Peer, VoiceCrypter};
 use speakez::server::Message;
 
 #[global_allocator]
@@ -55,7 +55,7 @@ fn bench_handshake_session_created(bencher: Bencher) {
             let mut s = new_state(1);
             let session = s.new_session().unwrap();
 
-            (s, Message::SessionCreated(session))
+            (s, Message::SessionCreated(session, Peer::default()))
         })
         .bench_values(|(s, msg)| black_box(speakez::server::handle_message(s, msg, now)));
 }
@@ -27,18 +27,26 @@ impl State {
     }
 
     pub fn handle_message(mut self, m: MessageBuf) -> Status {
-        self.state = self.state.handle(m);
+        if let Err(e) = self.state.handle(m) {
+            crate::tracing::warn!("ignoring handshake message: {}", e);
+        }
         match self.state {
             handshake::client::State::ServerSync(data) => {
-                let session_id = data.sync.session.unwrap();
-                let session = Session::new(session_id).unwrap();
+                let session = data
+                    .sync
+                    .session
+                    .and_then(Session::new)
+                    .expect("ServerSync is checked for a session");
 
                 let mut state = ClientState::new(session);
+                state.crypt_setup = data.state.crypt;
                 for user in data.state.users {
-                    let session = Session::new(user.session.unwrap()).unwrap();
-                    let channel = ChannelID::new(user.channel_id.unwrap());
+                    let Some(session) = user.session.and_then(Session::new) else {
+                        continue;
+                    };
+                    let channel = ChannelID::new(user.channel_id.unwrap_or_default());
                     let u = User {
-                        name: user.name.unwrap(),
+                        name: user.name.unwrap_or_default(),
                         session,
                         channel,
                     };
@@ -46,24 +54,28 @@ impl State {
                 }
 
                 for channel in data.state.channels {
-                    let id = ChannelID::new(channel.channel_id.unwrap());
+                    let Some(id) = channel.channel_id.map(ChannelID::new) else {
+                        continue;
+                    };
                     let control::proto::ChannelState {
                         name,
                         description,
                         temporary,
                         position,
                         parent,
+                        links,
                         ..
                     } = channel;
 
                     let c = Channel {
                         id,
-                        name: name.unwrap(),
-                        description: description.unwrap(),
+                        name: name.unwrap_or_default(),
+                        description: description.unwrap_or_default(),
                         temporary: temporary.unwrap_or(false),
                         max_users: None,
-                        position: position.map(|i| NonZeroI32::new(i).unwrap()),
+                        position: position.and_then(NonZeroI32::new),
                         parent: parent.map(ChannelID::new),
+                        links: links.into_iter().map(ChannelID::new).collect(),
                     };
 
                     state.channels.insert(id, c);
@@ -26,6 +26,8 @@ pub struct State {
     pub session: Session,
     pub users: HashMap<Session, User>,
     pub channels: HashMap<ChannelID, Channel>,
+    /// Keys for encrypted UDP voice from the handshake.
+    pub crypt_setup: control::proto::CryptSetup,
 
     pub outbox: Vec<Event>,
 }
@@ -36,6 +38,7 @@ impl State {
             session,
             users: HashMap::new(),
             channels: HashMap::new(),
+            crypt_setup: Default::default(),
             outbox: vec![],
         }
     }
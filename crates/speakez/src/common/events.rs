@@ -16,6 +16,10 @@ pub struct VoiceMessage {
     pub data: Vec<u8>,
     pub frame_number: u64,
     pub sender: Session,
+    /// 0 for normal talking, 31 for server loopback.
+    pub target: u32,
+    /// Set on the last frame before the sender stops talking.
+    pub is_terminator: bool,
 }
 
 impl From<VoiceMessage> for mumble::voice::Audio {
@@ -23,8 +27,9 @@ impl From<VoiceMessage> for mumble::voice::Audio {
         mumble::voice::Audio {
             opus_data: msg.data,
             frame_number: msg.frame_number,
-            // is_terminator: incoming_audio.is_terminator,
+            is_terminator: msg.is_terminator,
             sender_session: msg.sender.into(),
+            header: Some(mumble::voice::audio::Header::Target(msg.target)),
             ..Default::default()
         }
     }
@@ -180,48 +185,45 @@ pub enum Event {
     UserJoinedServer(UserJoinedServer),
 }
 
-pub fn mumble_voice_to_event(audio: mumble::voice::Audio) -> VoiceMessage {
-    let sender = Session::new(audio.sender_session).unwrap();
-    VoiceMessage {
+/// Returns None when the audio does not have a sender.
+pub fn mumble_voice_to_event(audio: mumble::voice::Audio) -> Option<VoiceMessage> {
+    let sender = Session::new(audio.sender_session)?;
+    let target = match audio.header {
+        Some(mumble::voice::audio::Header::Target(target)) => target,
+        _ => 0,
+    };
+    Some(VoiceMessage {
         data: audio.opus_data,
         frame_number: audio.frame_number,
         sender,
-    }
+        target,
+        is_terminator: audio.is_terminator,
+    })
 }
 
-fn mumble_text_to_event(e: control::proto::TextMessage) -> UserSentMessage {
-    let session = e.actor.and_then(Session::new).unwrap();
-    UserSentMessage {
+fn mumble_text_to_event(e: control::proto::TextMessage) -> Option<UserSentMessage> {
+    let session = e.actor.and_then(Session::new)?;
+    Some(UserSentMessage {
         user: session,
         channels: e.channel_id.into_iter().map(ChannelID::new).collect(),
-        recipients: e
-            .session
-            .into_iter()
-            .map(|s| Session::new(s).unwrap())
-            .collect(),
+        recipients: e.session.into_iter().filter_map(Session::new).collect(),
         message: e.message,
-    }
+    })
 }
 
-fn mumble_user_remove_to_event(e: control::proto::UserRemove) -> UserRemoved {
-    let session = Session::new(e.session).unwrap();
-    let reason = match (e.actor, e.ban()) {
-        (Some(actor), true) => {
-            let by = Session::new(actor).unwrap();
-            UserRemovedReason::Banned { by }
-        }
-        (Some(actor), false) => {
-            let by = Session::new(actor).unwrap();
-            UserRemovedReason::Kicked { by }
-        }
+fn mumble_user_remove_to_event(e: control::proto::UserRemove) -> Option<UserRemoved> {
+    let session = Session::new(e.session)?;
+    let reason = match (e.actor.and_then(Session::new), e.ban()) {
+        (Some(by), true) => UserRemovedReason::Banned { by },
+        (Some(by), false) => UserRemovedReason::Kicked { by },
         (_, _) => UserRemovedReason::Left,
     };
 
-    UserRemoved {
+    Some(UserRemoved {
         user: session,
         reason,
         reason_msg: e.reason,
-    }
+    })
 }
 
 pub trait UserState {
@@ -229,16 +231,16 @@ pub trait UserState {
 }
 
 fn mumble_user_state_to_event(s: &impl UserState, e: control::proto::UserState) -> Option<Event> {
-    let session = Session::new(e.session.unwrap()).unwrap();
+    let session = Session::new(e.session?)?;
 
     let user = match s.get_user(&session) {
         Some(u) => u,
         None => {
-            // TODO: unwrap_or(ROOT_CHANNEL)?
-            let channel_id = ChannelID::new(e.channel_id.unwrap());
+            // users join the root channel unless told otherwise
+            let channel_id = ChannelID::new(e.channel_id.unwrap_or_default());
             let event = UserJoinedServer {
                 user: session,
-                name: e.name.unwrap(),
+                name: e.name.unwrap_or_default(),
                 channel_id,
             };
             return Some(Event::UserJoinedServer(event));
@@ -265,13 +267,13 @@ fn mumble_user_state_to_event(s: &impl UserState, e: control::proto::UserState)
 }
 
 fn mumble_audio_to_event(m: &MessageBuf, sender: Option<Session>) -> Option<VoiceMessage> {
-    let msg = mumble::voice::Message::decode(m.body()).unwrap();
+    let msg = mumble::voice::Message::decode(m.body()).ok()?;
     match msg {
         mumble::voice::Message::Audio(mut audio) => {
             if audio.sender_session == 0 {
                 audio.sender_session = sender.map_or(0, |s| s.into());
             }
-            Some(mumble_voice_to_event(audio))
+            mumble_voice_to_event(audio)
         }
         mumble::voice::Message::Ping(_) => None,
     }
@@ -288,23 +290,24 @@ pub fn mumble_to_event<S: UserState>(
 
     match m.typ {
         control::MessageType::UserState => {
-            let e = control::proto::UserState::decode(m.body()).unwrap();
-            e.session?;
+            let e = control::proto::UserState::decode(m.body()).ok()?;
             mumble_user_state_to_event(s, e)
         }
         control::MessageType::UserRemove => {
-            let e = control::proto::UserRemove::decode(m.body()).unwrap();
-            let e = mumble_user_remove_to_event(e);
-            Some(Event::UserRemoved(e))
+            let mut e = control::proto::UserRemove::decode(m.body()).ok()?;
+            if e.actor.is_none() {
+                e.actor = sender.map(|s| s.into())
+            }
+
+            mumble_user_remove_to_event(e).map(Event::UserRemoved)
         }
         control::MessageType::TextMessage => {
-            let mut e = control::proto::TextMessage::decode(m.body()).unwrap();
+            let mut e = control::proto::TextMessage::decode(m.body()).ok()?;
             if e.actor.is_none() {
                 e.actor = sender.map(|s| s.into())
             }
 
-            let e = mumble_text_to_event(e);
-            Some(Event::UserSentMessage(e))
+            mumble_text_to_event(e).map(Event::UserSentMessage)
         }
         control::MessageType::UDPTunnel => unreachable!("UDP tunnel handled above"),
         _ => None,
@@ -51,7 +51,8 @@ pub struct Channel {
     pub max_users: Option<NonZeroU32>,
     pub position: Option<NonZeroI32>,
     pub parent: Option<ChannelID>,
-    // TODO: description_hash
+    /// Linked channels hear normal talking from this one. Links always go both ways.
+    pub links: Vec<ChannelID>,
 }
 
 impl Channel {
@@ -70,6 +71,7 @@ impl Channel {
             max_users,
             position: None,
             parent: None,
+            links: vec![],
         }
     }
 }
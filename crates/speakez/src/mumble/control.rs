@@ -11,6 +11,27 @@ pub mod proto {
     // include!(concat!(env!("OUT_DIR"), "/mumble.proto.rs"));
 }
 
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The buffer is shorter than a prefix or does not match the length in it.
+    InvalidLength,
+    UnknownType(u16),
+    /// The prefix contains a length over `proto::MAX_MSG_SIZE`.
+    TooLarge(usize),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidLength => write!(f, "invalid message length"),
+            Error::UnknownType(typ) => write!(f, "unknown message type {}", typ),
+            Error::TooLarge(size) => write!(f, "message of {} bytes is too large", size),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 pub fn get_prefix_from_buf(buf: &[u8]) -> Option<&[u8]> {
     if buf.len() >= proto::PREFIX_TOTAL_SIZE {
         Some(&buf[0..proto::PREFIX_TOTAL_SIZE])
@@ -59,19 +80,18 @@ pub fn message_length(m: &impl ProtoMessage) -> usize {
     m.encoded_len()
 }
 
-pub fn parse_prefix(buf: &[u8]) -> (MessageType, usize) {
-    assert_eq!(buf.len(), proto::PREFIX_TOTAL_SIZE);
-    let msg_type = u16::from_be_bytes(buf[0..proto::PREFIX_TYPE_SIZE].try_into().unwrap());
-    let msg_len = u32::from_be_bytes(
-        buf[proto::PREFIX_TYPE_SIZE..proto::PREFIX_TOTAL_SIZE]
-            .try_into()
-            .unwrap(),
-    );
-
-    (
-        MessageType::from_u16(msg_type).unwrap(),
-        msg_len.try_into().unwrap(),
-    )
+/// Returns the type and body length of a message from the start of `buf`.
+pub fn parse_prefix(buf: &[u8]) -> Result<(MessageType, usize), Error> {
+    let prefix = get_prefix_from_buf(buf).ok_or(Error::InvalidLength)?;
+    let (typ, len) = prefix.split_at(proto::PREFIX_TYPE_SIZE);
+    let msg_type = u16::from_be_bytes([typ[0], typ[1]]);
+    let msg_len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]);
+
+    if msg_len > proto::MAX_MSG_SIZE {
+        return Err(Error::TooLarge(msg_len as usize));
+    }
+    let typ = MessageType::from_u16(msg_type).ok_or(Error::UnknownType(msg_type))?;
+    Ok((typ, msg_len as usize))
 }
 
 #[derive(Debug)]
@@ -81,9 +101,20 @@ pub struct MessageBuf {
 }
 
 impl MessageBuf {
+    /// Parse a single message, `data` must contain the prefix and the whole body.
+    pub fn parse(data: Vec<u8>) -> Result<Self, Error> {
+        let (typ, size) = parse_prefix(&data)?;
+        if data.len() != proto::PREFIX_TOTAL_SIZE + size {
+            return Err(Error::InvalidLength);
+        }
+        Ok(MessageBuf { typ, data })
+    }
+
     /// Return a reference to the data without the prefix.
     pub fn body(&self) -> &[u8] {
-        &self.data[proto::PREFIX_TOTAL_SIZE..]
+        self.data
+            .get(proto::PREFIX_TOTAL_SIZE..)
+            .unwrap_or_default()
     }
 }
 
@@ -236,6 +267,9 @@ typed_messages!(
     (proto::ServerSync, MessageType::ServerSync),
     (proto::Authenticate, MessageType::Authenticate),
     (proto::CryptSetup, MessageType::CryptSetup),
+    (proto::PermissionDenied, MessageType::PermissionDenied),
+    (proto::UserStats, MessageType::UserStats),
+    (proto::RequestBlob, MessageType::RequestBlob),
 );
 
 // https://matklad.github.io/2022/03/26/self-modifying-code.html
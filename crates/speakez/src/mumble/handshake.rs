@@ -1,3 +1,32 @@
+use crate::mumble::control::MessageType;
+
+#[derive(Debug)]
+pub enum Error {
+    Decode(prost::DecodeError),
+    /// The message is not expected at this point of the handshake.
+    Unexpected(MessageType),
+    /// A field required to continue the handshake is missing.
+    Missing(&'static str),
+}
+
+impl From<prost::DecodeError> for Error {
+    fn from(e: prost::DecodeError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Decode(e) => write!(f, "invalid handshake message: {}", e),
+            Error::Unexpected(typ) => write!(f, "unexpected {} message", typ.as_str()),
+            Error::Missing(field) => write!(f, "handshake message is missing {}", field),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 pub mod client {
     use crate::mumble::control::proto;
     use crate::mumble::control::MessageBuf;
@@ -6,12 +35,14 @@ pub mod client {
 
     use prost::Message;
 
+    use super::Error;
+
     #[derive(Clone, Debug)]
     pub struct ServerVersion {
         pub version: Version,
     }
 
-    #[derive(Clone, Debug)]
+    #[derive(Clone, Debug, Default)]
     pub struct ServerState {
         pub(crate) crypt: proto::CryptSetup,
         pub(crate) channels: Vec<proto::ChannelState>,
@@ -44,49 +75,55 @@ pub mod client {
             State::Connected
         }
 
-        fn handle_version(self, msg: proto::Version) -> Self {
+        fn handle_version(&mut self, msg: proto::Version) {
             let version = mumble::Version::from_u64(msg.version_v2());
-            State::ServerVersion(ServerVersion { version })
+            *self = State::ServerVersion(ServerVersion { version })
         }
 
-        fn handle_crypt_setup(self, msg: proto::CryptSetup) -> Self {
+        fn handle_crypt_setup(&mut self, msg: proto::CryptSetup) {
             let state = ServerState {
                 crypt: msg,
                 users: Vec::new(),
                 channels: Vec::new(),
             };
-            State::StateSync(state)
+            *self = State::StateSync(state)
         }
 
-        pub fn handle(self, m: MessageBuf) -> Self {
+        /// The state is left unchanged when an error is returned.
+        pub fn handle(&mut self, m: MessageBuf) -> Result<(), Error> {
             match self {
                 State::Connected if m.typ == control::MessageType::Version => {
-                    let msg = proto::Version::decode(m.body()).unwrap();
+                    let msg = proto::Version::decode(m.body())?;
                     self.handle_version(msg)
                 }
                 // allow sending of authenticate without waiting for the server version,
-                State::SentAuthenticate if m.typ == control::MessageType::Version => self,
+                State::SentAuthenticate if m.typ == control::MessageType::Version => {}
                 State::SentAuthenticate if m.typ == control::MessageType::CryptSetup => {
-                    let msg = control::proto::CryptSetup::decode(m.body()).unwrap();
+                    let msg = control::proto::CryptSetup::decode(m.body())?;
                     self.handle_crypt_setup(msg)
                 }
-                State::StateSync(mut s) if m.typ == control::MessageType::ChannelState => {
-                    let msg = control::proto::ChannelState::decode(m.body()).unwrap();
+                State::StateSync(s) if m.typ == control::MessageType::ChannelState => {
+                    let msg = control::proto::ChannelState::decode(m.body())?;
                     s.channels.push(msg);
-                    State::StateSync(s)
                 }
-                State::StateSync(mut s) if m.typ == control::MessageType::UserState => {
-                    let msg = control::proto::UserState::decode(m.body()).unwrap();
+                State::StateSync(s) if m.typ == control::MessageType::UserState => {
+                    let msg = control::proto::UserState::decode(m.body())?;
                     s.users.push(msg);
-                    State::StateSync(s)
                 }
-                State::StateSync(state) if m.typ == control::MessageType::ServerSync => {
-                    let msg = control::proto::ServerSync::decode(m.body()).unwrap();
-                    let sync = ServerSync { state, sync: msg };
-                    State::ServerSync(sync)
+                State::StateSync(s) if m.typ == control::MessageType::ServerSync => {
+                    let msg = control::proto::ServerSync::decode(m.body())?;
+                    if msg.session.unwrap_or_default() == 0 {
+                        return Err(Error::Missing("ServerSync.session"));
+                    }
+                    let sync = ServerSync {
+                        state: std::mem::take(s),
+                        sync: msg,
+                    };
+                    *self = State::ServerSync(sync)
                 }
-                _ => todo!("client handshake: {:?}", m.typ),
+                _ => return Err(Error::Unexpected(m.typ)),
             }
+            Ok(())
         }
     }
 }
@@ -98,6 +135,8 @@ pub mod server {
     use crate::mumble::Version;
     use crate::mumble::{self, control};
 
+    use super::Error;
+
     #[derive(Clone, Debug)]
     pub enum AuthMethod {
         Password(String),
@@ -113,9 +152,24 @@ pub mod server {
     #[derive(Clone, Debug)]
     pub struct ClientVersion {
         pub(crate) version: Version,
+        pub(crate) release: Option<String>,
+        pub(crate) os: Option<String>,
+        pub(crate) os_version: Option<String>,
+    }
+
+    impl From<&ClientVersion> for control::proto::Version {
+        fn from(v: &ClientVersion) -> Self {
+            control::proto::Version {
+                version_v2: Some(v.version.to_u64()),
+                release: v.release.clone(),
+                os: v.os.clone(),
+                os_version: v.os_version.clone(),
+                ..Default::default()
+            }
+        }
     }
 
-    #[derive(Debug)]
+    #[derive(Clone, Debug)]
     pub struct ClientAuth {
         pub(crate) version: ClientVersion,
         pub(crate) auth: Authentication,
@@ -125,7 +179,7 @@ pub mod server {
     pub enum State {
         SentServerVersion,
         ClientVersion(ClientVersion),
-        Authenticate(Authentication),
+        Authenticate(ClientAuth),
     }
 
     impl State {
@@ -135,29 +189,42 @@ pub mod server {
 
         fn handle_version(&mut self, msg: mumble::control::proto::Version) {
             let version = mumble::Version::from_u64(msg.version_v2());
-            *self = State::ClientVersion(ClientVersion { version })
+            *self = State::ClientVersion(ClientVersion {
+                version,
+                release: msg.release,
+                os: msg.os,
+                os_version: msg.os_version,
+            })
         }
 
-        fn handle_authenticate(&mut self, msg: mumble::control::proto::Authenticate) {
+        fn handle_authenticate(
+            &mut self,
+            version: ClientVersion,
+            msg: mumble::control::proto::Authenticate,
+        ) {
             let auth = Authentication {
                 username: msg.username().to_string(),
-                method: AuthMethod::Password(msg.password.unwrap()),
+                // clients authenticating with only a certificate do not send a password
+                method: AuthMethod::Password(msg.password.unwrap_or_default()),
             };
-            *self = State::Authenticate(auth)
+            *self = State::Authenticate(ClientAuth { version, auth })
         }
 
-        pub fn handle(&mut self, m: MessageBuf) {
+        /// The state is left unchanged when an error is returned.
+        pub fn handle(&mut self, m: MessageBuf) -> Result<(), Error> {
             match self {
                 State::SentServerVersion if m.typ == control::MessageType::Version => {
-                    let msg = control::proto::Version::decode(m.body()).unwrap();
+                    let msg = control::proto::Version::decode(m.body())?;
                     self.handle_version(msg)
                 }
-                State::ClientVersion(_) if m.typ == control::MessageType::Authenticate => {
-                    let msg = control::proto::Authenticate::decode(m.body()).unwrap();
-                    self.handle_authenticate(msg)
+                State::ClientVersion(version) if m.typ == control::MessageType::Authenticate => {
+                    let version = version.clone();
+                    let msg = control::proto::Authenticate::decode(m.body())?;
+                    self.handle_authenticate(version, msg)
                 }
-                got => todo!("got {:?}, in {:?} state", m.typ, got),
+                _ => return Err(Error::Unexpected(m.typ)),
             }
+            Ok(())
         }
     }
 }
@@ -196,7 +263,7 @@ mod tests {
 
     #[test]
     fn test_client_handshake() {
-        let s = client::State::SentAuthenticate;
+        let mut s = client::State::SentAuthenticate;
 
         // let msg = MessageBuf {
         //     typ: MessageType::Version,
@@ -214,7 +281,32 @@ mod tests {
             },
         ];
 
-        let s = msgs.into_iter().fold(s, |s, m| s.handle(m));
+        for m in msgs {
+            s.handle(m).unwrap();
+        }
         eprintln!("{:?}", s);
     }
+
+    #[test]
+    fn test_server_handshake_errors() {
+        let mut s = server::State::new();
+
+        // authenticate before the version
+        let auth = MessageBuf {
+            typ: MessageType::Authenticate,
+            data: proto::Authenticate::default().as_vec(),
+        };
+        assert!(matches!(
+            s.handle(auth),
+            Err(Error::Unexpected(MessageType::Authenticate))
+        ));
+        assert!(matches!(s, server::State::SentServerVersion));
+
+        let invalid = MessageBuf {
+            typ: MessageType::Version,
+            data: vec![0, 0, 0, 0, 0, 1, 0xFF],
+        };
+        assert!(matches!(s.handle(invalid), Err(Error::Decode(_))));
+        assert!(matches!(s, server::State::SentServerVersion));
+    }
 }
@@ -9,6 +9,16 @@ pub fn default() -> u32 {
         | Permissions::WHISPER
 }
 
+/// Permissions for connections over the admin socket.
+pub fn admin() -> u32 {
+    default()
+        | Permissions::MUTE_DEAFEN
+        | Permissions::MOVE
+        | Permissions::LINK_CHANNEL
+        | Permissions::KICK
+        | Permissions::RESET_USER_CONTENT
+}
+
 // enum Perm {
 // 		None            = 0x0,
 // 		Write           = 0x1,
@@ -37,34 +47,36 @@ pub fn default() -> u32 {
 // 	};
 
 impl Permissions {
-    const NONE: u32 = 0;
+    pub const NONE: u32 = 0;
     /// Write access to channel control. Implies all other permissions (except Speak).
-    const WRITE: u32 = 0x01;
+    pub const WRITE: u32 = 0x01;
     /// Traverse channel.
     /// Without this, a client cannot reach subchannels, no matter which privileges it has there.
-    const TRAVERSE: u32 = 0x02;
+    pub const TRAVERSE: u32 = 0x02;
     /// Enter channel.
-    const ENTER: u32 = 0x04;
+    pub const ENTER: u32 = 0x04;
     /// Speak in channel.
-    const SPEAK: u32 = 0x08;
+    pub const SPEAK: u32 = 0x08;
+    /// Mute and deafen other users in this channel, also required to make a user a priority speaker.
+    pub const MUTE_DEAFEN: u32 = 0x10;
+    /// Move users from channel. You need this permission in both the source and destination channel to move another user.
+    pub const MOVE: u32 = 0x20;
+    /// Link this channel. You need this permission in both the source and destination channel to link channels, or in either channel to unlink them.
+    pub const LINK_CHANNEL: u32 = 0x80;
     /// Whisper to channel. This is different from Speak, so you can set up different permissions.
-    const WHISPER: u32 = 0x100;
+    pub const WHISPER: u32 = 0x100;
     /// Send text message to channel.
-    const TEXT_MESSAGE: u32 = 0x200;
-    const LISTEN: u32 = 0x800;
+    pub const TEXT_MESSAGE: u32 = 0x200;
+    pub const LISTEN: u32 = 0x800;
+    /// Kick user from server. Only valid on root channel.
+    pub const KICK: u32 = 0x10000;
+    /// Clear the comment and avatar of other users. Only valid on root channel.
+    pub const RESET_USER_CONTENT: u32 = 0x100000;
 }
-// 	/** Mute and deafen other users in this channel. */
-// 	const int PermissionMuteDeafen = 0x10;
-// 	/** Move users from channel. You need this permission in both the source and destination channel to move another user. */
-// 	const int PermissionMove = 0x20;
 // 	/** Make new channel as a subchannel of this channel. */
 // 	const int PermissionMakeChannel = 0x40;
 // 	/** Make new temporary channel as a subchannel of this channel. */
 // 	const int PermissionMakeTempChannel = 0x400;
-// 	/** Link this channel. You need this permission in both the source and destination channel to link channels, or in either channel to unlink them. */
-// 	const int PermissionLinkChannel = 0x80;
-// 	/** Kick user from server. Only valid on root channel. */
-// 	const int PermissionKick = 0x10000;
 // 	/** Ban user from server. Only valid on root channel. */
 // 	const int PermissionBan = 0x20000;
 // 	/** Register and unregister users. Only valid on root channel. */
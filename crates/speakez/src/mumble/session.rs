@@ -43,4 +43,15 @@ impl Sessions {
     pub fn return_session(&mut self, s: Session) {
         self.data.push(s)
     }
+
+    /// Reserve a specific session, returns false if it is already in use.
+    pub fn take(&mut self, s: Session) -> bool {
+        match self.data.iter().position(|free| *free == s) {
+            Some(i) => {
+                self.data.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
 }
@@ -59,18 +59,20 @@ impl Message {
         use prost::Message as _;
 
         // TODO: move this piece out?
-        let typ_byte = buf[0];
+        let Some((&typ_byte, body)) = buf.split_first() else {
+            return Err(prost::DecodeError::new("empty message"));
+        };
         let typ = MessageType::from_u16(typ_byte.into()).ok_or(prost::DecodeError::new(
             format!("invalid message type, found: {}", typ_byte),
         ))?;
 
         match typ {
             MessageType::Audio => {
-                let msg = Audio::decode(&buf[1..])?;
+                let msg = Audio::decode(body)?;
                 Ok(Message::Audio(msg))
             }
             MessageType::Ping => {
-                let msg = Ping::decode(&buf[1..])?;
+                let msg = Ping::decode(body)?;
                 Ok(Message::Ping(msg))
             }
         }
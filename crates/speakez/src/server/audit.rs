@@ -0,0 +1,49 @@
+use std::net::SocketAddr;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::common::ChannelID;
+use crate::mumble::session::Session;
+
+/// Membership and moderation actions. Audio is not recorded.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "action", rename_all = "snake_case"))]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// A connection was accepted, the handshake has not happened yet.
+    Connected,
+    /// The handshake completed and the user joined the server.
+    Joined { channel: ChannelID },
+    /// The connection was closed.
+    Left,
+    /// The user was removed from the server by an admin.
+    Kicked { by: Session, reason: Option<String> },
+    SwitchedChannel {
+        from: ChannelID,
+        to: ChannelID,
+        by: Session,
+    },
+    /// Only the recipients are recorded, not the message itself.
+    SentMessage {
+        recipients: Vec<Session>,
+        channels: Vec<ChannelID>,
+    },
+    /// Disconnected for sending messages faster than the rate limit allows.
+    Flooding,
+    /// The user attempted something they lack the permission for.
+    Denied { permission: u32 },
+}
+
+/// A single audit log entry, the server is responsible for adding a timestamp.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub session: Session,
+    /// Only known once the handshake has completed.
+    pub username: Option<String>,
+    pub addr: Option<SocketAddr>,
+    pub cert_hash: Option<String>,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub action: Action,
+}
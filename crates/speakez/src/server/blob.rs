@@ -0,0 +1,36 @@
+//! Comments, textures and descriptions of at least `HASH_THRESHOLD` bytes are sent as
+//! a SHA1 hash, clients fetch the full content with a RequestBlob message.
+
+use sha1::{Digest, Sha1};
+
+use crate::mumble::control::proto;
+
+pub const HASH_THRESHOLD: usize = 128;
+
+fn hash(data: &[u8]) -> Vec<u8> {
+    Sha1::digest(data).to_vec()
+}
+
+pub fn set_comment(msg: &mut proto::UserState, comment: &str) {
+    if comment.len() < HASH_THRESHOLD {
+        msg.comment = Some(comment.to_string());
+    } else {
+        msg.comment_hash = Some(hash(comment.as_bytes()));
+    }
+}
+
+pub fn set_texture(msg: &mut proto::UserState, texture: &[u8]) {
+    if texture.len() < HASH_THRESHOLD {
+        msg.texture = Some(texture.to_vec());
+    } else {
+        msg.texture_hash = Some(hash(texture));
+    }
+}
+
+pub fn set_description(msg: &mut proto::ChannelState, description: &str) {
+    if description.len() < HASH_THRESHOLD {
+        msg.description = Some(description.to_string());
+    } else {
+        msg.description_hash = Some(hash(description.as_bytes()));
+    }
+}
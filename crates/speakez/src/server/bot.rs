@@ -0,0 +1,75 @@
+//! Bots are users that live inside the server process instead of connecting to it.
+//! They are added with [`Message::BotCreated`], hear the audio routed to them through
+//! [`State::bot_outbox`] and speak with [`Message::BotAudio`]. A bot is removed with
+//! [`Message::SessionDisconnect`] like any other session.
+//!
+//! [`Message::BotCreated`]: super::Message::BotCreated
+//! [`Message::BotAudio`]: super::Message::BotAudio
+//! [`Message::SessionDisconnect`]: super::Message::SessionDisconnect
+
+use std::time::Instant;
+
+use crate::common::events::VoiceMessage;
+use crate::common::{ChannelID, User};
+use crate::mumble::handshake::server::ClientVersion;
+use crate::mumble::session::Session;
+use crate::mumble::{self};
+
+use super::handshake;
+use super::state::{Peer, State, VoiceTransport};
+
+#[derive(Clone, Debug)]
+pub struct Bot {
+    pub name: String,
+    /// The channel the bot joins, it must already exist.
+    pub channel: ChannelID,
+}
+
+/// Whether `bot` can be added. Checked before the bot is given a session, so a bot that
+/// can not join never holds one.
+pub fn can_join(s: &State, bot: &Bot) -> bool {
+    s.channels.iter().any(|c| c.id == bot.channel)
+}
+
+pub(super) fn handle_bot_created(mut s: State, session: Session, bot: Bot, now: Instant) -> State {
+    if !can_join(&s, &bot) {
+        crate::tracing::warn!("bot {} joined unknown channel {:?}", bot.name, bot.channel);
+        s.delete_session(session);
+        return s;
+    }
+
+    let user = User {
+        name: bot.name,
+        session,
+        channel: bot.channel,
+    };
+    let version = ClientVersion {
+        version: mumble::Version::new(1, 5, 0),
+        release: Some("speakez bot".to_string()),
+        os: None,
+        os_version: None,
+    };
+    let mut info = handshake::new_session_info(&s, user, version, Peer::default(), now);
+    info.voice_transport = VoiceTransport::Bot;
+    handshake::handle_session_connected(&mut s, info);
+    s
+}
+
+pub(super) fn handle_bot_audio(
+    s: State,
+    session: Session,
+    mut msg: VoiceMessage,
+    now: Instant,
+) -> State {
+    let is_bot = s
+        .session_info
+        .get(&session)
+        .is_some_and(|info| info.voice_transport == VoiceTransport::Bot);
+    if !is_bot {
+        crate::tracing::warn!("ignoring bot audio from {:?}, it is not a bot", session);
+        return s;
+    }
+
+    msg.sender = session;
+    super::messages::handle_voice_message(s, session, msg, now)
+}
@@ -1,17 +1,24 @@
+use std::collections::HashMap;
 use std::time::Instant;
 
 use crate::common::{events, User, ROOT_CHANNEL};
 
-use super::state::{push_message, SessionInfo, SessionStats, State as ServerState, VoiceTransport};
+use super::state::{
+    push_message, Peer, RateLimiter, SessionInfo, SessionStats, State as ServerState,
+    VoiceTransport,
+};
 use super::Destination;
+use super::{audit, blob};
 use crate::mumble::control::{self, MessageBuf};
+use crate::mumble::handshake::{self, server::ClientVersion};
 use crate::mumble::session::Session;
-use crate::mumble::{self, handshake};
 
 #[derive(Debug)]
 pub enum Status {
     Handshake(State),
-    Connected(User),
+    Connected(User, ClientVersion),
+    /// The message was rejected, the handshake state is unchanged.
+    Invalid(State, handshake::Error),
 }
 
 /// State used during the initial handshake.
@@ -19,26 +26,30 @@ pub enum Status {
 pub struct State {
     pub state: handshake::server::State,
     pub session: Session,
+    pub peer: Peer,
 }
 
 impl State {
-    pub fn new(session: Session) -> Self {
+    pub fn new(session: Session, peer: Peer) -> Self {
         Self {
             state: handshake::server::State::new(),
             session,
+            peer,
         }
     }
 
     pub fn handle_message(mut self, m: MessageBuf) -> Status {
-        self.state.handle(m);
+        if let Err(e) = self.state.handle(m) {
+            return Status::Invalid(self, e);
+        }
         match self.state {
             handshake::server::State::Authenticate(auth) => {
                 let u = User {
-                    name: auth.username,
+                    name: auth.auth.username,
                     session: self.session,
                     channel: ROOT_CHANNEL,
                 };
-                Status::Connected(u)
+                Status::Connected(u, auth.version)
             }
             _ => Status::Handshake(self),
         }
@@ -52,19 +63,32 @@ pub(super) fn handle_handshake(
     m: MessageBuf,
     msg_received_at: Instant,
 ) -> ServerState {
+    let peer = hs.peer.clone();
     match hs.handle_message(m) {
         Status::Handshake(state) => {
             s.session_handshake.insert(session, state);
         }
-        Status::Connected(user) => {
-            let info = new_session_info(&s, user, msg_received_at);
+        Status::Connected(user, version) => {
+            let info = new_session_info(&s, user, version, peer, msg_received_at);
             handle_session_connected(&mut s, info);
         }
+        Status::Invalid(state, e) => {
+            crate::tracing::warn!("closing connection of {:?}: {}", session, e);
+            // keep the handshake until the connection closes so the session is returned
+            s.session_handshake.insert(session, state);
+            s.push_disconnect(session);
+        }
     }
     s
 }
 
-fn new_session_info(s: &ServerState, user: User, msg_received_at: Instant) -> SessionInfo {
+pub(super) fn new_session_info(
+    s: &ServerState,
+    user: User,
+    version: ClientVersion,
+    peer: Peer,
+    msg_received_at: Instant,
+) -> SessionInfo {
     SessionInfo {
         voice_transport: VoiceTransport::Tcp,
         voice_crypter: (s.voice_crypter)(),
@@ -72,22 +96,39 @@ fn new_session_info(s: &ServerState, user: User, msg_received_at: Instant) -> Se
         stats: SessionStats {
             last_seen_tcp: msg_received_at,
             last_seen_udp: None,
+            connected_at: msg_received_at,
+            last_active: msg_received_at,
+            last_ping: Default::default(),
+            bandwidth: Default::default(),
+            messages: RateLimiter::new(msg_received_at, &s.config),
         },
+        peer,
+        version,
+        comment: String::new(),
+        texture: vec![],
+        listening: HashMap::new(),
+        priority_speaker: false,
     }
 }
 
-fn handle_session_connected(s: &mut ServerState, info: SessionInfo) {
+pub(super) fn handle_session_connected(s: &mut ServerState, info: SessionInfo) {
     let session = info.user.session;
-    let msg = events::UserJoinedServer {
+    let channel = info.user.channel;
+    let mut msg: control::proto::UserState = events::UserJoinedServer {
         name: info.user.name.clone(),
         user: session,
-        channel_id: ROOT_CHANNEL,
+        channel_id: channel,
     }
     .into();
+    msg.recording = s.is_recording(channel).then_some(true);
 
-    sync_server_state_to_session(s, &info, &msg);
+    // bots have no connection to sync
+    if info.voice_transport != VoiceTransport::Bot {
+        sync_server_state_to_session(s, &info, &msg);
+    }
     s.session_info.insert(session, info);
     s.push_message(msg, Destination::AllButOne(session));
+    s.push_audit(session, audit::Action::Joined { channel });
 }
 
 /// This function assumes the user_state is not in the state already.
@@ -107,12 +148,9 @@ fn sync_server_state_to_session(
     };
     s.push_message(msg, Destination::Single(session));
 
-    let channel_states = s
-        .channels
-        .iter()
-        .map(|channel| control::proto::ChannelState {
+    let channel_states = s.channels.iter().map(|channel| {
+        let mut msg = control::proto::ChannelState {
             name: Some(channel.name.clone()),
-            description: Some(channel.description.clone()),
             channel_id: Some(channel.id.as_u32()),
             position: channel.position.map(|i| i.into()),
             parent: if channel.id == ROOT_CHANNEL {
@@ -120,22 +158,36 @@ fn sync_server_state_to_session(
             } else {
                 Some(ROOT_CHANNEL.into())
             },
+            // links are both ways, whichever channel is synced last links them for the client
+            links: channel.links.iter().map(|c| c.as_u32()).collect(),
             ..Default::default()
-        });
+        };
+        blob::set_description(&mut msg, &channel.description);
+        msg
+    });
 
     for msg in channel_states {
         push_message(&mut s.outbox, &msg, Destination::Single(session));
     }
 
-    let user_states = s
-        .session_info
-        .values()
-        .map(|info| control::proto::UserState {
+    let user_states = s.session_info.values().map(|info| {
+        let mut msg = control::proto::UserState {
             name: Some(info.user.name.clone()),
             session: Some(info.user.session.into()),
             channel_id: Some(info.user.channel.into()),
+            listening_channel_add: info.listening.keys().map(|c| c.as_u32()).collect(),
+            priority_speaker: info.priority_speaker.then_some(true),
+            recording: s.recording.contains(&info.user.channel).then_some(true),
             ..Default::default()
-        });
+        };
+        if !info.comment.is_empty() {
+            blob::set_comment(&mut msg, &info.comment);
+        }
+        if !info.texture.is_empty() {
+            blob::set_texture(&mut msg, &info.texture);
+        }
+        msg
+    });
 
     for msg in user_states {
         push_message(&mut s.outbox, &msg, Destination::Single(session));
@@ -146,7 +198,7 @@ fn sync_server_state_to_session(
         session: Some(session.into()),
         welcome_text: Some("Hello Test user".to_string()),
         max_bandwidth: Some(s.config.max_bandwidth),
-        permissions: Some(mumble::permissions::default().into()),
+        permissions: Some(info.permissions().into()),
     };
     s.push_message(msg, Destination::Single(session));
 }
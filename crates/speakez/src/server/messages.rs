@@ -1,29 +1,36 @@
-use prost::Message as _;
-
-use crate::common::events::{self, mumble_to_event, Event};
-use std::net::SocketAddr;
+use crate::common::events::{self, mumble_to_event, Event, UserRemovedReason, UserState as _};
+use crate::common::{ChannelID, ROOT_CHANNEL};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::time::Instant;
 
 use crate::mumble::control::{Message as _, MessageBuf};
+use crate::mumble::permissions::Permissions;
 use crate::mumble::session::Session;
 use crate::mumble::{self, control, voice};
 
+use super::audit::Action;
 use super::handshake::handle_handshake;
 use super::state::{
-    Destination, OutboxDestination, OutboxMessage, OutboxType, State, VoiceTransport,
+    canonical_addr, push_message, Destination, OutboxDestination, OutboxMessage, OutboxType, Peer,
+    State, VoiceTransport,
 };
-use super::{handshake, version};
+use super::{blob, bot, handshake, recording, version};
 
 #[derive(Debug)]
 pub enum Message {
     Tick,
-    SessionCreated(Session),
+    SessionCreated(Session, Peer),
     SessionDisconnect(Session),
     Mumble(Session, MessageBuf),
     UDP(SocketAddr, Vec<u8>),
+    /// Add a bot using a session from `State::new_session`.
+    BotCreated(Session, bot::Bot),
+    BotAudio(Session, events::VoiceMessage),
 }
 
 fn handle_session_disconnect(mut s: State, session: Session) -> State {
+    s.push_audit(session, Action::Left);
     let user = match s.delete_session(session) {
         Some(info) => info.user,
         None => return s,
@@ -39,14 +46,141 @@ fn handle_session_disconnect(mut s: State, session: Session) -> State {
     s
 }
 
+/// Approximate size of the IP, UDP and crypt headers sent with each audio packet.
+const VOICE_PACKET_OVERHEAD: usize = 32;
+/// Voice target reaching the speaker's channel and any channels linked to it.
+const TARGET_NORMAL: u32 = 0;
+/// Voice target the server sends back to the speaker.
+const TARGET_LOOPBACK: u32 = 31;
+/// Audio context for speech heard in the listener's own or a linked channel.
+const CONTEXT_NORMAL: u32 = 0;
+/// Audio context for speech heard through a channel listener.
+const CONTEXT_LISTEN: u32 = 3;
+
 // #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
-fn handle_voice_message(mut s: State, session: Session, msg: events::VoiceMessage) -> State {
-    let audio_msg = mumble::voice::Message::Audio(msg.into());
-    s.push_voice_message(audio_msg, Destination::AllButOne(session));
+pub(super) fn handle_voice_message(
+    mut s: State,
+    session: Session,
+    msg: events::VoiceMessage,
+    now: Instant,
+) -> State {
+    let max_bandwidth = s.config.max_bandwidth;
+    let Some(info) = s.session_info.get_mut(&session) else {
+        return s;
+    };
+
+    let bandwidth = &mut info.stats.bandwidth;
+    if !bandwidth.try_add(now, msg.data.len() + VOICE_PACKET_OVERHEAD, max_bandwidth) {
+        if bandwidth.should_warn(now) {
+            let msg = control::proto::PermissionDenied {
+                session: Some(session.into()),
+                reason: Some(format!(
+                    "Audio exceeds the server bandwidth limit of {} kbit/s",
+                    max_bandwidth / 1000
+                )),
+                r#type: Some(control::proto::permission_denied::DenyType::Text.into()),
+                ..Default::default()
+            };
+            s.push_message(msg, Destination::Single(session));
+        }
+        return s;
+    }
+    info.stats.last_active = now;
+    let channel = info.user.channel;
+
+    let target = msg.target;
+    let audio = |context, volume_adjustment| {
+        mumble::voice::Message::Audio(mumble::voice::Audio {
+            header: Some(mumble::voice::audio::Header::Context(context)),
+            volume_adjustment,
+            ..msg.clone().into()
+        })
+    };
+
+    match target {
+        TARGET_NORMAL => {
+            let channels = s.linked_channels(channel);
+            let mut hearing = vec![];
+            // listeners grouped by the bits of their volume adjustment
+            let mut listening: HashMap<u32, Vec<Session>> = HashMap::new();
+            for info in s.session_info.values() {
+                let other = info.user.session;
+                if other == session {
+                    continue;
+                }
+                if channels.contains(&info.user.channel) {
+                    hearing.push(other);
+                } else if let Some(volume) = channels.iter().find_map(|c| info.listening.get(c)) {
+                    listening.entry(volume.to_bits()).or_default().push(other);
+                }
+            }
+
+            deliver_to_bots(&mut s, &mut hearing, &msg);
+            if !hearing.is_empty() {
+                s.push_voice_message(audio(CONTEXT_NORMAL, 0.0), Destination::Group(hearing));
+            }
+            record_voice(&mut s, session, &channels, &msg, now);
+            for (volume, mut listeners) in listening {
+                deliver_to_bots(&mut s, &mut listeners, &msg);
+                if listeners.is_empty() {
+                    continue;
+                }
+                let msg = audio(CONTEXT_LISTEN, f32::from_bits(volume));
+                s.push_voice_message(msg, Destination::Group(listeners));
+            }
+        }
+        TARGET_LOOPBACK => {
+            let mut sessions = vec![session];
+            deliver_to_bots(&mut s, &mut sessions, &msg);
+            if !sessions.is_empty() {
+                s.push_voice_message(audio(CONTEXT_NORMAL, 0.0), Destination::Single(session));
+            }
+        }
+        target => {
+            crate::tracing::debug!("ignoring audio for unsupported voice target {}", target);
+        }
+    }
 
     s
 }
 
+/// Move bots out of `sessions`, they hear audio through the bot outbox instead.
+fn deliver_to_bots(s: &mut State, sessions: &mut Vec<Session>, msg: &events::VoiceMessage) {
+    sessions.retain(|session| {
+        let is_bot = s
+            .session_info
+            .get(session)
+            .is_some_and(|info| info.voice_transport == VoiceTransport::Bot);
+        if is_bot {
+            s.bot_outbox.push((*session, msg.clone()));
+        }
+        !is_bot
+    });
+}
+
+/// Keep the frame for every recorded channel it can be heard in.
+fn record_voice(
+    s: &mut State,
+    speaker: Session,
+    channels: &HashSet<ChannelID>,
+    msg: &events::VoiceMessage,
+    now: Instant,
+) {
+    let Some(info) = s.session_info.get(&speaker) else {
+        return;
+    };
+    for &channel in channels.intersection(&s.recording) {
+        let frame = recording::Frame {
+            channel,
+            speaker,
+            username: info.user.name.clone(),
+            at: now,
+            data: msg.data.clone(),
+        };
+        s.recorder.push(recording::Event::Frame(frame));
+    }
+}
+
 fn handle_udp_unencrypted_ping(
     mut s: State,
     from: SocketAddr,
@@ -74,16 +208,12 @@ fn handle_udp_ping(mut s: State, session: Session, m: mumble::voice::Ping, now:
     s
 }
 
-/// Returns the first session found that has a matching SocketAddr.
+/// Returns the session using the SocketAddr for UDP.
 fn find_matching_addr(s: &State, from: SocketAddr) -> Option<Session> {
-    // TODO: Switch to hashmap to store a sessions SocketAddr, prevent multiple Sessions having the
-    // same SocketAddr.
-    s.session_info
-        .iter()
-        .find_map(|(session, info)| match info.voice_transport {
-            VoiceTransport::Udp(addr) if addr == from => Some(*session),
-            _ => None,
-        })
+    s.socketaddr_to_session
+        .get(&from)
+        .copied()
+        .filter(|session| s.session_info.contains_key(session))
 }
 
 /// Find a session that can decrypt and decode the provided data.
@@ -145,11 +275,17 @@ fn handle_udp_message(mut s: State, from: SocketAddr, data: Vec<u8>, now: Instan
             .expect("session should have session info");
 
         let mut b = bytes::BytesMut::from(&data[..]);
-        info.voice_crypter.decrypt(&mut b).unwrap();
+        if let Err(e) = info.voice_crypter.decrypt(&mut b) {
+            crate::tracing::debug!("dropping UDP packet from {:?}: {}", session, e);
+            return s;
+        }
 
         let msg = match mumble::voice::Message::decode(&b) {
             Ok(m) => m,
-            Err(_) => todo!("error to client"),
+            Err(e) => {
+                crate::tracing::debug!("dropping UDP packet from {:?}: {}", session, e);
+                return s;
+            }
         };
 
         match msg {
@@ -158,8 +294,10 @@ fn handle_udp_message(mut s: State, from: SocketAddr, data: Vec<u8>, now: Instan
                     a.sender_session = session.into();
                 }
 
-                let msg = events::mumble_voice_to_event(a);
-                return handle_voice_message(s, session, msg);
+                let Some(msg) = events::mumble_voice_to_event(a) else {
+                    return s;
+                };
+                return handle_voice_message(s, session, msg, now);
             }
             mumble::voice::Message::Ping(p) => return handle_udp_ping(s, session, p, now),
         };
@@ -167,20 +305,17 @@ fn handle_udp_message(mut s: State, from: SocketAddr, data: Vec<u8>, now: Instan
 
     if let Some((session, msg)) = find_matching_crypt(&mut s, &data) {
         crate::tracing::debug!("found matching crypt, {:#?}", session);
-        let info = s
-            .session_info
-            .get_mut(&session)
-            .expect("session should have session info");
-
-        info.voice_transport = VoiceTransport::Udp(from);
+        s.set_udp_addr(session, from);
 
         match msg {
             mumble::voice::Message::Audio(mut a) => {
                 if a.sender_session == 0 {
                     a.sender_session = session.into();
                 }
-                let msg = events::mumble_voice_to_event(a);
-                return handle_voice_message(s, session, msg);
+                let Some(msg) = events::mumble_voice_to_event(a) else {
+                    return s;
+                };
+                return handle_voice_message(s, session, msg, now);
             }
             mumble::voice::Message::Ping(p) => return handle_udp_ping(s, session, p, now),
         }
@@ -194,21 +329,61 @@ fn handle_udp_message(mut s: State, from: SocketAddr, data: Vec<u8>, now: Instan
     s
 }
 
-fn handle_event(mut s: State, session: Session, e: Event) -> State {
+/// Decode the body of `m`, invalid messages are logged and dropped.
+fn decode<M: prost::Message + Default>(session: Session, m: &MessageBuf) -> Option<M> {
+    match M::decode(m.body()) {
+        Ok(msg) => Some(msg),
+        Err(e) => {
+            crate::tracing::debug!("invalid {:?} from {:?}: {}", m.typ, session, e);
+            None
+        }
+    }
+}
+
+fn handle_event(mut s: State, session: Session, e: Event, now: Instant) -> State {
     match e {
         Event::UserSentAudio(e) => {
-            return handle_voice_message(s, session, e);
+            return handle_voice_message(s, session, e, now);
         }
         Event::UserSwitchedChannel(e) => {
-            let info = s.session_info.get_mut(&e.user).unwrap();
+            if e.user != session && !s.is_admin(session) {
+                deny(&mut s, session, Permissions::MOVE);
+                return s;
+            }
+
+            let Some(info) = s.session_info.get_mut(&e.user) else {
+                return s;
+            };
             if info.user.channel == e.from_channel {
                 info.user.channel = e.to_channel;
-                s.push_message(e.into_mumble(), Destination::All);
+                let action = Action::SwitchedChannel {
+                    from: e.from_channel,
+                    to: e.to_channel,
+                    by: session,
+                };
+                s.push_audit(e.user, action);
+
+                let recording = s.is_recording(e.to_channel);
+                let changed = recording != s.is_recording(e.from_channel);
+                let mut msg = e.into_mumble();
+                msg.actor = Some(session.into());
+                if changed {
+                    msg.recording = Some(recording);
+                }
+                s.push_message(msg, Destination::All);
             }
         }
-        Event::UserRemoved(user_removed) => todo!(),
-        Event::UserJoinedServer(user_joined_server) => todo!(),
+        Event::UserRemoved(e) => return handle_user_removed(s, session, e),
+        Event::UserJoinedServer(e) => {
+            crate::tracing::warn!("ignoring UserState for unknown session: {:?}", e.user);
+        }
         Event::UserSentMessage(m) => {
+            let action = Action::SentMessage {
+                recipients: m.recipients.clone(),
+                channels: m.channels.clone(),
+            };
+            s.push_audit(session, action);
+
             let msg = OutboxMessage {
                 typ: OutboxType::Control,
                 data: m.into_mumble().as_vec(),
@@ -221,6 +396,446 @@ fn handle_event(mut s: State, session: Session, e: Event) -> State {
     s
 }
 
+/// Let the session know it is missing `permission`.
+fn deny(s: &mut State, session: Session, permission: u32) {
+    let msg = control::proto::PermissionDenied {
+        permission: Some(permission),
+        channel_id: Some(ROOT_CHANNEL.into()),
+        session: Some(session.into()),
+        r#type: Some(control::proto::permission_denied::DenyType::Permission.into()),
+        ..Default::default()
+    };
+    s.push_message(msg, Destination::Single(session));
+    s.push_audit(session, Action::Denied { permission });
+}
+
+/// Admins can kick other users, bans are not supported.
+fn handle_user_removed(mut s: State, actor: Session, e: events::UserRemoved) -> State {
+    if matches!(e.reason, UserRemovedReason::Banned { .. }) || !s.is_admin(actor) {
+        deny(&mut s, actor, Permissions::KICK);
+        return s;
+    }
+    if !s.session_info.contains_key(&e.user) {
+        return s;
+    }
+
+    let action = Action::Kicked {
+        by: actor,
+        reason: e.reason_msg.clone(),
+    };
+    s.push_audit(e.user, action);
+
+    let event = events::UserRemoved {
+        user: e.user,
+        reason: UserRemovedReason::Kicked { by: actor },
+        reason_msg: e.reason_msg,
+    };
+    disconnect_user(&mut s, event);
+
+    s
+}
+
+/// Remove the user and close their connection.
+fn disconnect_user(s: &mut State, e: events::UserRemoved) {
+    let user = e.user;
+    if s.restored.remove(&user) {
+        s.delete_session(user);
+    } else {
+        s.remove_user(user);
+    }
+    // The removed user is included so their client can show the reason.
+    s.push_message(e.into_mumble(), Destination::All);
+    s.push_disconnect(user);
+}
+
+/// Control messages that go through the rate limiter.
+fn is_rate_limited(typ: control::MessageType) -> bool {
+    matches!(
+        typ,
+        control::MessageType::TextMessage
+            | control::MessageType::UserState
+            | control::MessageType::ChannelState
+            | control::MessageType::UserRemove
+    )
+}
+
+/// Returns false if the message should be dropped.
+fn check_rate_limit(s: &mut State, session: Session, now: Instant) -> bool {
+    let Some(info) = s.session_info.get_mut(&session) else {
+        return false;
+    };
+    if info.stats.messages.allow(now, &s.config) {
+        return true;
+    }
+
+    crate::tracing::debug!("rate limited session {:?}", session);
+    if info.stats.messages.dropped() > s.config.max_dropped_messages {
+        s.push_audit(session, Action::Flooding);
+        let event = events::UserRemoved {
+            user: session,
+            reason: UserRemovedReason::Left,
+            reason_msg: Some("Disconnected for flooding".to_string()),
+        };
+        disconnect_user(s, event);
+    }
+    false
+}
+
+/// Everyone can see how long a user has been connected, users in the same channel can
+/// see connection statistics and only admins can see the address, certificate and
+/// client version of other users.
+fn handle_user_stats(
+    mut s: State,
+    session: Session,
+    req: control::proto::UserStats,
+    now: Instant,
+) -> State {
+    let target = req.session.and_then(Session::new).unwrap_or(session);
+    let extended = target == session || s.is_admin(session);
+    let channel = s.get_user(&session).map(|u| u.channel);
+    let Some(info) = s.session_info.get_mut(&target) else {
+        return s;
+    };
+    let local = extended || channel == Some(info.user.channel);
+
+    let stats = &mut info.stats;
+    let mut msg = control::proto::UserStats {
+        session: Some(target.into()),
+        stats_only: req.stats_only,
+        bandwidth: Some(stats.bandwidth.bits_per_sec(now)),
+        onlinesecs: Some(now.duration_since(stats.connected_at).as_secs() as u32),
+        idlesecs: Some(now.duration_since(stats.last_active).as_secs() as u32),
+        opus: Some(true),
+        ..Default::default()
+    };
+
+    if local {
+        let ping = &stats.last_ping;
+        let crypt = info.voice_crypter.stats();
+        msg.from_client = Some(control::proto::user_stats::Stats {
+            good: ping.good,
+            late: ping.late,
+            lost: ping.lost,
+            resync: ping.resync,
+        });
+        msg.from_server = Some(control::proto::user_stats::Stats {
+            good: Some(crypt.good),
+            late: Some(crypt.late),
+            lost: Some(crypt.lost),
+            resync: Some(crypt.resync),
+        });
+        msg.udp_packets = ping.udp_packets;
+        msg.tcp_packets = ping.tcp_packets;
+        msg.udp_ping_avg = ping.udp_ping_avg;
+        msg.udp_ping_var = ping.udp_ping_var;
+        msg.tcp_ping_avg = ping.tcp_ping_avg;
+        msg.tcp_ping_var = ping.tcp_ping_var;
+    }
+
+    if extended && !req.stats_only() {
+        msg.version = Some((&info.version).into());
+        msg.certificates = info.peer.certificates.clone();
+        msg.address = info.peer.addr.map(|addr| match addr.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        });
+        msg.strong_certificate = Some(false);
+    }
+
+    s.push_message(msg, Destination::Single(session));
+    s
+}
+
+/// Update the comment or avatar from a UserState message. Users can change their own,
+/// admins can also clear those of other users.
+fn handle_user_content(mut s: State, actor: Session, msg: &control::proto::UserState) -> State {
+    if msg.comment.is_none() && msg.texture.is_none() {
+        return s;
+    }
+
+    let target = msg.session.and_then(Session::new).unwrap_or(actor);
+    let clearing = msg.comment.as_deref().is_none_or(str::is_empty)
+        && msg.texture.as_deref().is_none_or(<[u8]>::is_empty);
+    if target != actor && !(clearing && s.is_admin(actor)) {
+        deny(&mut s, actor, Permissions::RESET_USER_CONTENT);
+        return s;
+    }
+
+    let too_long = msg
+        .comment
+        .as_ref()
+        .is_some_and(|c| c.len() > s.config.max_comment_length)
+        || msg
+            .texture
+            .as_ref()
+            .is_some_and(|t| t.len() > s.config.max_texture_size);
+    if too_long {
+        let msg = control::proto::PermissionDenied {
+            session: Some(actor.into()),
+            r#type: Some(control::proto::permission_denied::DenyType::TextTooLong.into()),
+            ..Default::default()
+        };
+        s.push_message(msg, Destination::Single(actor));
+        return s;
+    }
+
+    let Some(info) = s.session_info.get_mut(&target) else {
+        return s;
+    };
+    let mut update = control::proto::UserState {
+        session: Some(target.into()),
+        actor: Some(actor.into()),
+        ..Default::default()
+    };
+    if let Some(comment) = &msg.comment {
+        blob::set_comment(&mut update, comment);
+        info.comment = comment.clone();
+    }
+    if let Some(texture) = &msg.texture {
+        blob::set_texture(&mut update, texture);
+        info.texture = texture.clone();
+    }
+    s.push_message(update, Destination::All);
+
+    s
+}
+
+/// Mark or unmark a user as priority speaker. This requires the MuteDeafen permission,
+/// even when changing it for yourself.
+fn handle_priority_speaker(mut s: State, actor: Session, msg: &control::proto::UserState) -> State {
+    let Some(priority_speaker) = msg.priority_speaker else {
+        return s;
+    };
+
+    let allowed = s
+        .session_info
+        .get(&actor)
+        .is_some_and(|info| info.permissions() & Permissions::MUTE_DEAFEN != 0);
+    if !allowed {
+        deny(&mut s, actor, Permissions::MUTE_DEAFEN);
+        return s;
+    }
+
+    let target = msg.session.and_then(Session::new).unwrap_or(actor);
+    let Some(info) = s.session_info.get_mut(&target) else {
+        return s;
+    };
+    info.priority_speaker = priority_speaker;
+
+    let update = control::proto::UserState {
+        session: Some(target.into()),
+        actor: Some(actor.into()),
+        priority_speaker: Some(priority_speaker),
+        ..Default::default()
+    };
+    s.push_message(update, Destination::All);
+    s
+}
+
+/// Start or stop recording the channel the user is in.
+fn handle_recording(
+    mut s: State,
+    actor: Session,
+    msg: &control::proto::UserState,
+    now: Instant,
+) -> State {
+    let Some(recording) = msg.recording else {
+        return s;
+    };
+    let Some(info) = s.session_info.get(&actor) else {
+        return s;
+    };
+    let channel = info.user.channel;
+
+    let allowed = msg.session.is_none_or(|target| target == u32::from(actor))
+        && (s.config.allow_recording || s.is_admin(actor));
+    if !allowed {
+        let msg = control::proto::PermissionDenied {
+            session: Some(actor.into()),
+            reason: Some("Recording is not allowed on this server".to_string()),
+            r#type: Some(control::proto::permission_denied::DenyType::Text.into()),
+            ..Default::default()
+        };
+        s.push_message(msg, Destination::Single(actor));
+        return s;
+    }
+
+    if recording {
+        s.start_recording(channel, now);
+    } else {
+        s.stop_recording(channel);
+    }
+    s
+}
+
+/// Start or stop listening to channels and change their volume. Users can only change
+/// what they listen to themselves.
+fn handle_listening(mut s: State, actor: Session, msg: &control::proto::UserState) -> State {
+    if msg.listening_channel_add.is_empty()
+        && msg.listening_channel_remove.is_empty()
+        && msg.listening_volume_adjustment.is_empty()
+    {
+        return s;
+    }
+
+    let allowed = msg.session.is_none_or(|target| target == u32::from(actor))
+        && s.session_info
+            .get(&actor)
+            .is_some_and(|info| info.permissions() & Permissions::LISTEN != 0);
+    if !allowed {
+        deny(&mut s, actor, Permissions::LISTEN);
+        return s;
+    }
+
+    let channels: Vec<_> = s.channels.iter().map(|c| c.id).collect();
+    let Some(info) = s.session_info.get_mut(&actor) else {
+        return s;
+    };
+    let user_state = || control::proto::UserState {
+        session: Some(actor.into()),
+        actor: Some(actor.into()),
+        ..Default::default()
+    };
+
+    let mut update = user_state();
+    for &id in &msg.listening_channel_add {
+        let channel = ChannelID::new(id);
+        if channels.contains(&channel) && !info.listening.contains_key(&channel) {
+            info.listening.insert(channel, 1.0);
+            update.listening_channel_add.push(id);
+        }
+    }
+    for &id in &msg.listening_channel_remove {
+        if info.listening.remove(&ChannelID::new(id)).is_some() {
+            update.listening_channel_remove.push(id);
+        }
+    }
+
+    // volume adjustments only matter to the listener
+    let mut volumes = user_state();
+    for adjustment in &msg.listening_volume_adjustment {
+        let (Some(id), Some(volume)) = (adjustment.listening_channel, adjustment.volume_adjustment)
+        else {
+            continue;
+        };
+        if !volume.is_finite() || volume < 0.0 {
+            continue;
+        }
+        if let Some(current) = info.listening.get_mut(&ChannelID::new(id)) {
+            *current = volume;
+            volumes.listening_volume_adjustment.push(adjustment.clone());
+        }
+    }
+
+    if !update.listening_channel_add.is_empty() || !update.listening_channel_remove.is_empty() {
+        s.push_message(update, Destination::All);
+    }
+    if !volumes.listening_volume_adjustment.is_empty() {
+        s.push_message(volumes, Destination::Single(actor));
+    }
+    s
+}
+
+/// Add or remove the link between two channels, returns whether anything changed.
+fn set_link(s: &mut State, a: ChannelID, b: ChannelID, linked: bool) -> bool {
+    if a == b || s.channel_mut(a).is_none() || s.channel_mut(b).is_none() {
+        return false;
+    }
+
+    let mut changed = false;
+    for (from, to) in [(a, b), (b, a)] {
+        let links = &mut s.channel_mut(from).unwrap().links;
+        if linked && !links.contains(&to) {
+            links.push(to);
+            changed = true;
+        } else if !linked && links.contains(&to) {
+            links.retain(|l| *l != to);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Link and unlink channels, other changes to channels are not supported.
+fn handle_channel_state(mut s: State, actor: Session, msg: control::proto::ChannelState) -> State {
+    if msg.links_add.is_empty() && msg.links_remove.is_empty() {
+        return s;
+    }
+    let Some(channel) = msg.channel_id.map(ChannelID::new) else {
+        return s;
+    };
+
+    let allowed = s
+        .session_info
+        .get(&actor)
+        .is_some_and(|info| info.permissions() & Permissions::LINK_CHANNEL != 0);
+    if !allowed {
+        deny(&mut s, actor, Permissions::LINK_CHANNEL);
+        return s;
+    }
+
+    let mut update = control::proto::ChannelState {
+        channel_id: Some(channel.into()),
+        ..Default::default()
+    };
+    for link in msg.links_add {
+        if set_link(&mut s, channel, ChannelID::new(link), true) {
+            update.links_add.push(link);
+        }
+    }
+    for link in msg.links_remove {
+        if set_link(&mut s, channel, ChannelID::new(link), false) {
+            update.links_remove.push(link);
+        }
+    }
+
+    if !update.links_add.is_empty() || !update.links_remove.is_empty() {
+        s.channels_changed = true;
+        s.push_message(update, Destination::All);
+    }
+    s
+}
+
+/// Send the full content for hashes the client does not have cached.
+fn handle_request_blob(mut s: State, session: Session, req: control::proto::RequestBlob) -> State {
+    let dest = || Destination::Single(session);
+
+    for target in req.session_texture.into_iter().filter_map(Session::new) {
+        if let Some(info) = s.session_info.get(&target) {
+            let msg = control::proto::UserState {
+                session: Some(target.into()),
+                texture: Some(info.texture.clone()),
+                ..Default::default()
+            };
+            push_message(&mut s.outbox, &msg, dest());
+        }
+    }
+
+    for target in req.session_comment.into_iter().filter_map(Session::new) {
+        if let Some(info) = s.session_info.get(&target) {
+            let msg = control::proto::UserState {
+                session: Some(target.into()),
+                comment: Some(info.comment.clone()),
+                ..Default::default()
+            };
+            push_message(&mut s.outbox, &msg, dest());
+        }
+    }
+
+    for id in req.channel_description {
+        if let Some(channel) = s.channels.iter().find(|c| c.id.as_u32() == id) {
+            let msg = control::proto::ChannelState {
+                channel_id: Some(id),
+                description: Some(channel.description.clone()),
+                ..Default::default()
+            };
+            push_message(&mut s.outbox, &msg, dest());
+        }
+    }
+
+    s
+}
+
 fn handle_mumble_message(
     mut s: State,
     session: Session,
@@ -230,25 +845,79 @@ fn handle_mumble_message(
     if let Some(hs) = s.session_handshake.remove(&session) {
         return handle_handshake(s, hs, session, m, msg_received_at);
     }
+    let Some(info) = s.session_info.get_mut(&session) else {
+        // The user was removed, wait for the connection to close.
+        return s;
+    };
+    info.stats.last_seen_tcp = msg_received_at;
+    if m.typ != control::MessageType::Ping {
+        info.stats.last_active = msg_received_at;
+    }
+    if is_rate_limited(m.typ) && !check_rate_limit(&mut s, session, msg_received_at) {
+        return s;
+    }
+
+    if m.typ == control::MessageType::UserState {
+        let Some(msg) = decode::<control::proto::UserState>(session, &m) else {
+            return s;
+        };
+        s = handle_user_content(s, session, &msg);
+        s = handle_listening(s, session, &msg);
+        s = handle_priority_speaker(s, session, &msg);
+        s = handle_recording(s, session, &msg, msg_received_at);
+    }
 
     if let Some(event) = mumble_to_event(&s, &m, Some(session)) {
-        return handle_event(s, session, event);
+        return handle_event(s, session, event, msg_received_at);
     }
 
     match m.typ {
         control::MessageType::Ping => {
-            let p = control::proto::Ping::decode(m.body()).unwrap();
+            let Some(p) = decode::<control::proto::Ping>(session, &m) else {
+                return s;
+            };
+            let info = s
+                .session_info
+                .get_mut(&session)
+                .expect("session should have session info");
+
+            let crypt = info.voice_crypter.stats();
             let ping = control::proto::Ping {
-                good: p.good,
+                timestamp: p.timestamp,
+                good: Some(crypt.good),
+                late: Some(crypt.late),
+                lost: Some(crypt.lost),
+                resync: Some(crypt.resync),
                 ..Default::default()
             };
+            info.stats.last_ping = p;
             s.push_message(ping, Destination::Single(session));
         }
+        control::MessageType::ChannelState => {
+            let Some(msg) = decode::<control::proto::ChannelState>(session, &m) else {
+                return s;
+            };
+            return handle_channel_state(s, session, msg);
+        }
+        control::MessageType::RequestBlob => {
+            let Some(req) = decode::<control::proto::RequestBlob>(session, &m) else {
+                return s;
+            };
+            return handle_request_blob(s, session, req);
+        }
+        control::MessageType::UserStats => {
+            let Some(req) = decode::<control::proto::UserStats>(session, &m) else {
+                return s;
+            };
+            return handle_user_stats(s, session, req, msg_received_at);
+        }
         control::MessageType::PermissionQuery => {
-            let q = control::proto::PermissionQuery::decode(m.body()).unwrap();
+            let Some(q) = decode::<control::proto::PermissionQuery>(session, &m) else {
+                return s;
+            };
             let msg = control::proto::PermissionQuery {
                 channel_id: q.channel_id,
-                permissions: Some(mumble::permissions::default()),
+                permissions: s.session_info.get(&session).map(|i| i.permissions()),
                 ..Default::default()
             };
             s.push_message(msg, Destination::Single(session));
@@ -261,8 +930,10 @@ fn handle_mumble_message(
             };
             s.outbox.push(msg);
         }
-        control::MessageType::CryptSetup => todo!("Handle Crypt Resync"),
-        control::MessageType::UDPTunnel => unreachable!("UDPTunnel should be handled separately"),
+        control::MessageType::UDPTunnel => {
+            // audio was handled as an event, anything else is not forwarded
+            crate::tracing::debug!("dropping UDPTunnel message from {:?}", session);
+        }
         typ => {
             crate::tracing::info!("unhandled mumble message: {:#?}", typ);
         }
@@ -272,9 +943,10 @@ fn handle_mumble_message(
 }
 
 /// Send the server version to the client and add the session to the state.
-fn handle_session_new(mut s: State, session: Session) -> State {
-    let hs = handshake::State::new(session);
+fn handle_session_new(mut s: State, session: Session, peer: Peer) -> State {
+    let hs = handshake::State::new(session, peer);
     s.session_handshake.insert(session, hs);
+    s.push_audit(session, Action::Connected);
 
     s.push_message(version(), Destination::Single(session));
 
@@ -291,19 +963,24 @@ fn handle_tick(s: State, now: Instant) -> State {
 // #[instrument(skip(s, now, m))]
 pub fn handle_message(s: State, m: Message, now: Instant) -> State {
     match m {
-        Message::SessionCreated(session) => handle_session_new(s, session),
+        Message::SessionCreated(session, peer) => handle_session_new(s, session, peer),
         Message::SessionDisconnect(session) => handle_session_disconnect(s, session),
         Message::Mumble(session, m) => handle_mumble_message(s, session, m, now),
-        Message::UDP(from, data) => handle_udp_message(s, from, data, now),
+        Message::UDP(from, data) => handle_udp_message(s, canonical_addr(from), data, now),
         Message::Tick => handle_tick(s, now),
+        Message::BotCreated(session, b) => bot::handle_bot_created(s, session, b, now),
+        Message::BotAudio(session, msg) => bot::handle_bot_audio(s, session, msg, now),
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use prost::Message as _;
     use std::net::Ipv4Addr;
+    use std::time::Duration;
 
     use crate::common::{self, Channel};
+    use crate::server::audit;
     use crate::server::state::{MumbleCryptSetup, OutboxDestination, VoiceCrypter};
 
     use self::mumble::voice;
@@ -329,7 +1006,11 @@ mod tests {
         }
     }
 
-    fn perform_handshake(mut s: State, username: String) -> (State, Session) {
+    fn perform_handshake(s: State, username: String) -> (State, Session) {
+        perform_handshake_with_peer(s, username, Peer::default())
+    }
+
+    fn perform_handshake_with_peer(mut s: State, username: String, peer: Peer) -> (State, Session) {
         let session = s.new_session().unwrap();
         let auth = control::proto::Authenticate {
             username: Some(username),
@@ -339,7 +1020,7 @@ mod tests {
 
         let now = Instant::now();
         let s = vec![
-            Message::SessionCreated(session),
+            Message::SessionCreated(session, peer),
             Message::Mumble(session, message_to_buf(version())),
             Message::Mumble(session, message_to_buf(auth)),
         ]
@@ -408,6 +1089,7 @@ mod tests {
             max_users: None,
             position: None,
             parent: None,
+            links: vec![],
         };
         s.new_channel(channel.clone());
 
@@ -522,6 +1204,7 @@ mod tests {
             max_users: None,
             position: None,
             parent: None,
+            links: vec![],
         };
         s.new_channel(channel.clone());
 
@@ -537,7 +1220,7 @@ mod tests {
         s.outbox.drain(..);
 
         let msg = {
-            let (typ, _) = control::parse_prefix(&m.data[..control::proto::PREFIX_TOTAL_SIZE]);
+            let (typ, _) = control::parse_prefix(&m.data).unwrap();
             assert!(matches!(typ, control::MessageType::CryptSetup));
             control::proto::CryptSetup::decode(&m.data[control::proto::PREFIX_TOTAL_SIZE..])
                 .unwrap()
@@ -577,4 +1260,934 @@ mod tests {
         );
         assert_eq!(s.session_info.len(), 1);
     }
+
+    #[test]
+    fn test_udp_ipv4_mapped_addr() {
+        let s = new_state(10);
+        let (mut s, session) = perform_handshake(s, "username".to_string());
+        s.outbox.drain(..);
+
+        let packet = udp_ping_message_to_buf(mumble::voice::Ping {
+            timestamp: 1,
+            ..Default::default()
+        });
+
+        // first packet arrives on a dual-stack socket
+        let mapped = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).to_ipv6_mapped().into(), 8080);
+        let s = handle_message(s, Message::UDP(mapped, packet.clone()), Instant::now());
+
+        let addr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        assert_eq!(
+            s.session_info[&session].voice_transport,
+            VoiceTransport::Udp(addr)
+        );
+        assert_eq!(s.socketaddr_to_session.get(&addr), Some(&session));
+
+        // the same client seen on an IPv4 socket maps to the same session
+        let mut s = handle_message(s, Message::UDP(addr, packet.clone()), Instant::now());
+        let item = s.outbox.pop().expect("should have a ping packet");
+        assert_eq!(
+            (item.data, item.dest),
+            (
+                packet,
+                OutboxDestination::Session(Destination::Single(session))
+            )
+        );
+    }
+
+    fn user_remove(session: Session) -> MessageBuf {
+        message_to_buf(control::proto::UserRemove {
+            session: session.into(),
+            reason: Some("reason".to_string()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_kick_requires_admin() {
+        let s = new_state(10);
+        let (s, user) = perform_handshake(s, "user".to_string());
+        let (mut s, other) = perform_handshake(s, "other".to_string());
+        s.outbox.drain(..);
+        s.audit.drain(..);
+
+        let m = Message::Mumble(user, user_remove(other));
+        let mut s = handle_message(s, m, Instant::now());
+
+        want_message(
+            control::proto::PermissionDenied {
+                permission: Some(Permissions::KICK),
+                channel_id: Some(ROOT_CHANNEL.into()),
+                session: Some(user.into()),
+                r#type: Some(control::proto::permission_denied::DenyType::Permission.into()),
+                ..Default::default()
+            },
+            Destination::Single(user),
+            s.outbox.pop().unwrap(),
+        );
+        assert!(s.session_info.contains_key(&other));
+        assert_eq!(
+            s.audit.pop().map(|e| (e.session, e.action)),
+            Some((
+                user,
+                Action::Denied {
+                    permission: Permissions::KICK
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_admin_kick() {
+        let s = new_state(10);
+        let admin = Peer {
+            admin: true,
+            ..Default::default()
+        };
+        let (s, user) = perform_handshake_with_peer(s, "admin".to_string(), admin);
+        let (mut s, other) = perform_handshake(s, "other".to_string());
+        s.outbox.drain(..);
+        s.audit.drain(..);
+
+        let m = Message::Mumble(user, user_remove(other));
+        let mut s = handle_message(s, m, Instant::now());
+
+        s.outbox.reverse();
+        want_message(
+            control::proto::UserRemove {
+                session: other.into(),
+                actor: Some(user.into()),
+                reason: Some("reason".to_string()),
+                ban: None,
+            },
+            Destination::All,
+            s.outbox.pop().unwrap(),
+        );
+        let disconnect = s.outbox.pop().unwrap();
+        assert_eq!(
+            (disconnect.typ, disconnect.dest),
+            (
+                OutboxType::Disconnect,
+                OutboxDestination::Session(Destination::Single(other))
+            )
+        );
+        assert!(!s.session_info.contains_key(&other));
+        assert_eq!(
+            s.audit.pop().map(|e| (e.session, e.username, e.action)),
+            Some((
+                other,
+                Some("other".to_string()),
+                Action::Kicked {
+                    by: user,
+                    reason: Some("reason".to_string())
+                }
+            ))
+        );
+
+        // messages sent before the connection closes are ignored
+        let ping = message_to_buf(control::proto::Ping::default());
+        let s = handle_message(s, Message::Mumble(other, ping), Instant::now());
+        assert!(s.outbox.is_empty());
+
+        // session is only returned once the connection is closed
+        let mut s = handle_message(s, Message::SessionDisconnect(other), Instant::now());
+        assert!(s.audit.is_empty());
+        assert_eq!(s.new_session(), Some(other));
+    }
+
+    #[test]
+    fn test_audit_join() {
+        let peer = Peer {
+            addr: Some("127.0.0.1:8080".parse().unwrap()),
+            cert_hash: Some("abcd".to_string()),
+            ..Default::default()
+        };
+        let (s, session) = perform_handshake_with_peer(new_state(10), "user".to_string(), peer);
+
+        let entry = |username: Option<&str>, action| audit::Entry {
+            session,
+            username: username.map(str::to_string),
+            addr: Some("127.0.0.1:8080".parse().unwrap()),
+            cert_hash: Some("abcd".to_string()),
+            action,
+        };
+        assert_eq!(
+            s.audit,
+            vec![
+                entry(None, Action::Connected),
+                entry(
+                    Some("user"),
+                    Action::Joined {
+                        channel: ROOT_CHANNEL
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bandwidth_limit() {
+        let mut s = new_state(10);
+        // enough for two packets per second
+        let packet_size = 68 + VOICE_PACKET_OVERHEAD;
+        s.config.max_bandwidth = (packet_size * 2 * 8) as u32;
+        let (s, session) = perform_handshake(s, "user".to_string());
+        let (mut s, _) = perform_handshake(s, "listener".to_string());
+        s.outbox.drain(..);
+
+        let audio = || {
+            let body = udp_audio_message_to_buf(voice::Audio {
+                opus_data: vec![1u8; 68],
+                ..Default::default()
+            });
+            let mut data = vec![0u8; control::proto::PREFIX_TOTAL_SIZE + body.len()];
+            control::encode_udp_tunnel(&body, &mut data);
+            MessageBuf {
+                typ: control::MessageType::UDPTunnel,
+                data,
+            }
+        };
+        let now = Instant::now();
+        let send = |s: State, at: Instant| {
+            let mut s = handle_message(s, Message::Mumble(session, audio()), at);
+            let typ = s.outbox.pop().map(|m| m.typ);
+            assert!(s.outbox.is_empty());
+            (s, typ)
+        };
+
+        let (s, typ) = send(s, now);
+        assert_eq!(typ, Some(OutboxType::Voice));
+        let (s, typ) = send(s, now);
+        assert_eq!(typ, Some(OutboxType::Voice));
+
+        // dropped, only the first drop is reported
+        let (s, typ) = send(s, now);
+        assert_eq!(typ, Some(OutboxType::Control));
+        let (s, typ) = send(s, now + Duration::from_millis(500));
+        assert_eq!(typ, None);
+
+        let (_, typ) = send(s, now + Duration::from_secs(1));
+        assert_eq!(typ, Some(OutboxType::Voice));
+    }
+
+    #[test]
+    fn test_message_rate_limit() {
+        let mut s = new_state(10);
+        s.config.message_burst = 2;
+        s.config.message_limit = 1;
+        s.config.max_dropped_messages = 2;
+        let (mut s, session) = perform_handshake(s, "user".to_string());
+        s.outbox.drain(..);
+        s.audit.drain(..);
+
+        let text = || {
+            message_to_buf(control::proto::TextMessage {
+                message: "hello".to_string(),
+                ..Default::default()
+            })
+        };
+        let now = Instant::now();
+        let send = |s: State, at: Instant| {
+            let mut s = handle_message(s, Message::Mumble(session, text()), at);
+            let sent = s.outbox.drain(..).count();
+            (s, sent)
+        };
+
+        let (s, sent) = send(s, now);
+        assert_eq!(sent, 1);
+        let (s, sent) = send(s, now);
+        assert_eq!(sent, 1);
+        let (s, sent) = send(s, now);
+        assert_eq!(sent, 0);
+
+        // a token is available again after a second
+        let later = now + Duration::from_secs(1);
+        let (s, sent) = send(s, later);
+        assert_eq!(sent, 1);
+        let (s, sent) = send(s, later);
+        assert_eq!(sent, 0);
+        assert!(s.session_info.contains_key(&session));
+
+        // third message dropped without the bucket refilling
+        let mut s = handle_message(s, Message::Mumble(session, text()), later);
+        assert!(!s.session_info.contains_key(&session));
+        s.outbox.reverse();
+        assert_eq!(s.outbox.pop().map(|m| m.typ), Some(OutboxType::Control));
+        assert_eq!(s.outbox.pop().map(|m| m.typ), Some(OutboxType::Disconnect));
+        assert_eq!(s.audit.pop().map(|e| e.action), Some(Action::Flooding));
+    }
+
+    #[test]
+    fn test_user_stats() {
+        let peer = Peer {
+            addr: Some("127.0.0.1:8080".parse().unwrap()),
+            ..Default::default()
+        };
+        let (s, user) = perform_handshake_with_peer(new_state(10), "user".to_string(), peer);
+        let (mut s, other) = perform_handshake(s, "other".to_string());
+        s.outbox.drain(..);
+
+        let request = |s: State, from: Session, target: Session| {
+            let req = message_to_buf(control::proto::UserStats {
+                session: Some(target.into()),
+                ..Default::default()
+            });
+            let later = Instant::now() + Duration::from_secs(2);
+            let mut s = handle_message(s, Message::Mumble(from, req), later);
+            let m = s.outbox.pop().unwrap();
+            assert_eq!(
+                m.dest,
+                OutboxDestination::Session(Destination::Single(from))
+            );
+            let stats =
+                control::proto::UserStats::decode(&m.data[control::proto::PREFIX_TOTAL_SIZE..])
+                    .unwrap();
+            (s, stats)
+        };
+
+        // other users in the same channel can not see the address or version
+        let (s, stats) = request(s, other, user);
+        assert_eq!(stats.session, Some(user.into()));
+        assert!(stats.onlinesecs >= Some(2));
+        assert!(stats.from_server.is_some());
+        assert_eq!((stats.version, stats.address), (None, None));
+
+        let (_, stats) = request(s, user, user);
+        assert_eq!(stats.version.and_then(|v| v.os), Some("testOS".to_string()));
+        assert_eq!(
+            stats.address,
+            Some(
+                Ipv4Addr::new(127, 0, 0, 1)
+                    .to_ipv6_mapped()
+                    .octets()
+                    .to_vec()
+            )
+        );
+    }
+
+    #[test]
+    fn test_user_comment() {
+        let (s, user) = perform_handshake(new_state(10), "user".to_string());
+        let (mut s, other) = perform_handshake(s, "other".to_string());
+        s.outbox.drain(..);
+
+        let set_comment = |s: State, comment: &str| {
+            let msg = message_to_buf(control::proto::UserState {
+                comment: Some(comment.to_string()),
+                ..Default::default()
+            });
+            let mut s = handle_message(s, Message::Mumble(user, msg), Instant::now());
+            let m = s.outbox.remove(0);
+            assert_eq!(m.dest, OutboxDestination::Session(Destination::All));
+            let state =
+                control::proto::UserState::decode(&m.data[control::proto::PREFIX_TOTAL_SIZE..])
+                    .unwrap();
+            s.outbox.drain(..);
+            (s, state)
+        };
+
+        // short comments are sent inline
+        let (s, state) = set_comment(s, "hello");
+        assert_eq!(state.session, Some(user.into()));
+        assert_eq!(state.comment, Some("hello".to_string()));
+        assert_eq!(state.comment_hash, None);
+
+        // long comments only as a hash
+        let long = "a".repeat(blob::HASH_THRESHOLD);
+        let (s, state) = set_comment(s, &long);
+        assert_eq!(state.comment, None);
+        assert_eq!(state.comment_hash.map(|h| h.len()), Some(20));
+
+        let req = message_to_buf(control::proto::RequestBlob {
+            session_comment: vec![user.into()],
+            ..Default::default()
+        });
+        let mut s = handle_message(s, Message::Mumble(other, req), Instant::now());
+        want_message(
+            control::proto::UserState {
+                session: Some(user.into()),
+                comment: Some(long),
+                ..Default::default()
+            },
+            Destination::Single(other),
+            s.outbox.pop().unwrap(),
+        );
+        assert_eq!(s.outbox.pop(), None);
+    }
+
+    #[test]
+    fn test_user_comment_denied() {
+        let (s, user) = perform_handshake(new_state(10), "user".to_string());
+        let (mut s, other) = perform_handshake(s, "other".to_string());
+        s.config.max_comment_length = 10;
+        s.outbox.drain(..);
+
+        let too_long = message_to_buf(control::proto::UserState {
+            comment: Some("a".repeat(11)),
+            ..Default::default()
+        });
+        let mut s = handle_message(s, Message::Mumble(user, too_long), Instant::now());
+        want_message(
+            control::proto::PermissionDenied {
+                session: Some(user.into()),
+                r#type: Some(control::proto::permission_denied::DenyType::TextTooLong.into()),
+                ..Default::default()
+            },
+            Destination::Single(user),
+            s.outbox.remove(0),
+        );
+        s.outbox.drain(..);
+
+        // only admins can change the comment of other users
+        let other_comment = message_to_buf(control::proto::UserState {
+            session: Some(other.into()),
+            comment: Some(String::new()),
+            ..Default::default()
+        });
+        let mut s = handle_message(s, Message::Mumble(user, other_comment), Instant::now());
+        want_message(
+            control::proto::PermissionDenied {
+                permission: Some(Permissions::RESET_USER_CONTENT),
+                channel_id: Some(ROOT_CHANNEL.into()),
+                r#type: Some(control::proto::permission_denied::DenyType::Permission.into()),
+                session: Some(user.into()),
+                ..Default::default()
+            },
+            Destination::Single(user),
+            s.outbox.remove(0),
+        );
+    }
+
+    #[test]
+    fn test_channel_description_hash() {
+        let mut s = new_state(10);
+        s.new_channel(Channel {
+            id: common::ROOT_CHANNEL,
+            name: "Root".to_string(),
+            description: "a".repeat(blob::HASH_THRESHOLD),
+            temporary: false,
+            max_users: None,
+            position: None,
+            parent: None,
+            links: vec![],
+        });
+        let (mut s, session) = perform_handshake(s, "user".to_string());
+
+        s.outbox.reverse();
+        let state = s
+            .outbox
+            .iter()
+            .find(|m| m.data[1] == control::MessageType::ChannelState as u8)
+            .map(|m| {
+                control::proto::ChannelState::decode(&m.data[control::proto::PREFIX_TOTAL_SIZE..])
+                    .unwrap()
+            })
+            .unwrap();
+        assert_eq!(state.description, None);
+        assert!(state.description_hash.is_some());
+        s.outbox.drain(..);
+
+        let req = message_to_buf(control::proto::RequestBlob {
+            channel_description: vec![0],
+            ..Default::default()
+        });
+        let mut s = handle_message(s, Message::Mumble(session, req), Instant::now());
+        want_message(
+            control::proto::ChannelState {
+                channel_id: Some(0),
+                description: Some("a".repeat(blob::HASH_THRESHOLD)),
+                ..Default::default()
+            },
+            Destination::Single(session),
+            s.outbox.pop().unwrap(),
+        );
+    }
+
+    fn tunnel_audio(target: u32) -> MessageBuf {
+        let body = udp_audio_message_to_buf(voice::Audio {
+            opus_data: vec![1u8; 8],
+            header: Some(voice::audio::Header::Target(target)),
+            ..Default::default()
+        });
+        let mut data = vec![0u8; control::proto::PREFIX_TOTAL_SIZE + body.len()];
+        control::encode_udp_tunnel(&body, &mut data);
+        MessageBuf {
+            typ: control::MessageType::UDPTunnel,
+            data,
+        }
+    }
+
+    #[test]
+    fn test_channel_links() {
+        let mut s = new_state(10);
+        for id in 0..3 {
+            s.new_channel(Channel::new(
+                ChannelID::new(id),
+                format!("Channel{}", id),
+                String::new(),
+                false,
+                None,
+            ));
+        }
+        let admin_peer = Peer {
+            admin: true,
+            ..Default::default()
+        };
+        let (s, admin) = perform_handshake_with_peer(s, "admin".to_string(), admin_peer);
+        let (s, user) = perform_handshake(s, "user".to_string());
+        let (mut s, other) = perform_handshake(s, "other".to_string());
+        s.session_info.get_mut(&other).unwrap().user.channel = ChannelID::new(1);
+        s.outbox.drain(..);
+
+        let link = |links_add: Vec<u32>, links_remove: Vec<u32>| {
+            message_to_buf(control::proto::ChannelState {
+                channel_id: Some(0),
+                links_add,
+                links_remove,
+                ..Default::default()
+            })
+        };
+        let speak = |s: State| {
+            let mut s = handle_message(s, Message::Mumble(user, tunnel_audio(0)), Instant::now());
+            let dest = s.outbox.pop().map(|m| m.dest);
+            assert!(s.outbox.is_empty());
+            (s, dest)
+        };
+
+        // only the admin in the same channel hears the user
+        let (s, dest) = speak(s);
+        assert_eq!(
+            dest,
+            Some(OutboxDestination::Session(Destination::Group(vec![admin])))
+        );
+
+        let mut s = handle_message(
+            s,
+            Message::Mumble(user, link(vec![1], vec![])),
+            Instant::now(),
+        );
+        want_message(
+            control::proto::PermissionDenied {
+                permission: Some(Permissions::LINK_CHANNEL),
+                channel_id: Some(ROOT_CHANNEL.into()),
+                session: Some(user.into()),
+                r#type: Some(control::proto::permission_denied::DenyType::Permission.into()),
+                ..Default::default()
+            },
+            Destination::Single(user),
+            s.outbox.pop().unwrap(),
+        );
+
+        assert!(!s.channels_changed);
+
+        // linking to a missing channel is ignored
+        let mut s = handle_message(
+            s,
+            Message::Mumble(admin, link(vec![1, 5], vec![])),
+            Instant::now(),
+        );
+        want_message(
+            control::proto::ChannelState {
+                channel_id: Some(0),
+                links_add: vec![1],
+                ..Default::default()
+            },
+            Destination::All,
+            s.outbox.pop().unwrap(),
+        );
+        assert_eq!(s.channels[1].links, vec![ROOT_CHANNEL]);
+        assert!(s.channels_changed, "links are saved");
+
+        let (s, dest) = speak(s);
+        let Some(OutboxDestination::Session(Destination::Group(mut listeners))) = dest else {
+            panic!("expected a group, got {:?}", dest);
+        };
+        listeners.sort_by_key(|s| u32::from(*s));
+        assert_eq!(listeners, vec![admin, other]);
+
+        let mut s = handle_message(
+            s,
+            Message::Mumble(admin, link(vec![], vec![1])),
+            Instant::now(),
+        );
+        assert!(s.channels.iter().all(|c| c.links.is_empty()));
+        s.outbox.drain(..);
+        let (s, dest) = speak(s);
+        assert_eq!(
+            dest,
+            Some(OutboxDestination::Session(Destination::Group(vec![admin])))
+        );
+
+        // loopback only goes back to the speaker
+        let mut s = handle_message(s, Message::Mumble(user, tunnel_audio(31)), Instant::now());
+        assert_eq!(
+            s.outbox.pop().map(|m| m.dest),
+            Some(OutboxDestination::Session(Destination::Single(user)))
+        );
+    }
+
+    #[test]
+    fn test_channel_listener() {
+        let mut s = new_state(10);
+        for id in 0..2 {
+            s.new_channel(Channel::new(
+                ChannelID::new(id),
+                format!("Channel{}", id),
+                String::new(),
+                false,
+                None,
+            ));
+        }
+        let (s, speaker) = perform_handshake(s, "speaker".to_string());
+        let (mut s, listener) = perform_handshake(s, "listener".to_string());
+        s.session_info.get_mut(&listener).unwrap().user.channel = ChannelID::new(1);
+        s.outbox.drain(..);
+
+        let listen = message_to_buf(control::proto::UserState {
+            listening_channel_add: vec![0, 7],
+            listening_volume_adjustment: vec![control::proto::user_state::VolumeAdjustment {
+                listening_channel: Some(0),
+                volume_adjustment: Some(0.5),
+            }],
+            ..Default::default()
+        });
+        let mut s = handle_message(s, Message::Mumble(listener, listen), Instant::now());
+        s.outbox.reverse();
+        want_message(
+            control::proto::UserState {
+                session: Some(listener.into()),
+                actor: Some(listener.into()),
+                listening_channel_add: vec![0],
+                ..Default::default()
+            },
+            Destination::All,
+            s.outbox.pop().unwrap(),
+        );
+        want_message(
+            control::proto::UserState {
+                session: Some(listener.into()),
+                actor: Some(listener.into()),
+                listening_volume_adjustment: vec![control::proto::user_state::VolumeAdjustment {
+                    listening_channel: Some(0),
+                    volume_adjustment: Some(0.5),
+                }],
+                ..Default::default()
+            },
+            Destination::Single(listener),
+            s.outbox.pop().unwrap(),
+        );
+        assert_eq!(s.outbox.pop(), None);
+
+        let mut s = handle_message(s, Message::Mumble(speaker, tunnel_audio(0)), Instant::now());
+        let m = s.outbox.pop().unwrap();
+        assert_eq!(
+            m.dest,
+            OutboxDestination::Session(Destination::Group(vec![listener]))
+        );
+        let mumble::voice::Message::Audio(audio) = mumble::voice::Message::decode(&m.data).unwrap()
+        else {
+            panic!("expected audio");
+        };
+        assert_eq!(
+            audio.header,
+            Some(voice::audio::Header::Context(CONTEXT_LISTEN))
+        );
+        assert_eq!(audio.volume_adjustment, 0.5);
+        assert_eq!(s.outbox.pop(), None);
+
+        // other users can not change what someone listens to
+        let stop = || {
+            message_to_buf(control::proto::UserState {
+                session: Some(listener.into()),
+                listening_channel_remove: vec![0],
+                ..Default::default()
+            })
+        };
+        let s = handle_message(s, Message::Mumble(speaker, stop()), Instant::now());
+        assert_eq!(s.session_info[&listener].listening.len(), 1);
+
+        let mut s = handle_message(s, Message::Mumble(listener, stop()), Instant::now());
+        assert!(s.session_info[&listener].listening.is_empty());
+        s.outbox.drain(..);
+        let s = handle_message(s, Message::Mumble(speaker, tunnel_audio(0)), Instant::now());
+        assert!(s.outbox.is_empty());
+    }
+
+    #[test]
+    fn test_priority_speaker() {
+        let admin_peer = Peer {
+            admin: true,
+            ..Default::default()
+        };
+        let (s, host) = perform_handshake_with_peer(new_state(10), "host".to_string(), admin_peer);
+        let (mut s, user) = perform_handshake(s, "user".to_string());
+        s.outbox.drain(..);
+
+        let priority = |session: Session| {
+            message_to_buf(control::proto::UserState {
+                session: Some(session.into()),
+                priority_speaker: Some(true),
+                ..Default::default()
+            })
+        };
+
+        let mut s = handle_message(s, Message::Mumble(user, priority(user)), Instant::now());
+        want_message(
+            control::proto::PermissionDenied {
+                permission: Some(Permissions::MUTE_DEAFEN),
+                channel_id: Some(ROOT_CHANNEL.into()),
+                session: Some(user.into()),
+                r#type: Some(control::proto::permission_denied::DenyType::Permission.into()),
+                ..Default::default()
+            },
+            Destination::Single(user),
+            s.outbox.pop().unwrap(),
+        );
+        assert!(!s.session_info[&user].priority_speaker);
+
+        let mut s = handle_message(s, Message::Mumble(host, priority(host)), Instant::now());
+        want_message(
+            control::proto::UserState {
+                session: Some(host.into()),
+                actor: Some(host.into()),
+                priority_speaker: Some(true),
+                ..Default::default()
+            },
+            Destination::All,
+            s.outbox.pop().unwrap(),
+        );
+        assert!(s.session_info[&host].priority_speaker);
+    }
+
+    #[test]
+    fn test_recording() {
+        let mut s = new_state(10);
+        for id in 0..2 {
+            s.new_channel(Channel::new(
+                ChannelID::new(id),
+                format!("Channel{}", id),
+                String::new(),
+                false,
+                None,
+            ));
+        }
+        let admin_peer = Peer {
+            admin: true,
+            ..Default::default()
+        };
+        let (s, admin) = perform_handshake_with_peer(s, "admin".to_string(), admin_peer);
+        let (mut s, user) = perform_handshake(s, "user".to_string());
+        s.outbox.drain(..);
+
+        let record = |recording: bool| {
+            message_to_buf(control::proto::UserState {
+                recording: Some(recording),
+                ..Default::default()
+            })
+        };
+
+        let mut s = handle_message(s, Message::Mumble(user, record(true)), Instant::now());
+        let denied = s.outbox.pop().unwrap();
+        assert_eq!(
+            denied.dest,
+            OutboxDestination::Session(Destination::Single(user))
+        );
+        assert!(s.recorder.is_empty());
+
+        let now = Instant::now();
+        let mut s = handle_message(s, Message::Mumble(admin, record(true)), now);
+        assert_eq!(
+            s.recorder.pop(),
+            Some(recording::Event::Started {
+                channel: ROOT_CHANNEL,
+                at: now
+            })
+        );
+        // everyone in the channel is flagged
+        let mut flagged: Vec<_> = s
+            .outbox
+            .drain(..)
+            .map(|m| {
+                control::proto::UserState::decode(&m.data[control::proto::PREFIX_TOTAL_SIZE..])
+                    .unwrap()
+            })
+            .inspect(|m| assert_eq!(m.recording, Some(true)))
+            .filter_map(|m| m.session)
+            .collect();
+        flagged.sort();
+        assert_eq!(flagged, vec![u32::from(admin), u32::from(user)]);
+
+        let mut s = handle_message(s, Message::Mumble(user, tunnel_audio(0)), now);
+        let Some(recording::Event::Frame(frame)) = s.recorder.pop() else {
+            panic!("expected a recorded frame");
+        };
+        assert_eq!(
+            (frame.channel, frame.speaker, frame.username.as_str()),
+            (ROOT_CHANNEL, user, "user")
+        );
+        s.outbox.drain(..);
+
+        // leaving the recorded channel clears the flag
+        let switch = message_to_buf(control::proto::UserState {
+            session: Some(user.into()),
+            channel_id: Some(1),
+            ..Default::default()
+        });
+        let mut s = handle_message(s, Message::Mumble(user, switch), now);
+        let m = s.outbox.pop().unwrap();
+        let state = control::proto::UserState::decode(&m.data[control::proto::PREFIX_TOTAL_SIZE..])
+            .unwrap();
+        assert_eq!(state.recording, Some(false));
+
+        let s = handle_message(s, Message::Mumble(user, tunnel_audio(0)), now);
+        assert!(s.recorder.is_empty());
+
+        let mut s = handle_message(s, Message::Mumble(admin, record(false)), now);
+        assert_eq!(
+            s.recorder.pop(),
+            Some(recording::Event::Stopped {
+                channel: ROOT_CHANNEL
+            })
+        );
+
+        // servers can let anyone record
+        s.config.allow_recording = true;
+        let mut s = handle_message(s, Message::Mumble(user, record(true)), now);
+        assert_eq!(
+            s.recorder.pop(),
+            Some(recording::Event::Started {
+                channel: ChannelID::new(1),
+                at: now
+            })
+        );
+    }
+
+    #[test]
+    fn test_bot() {
+        let mut s = new_state(10);
+        s.new_channel(Channel::new(
+            ChannelID::new(0),
+            "Root".to_string(),
+            String::new(),
+            false,
+            None,
+        ));
+        let (mut s, user) = perform_handshake(s, "user".to_string());
+        s.outbox.drain(..);
+
+        let bot = s.new_session().unwrap();
+        let b = bot::Bot {
+            name: "bot".to_string(),
+            channel: ROOT_CHANNEL,
+        };
+        let mut s = handle_message(s, Message::BotCreated(bot, b), Instant::now());
+        want_message(
+            control::proto::UserState {
+                session: Some(bot.into()),
+                name: Some("bot".to_string()),
+                channel_id: Some(0),
+                ..Default::default()
+            },
+            Destination::AllButOne(bot),
+            s.outbox.pop().unwrap(),
+        );
+        assert_eq!(s.outbox.pop(), None);
+
+        // the bot hears the user instead of receiving a voice packet
+        let mut s = handle_message(s, Message::Mumble(user, tunnel_audio(0)), Instant::now());
+        assert_eq!(s.outbox.pop(), None);
+        let (to, heard) = s.bot_outbox.pop().unwrap();
+        assert_eq!(to, bot);
+        assert_eq!(heard.sender, user);
+        assert_eq!(heard.data, vec![1u8; 8]);
+
+        let audio = events::VoiceMessage {
+            data: vec![2u8; 8],
+            frame_number: 0,
+            sender: bot,
+            target: TARGET_NORMAL,
+            is_terminator: false,
+        };
+        let mut s = handle_message(s, Message::BotAudio(bot, audio.clone()), Instant::now());
+        let m = s.outbox.pop().unwrap();
+        assert_eq!(
+            m.dest,
+            OutboxDestination::Session(Destination::Group(vec![user]))
+        );
+        assert!(s.bot_outbox.is_empty());
+
+        // users can not speak as a bot
+        let s = handle_message(s, Message::BotAudio(user, audio), Instant::now());
+        assert!(s.outbox.is_empty());
+
+        let mut s = handle_message(s, Message::SessionDisconnect(bot), Instant::now());
+        assert!(!s.session_info.contains_key(&bot));
+        want_message(
+            control::proto::UserRemove {
+                session: bot.into(),
+                ..Default::default()
+            },
+            Destination::AllButOne(bot),
+            s.outbox.pop().unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        use crate::server::snapshot;
+
+        let mut s = new_state(10);
+        for id in 0..2 {
+            s.new_channel(Channel::new(
+                ChannelID::new(id),
+                format!("Channel{}", id),
+                String::new(),
+                false,
+                None,
+            ));
+        }
+        let (s, _first) = perform_handshake(s, "first".to_string());
+        let (mut s, user) = perform_handshake(s, "user".to_string());
+        let info = s.session_info.get_mut(&user).unwrap();
+        info.comment = "comment".to_string();
+        info.listening.insert(ChannelID::new(1), 0.5);
+        let now = Instant::now();
+        s.start_recording(ChannelID::new(1), now);
+
+        let taken = snapshot::take(&mut s, now);
+        assert_eq!(taken.users.len(), 2);
+        assert_eq!(taken.recording, vec![ChannelID::new(1)]);
+
+        // users are only restored when asked for
+        let restored = snapshot::restore(taken.clone(), s.voice_crypter, now, false);
+        assert_eq!(restored.channels.len(), 2);
+        assert!(restored.is_recording(ChannelID::new(1)));
+        assert!(restored.session_info.is_empty());
+
+        let restored = snapshot::restore(taken, s.voice_crypter, now, true);
+        assert_eq!(restored.channels.len(), 2);
+        assert!(restored.is_recording(ChannelID::new(1)));
+        assert!(restored.outbox.is_empty());
+        let info = restored.session_info.get(&user).unwrap();
+        assert_eq!(info.user.name, "user");
+        assert_eq!(info.comment, "comment");
+        assert_eq!(info.listening.get(&ChannelID::new(1)), Some(&0.5));
+        assert_eq!(info.voice_transport, VoiceTransport::Bot);
+        assert!(restored.restored.contains(&user));
+
+        // restored sessions are not handed out again
+        let admin = Peer {
+            admin: true,
+            ..Default::default()
+        };
+        let (restored, admin) = perform_handshake_with_peer(restored, "admin".to_string(), admin);
+        assert!(admin != user);
+
+        // until the restored user is kicked, nothing else would free their session
+        let m = Message::Mumble(admin, user_remove(user));
+        let mut restored = handle_message(restored, m, now);
+        assert!(!restored.session_info.contains_key(&user));
+        assert!(!restored.restored.contains(&user));
+        assert_eq!(restored.new_session(), Some(user));
+    }
 }
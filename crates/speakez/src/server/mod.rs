@@ -1,5 +1,10 @@
+pub mod audit;
+mod blob;
+pub mod bot;
 mod handshake;
 mod messages;
+pub mod recording;
+pub mod snapshot;
 pub mod state;
 
 pub use messages::{handle_message, Message};
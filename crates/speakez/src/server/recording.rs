@@ -0,0 +1,28 @@
+use std::time::Instant;
+
+use crate::common::ChannelID;
+use crate::mumble::session::Session;
+
+/// Audio heard in a recorded channel, the server is responsible for writing it to disk.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Started {
+        channel: ChannelID,
+        at: Instant,
+    },
+    Frame(Frame),
+    /// No more frames will be sent for the channel until it is started again.
+    Stopped {
+        channel: ChannelID,
+    },
+}
+
+/// A single Opus packet from a speaker.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub channel: ChannelID,
+    pub speaker: Session,
+    pub username: String,
+    pub at: Instant,
+    pub data: Vec<u8>,
+}
@@ -0,0 +1,202 @@
+//! A copy of the server state that can be written to disk for debugging, and used to
+//! start another server in the same state. Crypto keys and certificates are left out.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::common::{Channel, ChannelID, User};
+use crate::mumble::handshake::server::ClientVersion;
+use crate::mumble::session::Session;
+use crate::mumble::{self};
+
+use super::state::{
+    Config, CryptStats, NewVoiceCrypter, Peer, RateLimiter, SessionInfo, SessionStats, State,
+    VoiceTransport,
+};
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub config: Config,
+    pub channels: Vec<Channel>,
+    pub recording: Vec<ChannelID>,
+    pub users: Vec<UserSnapshot>,
+    /// Connections that have not finished the handshake.
+    pub handshakes: Vec<Session>,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug)]
+pub struct UserSnapshot {
+    pub user: User,
+    pub addr: Option<SocketAddr>,
+    pub cert_hash: Option<String>,
+    pub admin: bool,
+    /// None when voice is tunneled over TCP.
+    pub udp_addr: Option<SocketAddr>,
+    pub bot: bool,
+    pub version: u64,
+    pub release: Option<String>,
+    pub os: Option<String>,
+    pub os_version: Option<String>,
+    pub comment: String,
+    /// Only the size of the avatar is kept.
+    pub texture_size: usize,
+    pub listening: Vec<(ChannelID, f32)>,
+    pub priority_speaker: bool,
+    pub stats: UserStats,
+}
+
+/// Times are in seconds before the snapshot was taken.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug)]
+pub struct UserStats {
+    pub connected: u64,
+    pub last_active: u64,
+    pub last_seen_tcp: u64,
+    pub last_seen_udp: Option<u64>,
+    /// Incoming audio in bits per second.
+    pub bandwidth: u32,
+    pub dropped_messages: u32,
+    /// Packets decrypted by the server.
+    pub crypt: CryptStats,
+    pub tcp_ping_avg: f32,
+    pub udp_ping_avg: f32,
+    pub client_lost: u32,
+    pub client_late: u32,
+}
+
+fn secs_ago(now: Instant, at: Instant) -> u64 {
+    now.saturating_duration_since(at).as_secs()
+}
+
+fn instant_ago(now: Instant, secs: u64) -> Instant {
+    now.checked_sub(Duration::from_secs(secs)).unwrap_or(now)
+}
+
+pub fn take(s: &mut State, now: Instant) -> Snapshot {
+    let mut users: Vec<_> = s
+        .session_info
+        .values_mut()
+        .map(|info| {
+            let stats = &mut info.stats;
+            let ping = &stats.last_ping;
+            UserSnapshot {
+                user: info.user.clone(),
+                addr: info.peer.addr,
+                cert_hash: info.peer.cert_hash.clone(),
+                admin: info.peer.admin,
+                udp_addr: match info.voice_transport {
+                    VoiceTransport::Udp(addr) => Some(addr),
+                    _ => None,
+                },
+                bot: info.voice_transport == VoiceTransport::Bot,
+                version: info.version.version.to_u64(),
+                release: info.version.release.clone(),
+                os: info.version.os.clone(),
+                os_version: info.version.os_version.clone(),
+                comment: info.comment.clone(),
+                texture_size: info.texture.len(),
+                listening: info.listening.iter().map(|(c, v)| (*c, *v)).collect(),
+                priority_speaker: info.priority_speaker,
+                stats: UserStats {
+                    connected: secs_ago(now, stats.connected_at),
+                    last_active: secs_ago(now, stats.last_active),
+                    last_seen_tcp: secs_ago(now, stats.last_seen_tcp),
+                    last_seen_udp: stats.last_seen_udp.map(|at| secs_ago(now, at)),
+                    bandwidth: stats.bandwidth.bits_per_sec(now),
+                    dropped_messages: stats.messages.dropped(),
+                    crypt: info.voice_crypter.stats(),
+                    tcp_ping_avg: ping.tcp_ping_avg.unwrap_or_default(),
+                    udp_ping_avg: ping.udp_ping_avg.unwrap_or_default(),
+                    client_lost: ping.lost.unwrap_or_default(),
+                    client_late: ping.late.unwrap_or_default(),
+                },
+            }
+        })
+        .collect();
+    users.sort_by_key(|u| u32::from(u.user.session));
+
+    let mut recording: Vec<_> = s.recording.iter().copied().collect();
+    recording.sort_by_key(|c| c.as_u32());
+    let mut handshakes: Vec<_> = s.session_handshake.keys().copied().collect();
+    handshakes.sort_by_key(|session| u32::from(*session));
+
+    Snapshot {
+        config: s.config.clone(),
+        channels: s.channels.clone(),
+        recording,
+        users,
+        handshakes,
+    }
+}
+
+/// Start a server from a snapshot. With `users` set, users are restored without a
+/// connection, the same way bots are, so new clients see the server as it was. They hold
+/// their session until kicked. Handshakes are dropped.
+pub fn restore(
+    snapshot: Snapshot,
+    voice_crypter: NewVoiceCrypter,
+    now: Instant,
+    users: bool,
+) -> State {
+    let mut s = State::new(snapshot.config.max_users, voice_crypter);
+    s.config = snapshot.config;
+    for channel in snapshot.channels {
+        s.new_channel(channel);
+    }
+
+    let users = if users { snapshot.users } else { vec![] };
+    for u in users {
+        let session = u.user.session;
+        if !s.sessions.take(session) {
+            crate::tracing::warn!("skipping user with unavailable session {:?}", session);
+            continue;
+        }
+
+        let stats = SessionStats {
+            last_seen_tcp: instant_ago(now, u.stats.last_seen_tcp),
+            last_seen_udp: u.stats.last_seen_udp.map(|secs| instant_ago(now, secs)),
+            connected_at: instant_ago(now, u.stats.connected),
+            last_active: instant_ago(now, u.stats.last_active),
+            last_ping: Default::default(),
+            bandwidth: Default::default(),
+            messages: RateLimiter::new(now, &s.config),
+        };
+        let info = SessionInfo {
+            voice_transport: VoiceTransport::Bot,
+            voice_crypter: (s.voice_crypter)(),
+            user: u.user,
+            stats,
+            peer: Peer {
+                addr: u.addr,
+                cert_hash: u.cert_hash,
+                certificates: vec![],
+                admin: u.admin,
+            },
+            version: ClientVersion {
+                version: mumble::Version::from_u64(u.version),
+                release: u.release,
+                os: u.os,
+                os_version: u.os_version,
+            },
+            comment: u.comment,
+            texture: vec![],
+            listening: HashMap::from_iter(u.listening),
+            priority_speaker: u.priority_speaker,
+        };
+        s.session_info.insert(session, info);
+        s.restored.insert(session);
+    }
+
+    for channel in snapshot.recording {
+        s.start_recording(channel, now);
+    }
+    // there is nobody connected to send the recording flags to
+    s.outbox.clear();
+    s
+}
@@ -1,27 +1,149 @@
-use crate::common::events::UserState;
-use crate::common::{Channel, User};
-use std::collections::HashMap;
+use crate::common::events::{UserState, VoiceMessage};
+use crate::common::{Channel, ChannelID, User};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
 use std::net::SocketAddr;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use bytes::BytesMut;
 
+use crate::mumble::handshake::server::ClientVersion;
 use crate::mumble::session::Session;
 use crate::mumble::{self};
 
-use super::handshake;
+use super::{audit, handshake, recording};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum VoiceTransport {
     Tcp,
     Udp(SocketAddr),
+    /// The session is a bot, voice is delivered through `State::bot_outbox`.
+    Bot,
 }
 
 #[derive(Debug)]
 pub struct SessionStats {
     pub(crate) last_seen_tcp: Instant,
     pub(crate) last_seen_udp: Option<Instant>,
+    /// When the handshake completed.
+    pub(crate) connected_at: Instant,
+    /// Last time the user sent audio or a message other than a ping.
+    pub(crate) last_active: Instant,
+    /// Packet and ping statistics as reported by the client.
+    pub(crate) last_ping: mumble::control::proto::Ping,
+    pub(crate) bandwidth: Bandwidth,
+    pub(crate) messages: RateLimiter,
+}
+
+/// Token bucket limiting how often a session can send control messages.
+#[derive(Debug)]
+pub struct RateLimiter {
+    tokens: f32,
+    updated: Instant,
+    /// Messages dropped since the bucket was last full.
+    dropped: u32,
+}
+
+impl RateLimiter {
+    pub fn new(now: Instant, config: &Config) -> Self {
+        RateLimiter {
+            tokens: config.message_burst as f32,
+            updated: now,
+            dropped: 0,
+        }
+    }
+
+    /// Take a token if one is available, otherwise count the message as dropped.
+    pub fn allow(&mut self, now: Instant, config: &Config) -> bool {
+        let burst = config.message_burst as f32;
+        let elapsed = now.duration_since(self.updated).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * config.message_limit as f32).min(burst);
+        self.updated = now;
+
+        if self.tokens >= burst {
+            self.dropped = 0;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return true;
+        }
+
+        self.dropped += 1;
+        false
+    }
+
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+}
+
+/// Period incoming audio is measured over.
+const BANDWIDTH_WINDOW: Duration = Duration::from_secs(1);
+/// Minimum time between letting a client know it is over the limit.
+const BANDWIDTH_WARN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Incoming audio of a session over a sliding window.
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    packets: VecDeque<(Instant, usize)>,
+    bytes: usize,
+    last_warned: Option<Instant>,
+}
+
+impl Bandwidth {
+    /// Record a packet of `size` bytes. Returns false, without recording the packet, when
+    /// it would put the session over `max_bandwidth` bits per second.
+    pub fn try_add(&mut self, now: Instant, size: usize, max_bandwidth: u32) -> bool {
+        self.expire(now);
+
+        let max_bytes = max_bandwidth as u128 * BANDWIDTH_WINDOW.as_millis() / 8000;
+        if (self.bytes + size) as u128 > max_bytes {
+            return false;
+        }
+
+        self.packets.push_back((now, size));
+        self.bytes += size;
+        true
+    }
+
+    pub fn bits_per_sec(&mut self, now: Instant) -> u32 {
+        self.expire(now);
+        (self.bytes as u128 * 8000 / BANDWIDTH_WINDOW.as_millis()) as u32
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(at, size)) = self.packets.front() {
+            if now.duration_since(at) < BANDWIDTH_WINDOW {
+                break;
+            }
+            self.packets.pop_front();
+            self.bytes -= size;
+        }
+    }
+
+    /// Returns true when the client should be told about dropped packets.
+    pub fn should_warn(&mut self, now: Instant) -> bool {
+        let warn = self
+            .last_warned
+            .is_none_or(|at| now.duration_since(at) >= BANDWIDTH_WARN_INTERVAL);
+        if warn {
+            self.last_warned = Some(now);
+        }
+        warn
+    }
+}
+
+/// Details about the connection a session was created from.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Peer {
+    /// None for connections over the local admin socket.
+    pub addr: Option<SocketAddr>,
+    /// Hex encoded SHA1 hash of the client certificate, if one was provided.
+    pub cert_hash: Option<String>,
+    /// DER encoded client certificate chain.
+    pub certificates: Vec<Vec<u8>>,
+    /// Admins are allowed to kick and move other users.
+    pub admin: bool,
 }
 
 pub struct MumbleCryptSetup {
@@ -30,10 +152,23 @@ pub struct MumbleCryptSetup {
     pub server_nonce: Vec<u8>,
 }
 
+/// Packets decrypted by a VoiceCrypter.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CryptStats {
+    pub good: u32,
+    pub late: u32,
+    pub lost: u32,
+    pub resync: u32,
+}
+
 pub trait VoiceCrypter {
     fn encrypt(&mut self, buf: &mut BytesMut);
     fn decrypt(&mut self, buf: &mut BytesMut) -> Result<(), io::Error>;
     fn crypt_setup(&self) -> MumbleCryptSetup;
+    fn stats(&self) -> CryptStats {
+        CryptStats::default()
+    }
 }
 
 pub struct SessionInfo {
@@ -41,6 +176,17 @@ pub struct SessionInfo {
     pub voice_crypter: Box<dyn VoiceCrypter>,
     pub(crate) user: User,
     pub(crate) stats: SessionStats,
+    pub(crate) peer: Peer,
+    pub(crate) version: ClientVersion,
+    /// Empty when the user has not set a comment.
+    pub(crate) comment: String,
+    /// Avatar image, empty when not set.
+    pub(crate) texture: Vec<u8>,
+    /// Channels the user hears without having joined them, along with the volume
+    /// adjustment for each.
+    pub(crate) listening: HashMap<ChannelID, f32>,
+    /// Other users are ducked while a priority speaker talks.
+    pub(crate) priority_speaker: bool,
 }
 
 // impl SessionInfo {
@@ -59,26 +205,54 @@ pub struct SessionInfo {
 //     }
 // }
 
+impl SessionInfo {
+    pub fn permissions(&self) -> u32 {
+        if self.peer.admin {
+            mumble::permissions::admin()
+        } else {
+            mumble::permissions::default()
+        }
+    }
+}
+
 impl std::fmt::Debug for SessionInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("SessionInfo")
             .field("voice_transport", &self.voice_transport)
             .field("user", &self.user)
             .field("stats", &self.stats)
+            .field("peer", &self.peer)
+            .field("version", &self.version)
             .finish()
     }
 }
 
-#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug)]
 pub struct Config {
     pub max_bandwidth: u32,
     pub max_users: u16,
+    /// Messages per second a session can send once its burst has been used up.
+    pub message_limit: u32,
+    /// Messages a session can send at once.
+    pub message_burst: u32,
+    /// Sessions are disconnected after this many messages are dropped without giving
+    /// the limiter time to recover.
+    pub max_dropped_messages: u32,
+    /// Maximum length in bytes of a user comment.
+    pub max_comment_length: usize,
+    /// Maximum size in bytes of a user avatar.
+    pub max_texture_size: usize,
+    /// Let users start recording the channel they are in, admins always can.
+    pub allow_recording: bool,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum OutboxType {
     Control,
     Voice,
+    /// Close the connection once the queued messages have been sent.
+    Disconnect,
 }
 
 #[derive(Debug, PartialEq)]
@@ -107,12 +281,23 @@ pub struct State {
     pub config: Config,
     pub(in crate::server) sessions: mumble::session::Sessions,
     pub(in crate::server) channels: Vec<Channel>,
+    /// Set when channels were changed and should be saved, cleared by whoever saves them.
+    pub channels_changed: bool,
 
     pub session_handshake: HashMap<Session, handshake::State>,
     pub session_info: HashMap<Session, SessionInfo>,
     pub socketaddr_to_session: HashMap<SocketAddr, Session>,
 
     pub outbox: Vec<OutboxMessage>,
+    pub audit: Vec<audit::Entry>,
+    /// Channels whose audio is being recorded.
+    pub(in crate::server) recording: HashSet<ChannelID>,
+    pub recorder: Vec<recording::Event>,
+    /// Audio heard by bots.
+    pub bot_outbox: Vec<(Session, VoiceMessage)>,
+    /// Users restored from a snapshot. Nothing is connected for them, so their session is
+    /// freed as soon as they are removed.
+    pub restored: HashSet<Session>,
 
     pub voice_crypter: NewVoiceCrypter,
 }
@@ -123,6 +308,13 @@ impl UserState for State {
     }
 }
 
+/// IPv4 clients talking to a dual-stack socket show up with an IPv4-mapped IPv6
+/// address. Convert those back to plain IPv4 so a client maps to the same address
+/// regardless of which socket its packets arrived on.
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
 pub type NewVoiceCrypter = fn() -> Box<dyn VoiceCrypter>;
 
 pub fn push_message(
@@ -145,12 +337,24 @@ impl State {
             config: Config {
                 max_bandwidth: 480000,
                 max_users,
+                message_limit: 1,
+                message_burst: 5,
+                max_dropped_messages: 20,
+                max_comment_length: 5000,
+                max_texture_size: 128 * 1024,
+                allow_recording: false,
             },
             channels: vec![],
+            channels_changed: false,
             session_handshake: HashMap::with_capacity(max_users.into()),
             session_info: HashMap::with_capacity(max_users.into()),
             socketaddr_to_session: HashMap::with_capacity(max_users.into()),
             outbox: Vec::with_capacity(max_users.into()),
+            audit: vec![],
+            recording: HashSet::new(),
+            recorder: vec![],
+            bot_outbox: vec![],
+            restored: HashSet::new(),
             // udp_outbox: Vec::with_capacity(max_users.into()),
             voice_crypter,
         }
@@ -164,6 +368,10 @@ impl State {
         self.channels.push(c)
     }
 
+    pub fn channels(&self) -> &[Channel] {
+        &self.channels
+    }
+
     pub fn push_message(&mut self, m: impl mumble::control::Message, dest: Destination) {
         push_message(&mut self.outbox, &m, dest);
     }
@@ -195,10 +403,113 @@ impl State {
         self.outbox.push(msg);
     }
 
-    pub fn delete_session(&mut self, s: Session) -> Option<SessionInfo> {
-        self.sessions.return_session(s);
-        self.session_handshake.remove(&s);
+    pub fn push_disconnect(&mut self, session: Session) {
+        let msg = OutboxMessage {
+            typ: OutboxType::Disconnect,
+            data: vec![],
+            dest: OutboxDestination::Session(Destination::Single(session)),
+        };
+        self.outbox.push(msg);
+    }
+
+    pub fn push_audit(&mut self, session: Session, action: audit::Action) {
+        let (username, peer) = match self.session_info.get(&session) {
+            Some(info) => (Some(info.user.name.clone()), &info.peer),
+            None => match self.session_handshake.get(&session) {
+                Some(hs) => (None, &hs.peer),
+                None => return,
+            },
+        };
+
+        self.audit.push(audit::Entry {
+            session,
+            username,
+            addr: peer.addr,
+            cert_hash: peer.cert_hash.clone(),
+            action,
+        });
+    }
+
+    pub fn channel_mut(&mut self, id: ChannelID) -> Option<&mut Channel> {
+        self.channels.iter_mut().find(|c| c.id == id)
+    }
 
+    /// The channel along with every channel reachable through its links.
+    pub fn linked_channels(&self, channel: ChannelID) -> HashSet<ChannelID> {
+        let mut found = HashSet::from([channel]);
+        let mut queue = vec![channel];
+        while let Some(id) = queue.pop() {
+            let Some(c) = self.channels.iter().find(|c| c.id == id) else {
+                continue;
+            };
+            for link in &c.links {
+                if found.insert(*link) {
+                    queue.push(*link);
+                }
+            }
+        }
+        found
+    }
+
+    pub fn is_recording(&self, channel: ChannelID) -> bool {
+        self.recording.contains(&channel)
+    }
+
+    /// Start recording `channel`, users in the channel are flagged as recording so
+    /// everyone can see they are being recorded.
+    pub fn start_recording(&mut self, channel: ChannelID, now: Instant) {
+        if self.recording.insert(channel) {
+            self.recorder
+                .push(recording::Event::Started { channel, at: now });
+            self.push_recording_flags(channel, true);
+        }
+    }
+
+    pub fn stop_recording(&mut self, channel: ChannelID) {
+        if self.recording.remove(&channel) {
+            self.recorder.push(recording::Event::Stopped { channel });
+            self.push_recording_flags(channel, false);
+        }
+    }
+
+    fn push_recording_flags(&mut self, channel: ChannelID, recording: bool) {
+        let sessions: Vec<_> = self
+            .session_info
+            .values()
+            .filter(|info| info.user.channel == channel)
+            .map(|info| info.user.session)
+            .collect();
+        for session in sessions {
+            let msg = mumble::control::proto::UserState {
+                session: Some(session.into()),
+                recording: Some(recording),
+                ..Default::default()
+            };
+            self.push_message(msg, Destination::All);
+        }
+    }
+
+    pub fn is_admin(&self, session: Session) -> bool {
+        self.session_info
+            .get(&session)
+            .is_some_and(|info| info.peer.admin)
+    }
+
+    /// Switch the session to voice over UDP, sent from `addr`.
+    pub fn set_udp_addr(&mut self, session: Session, addr: SocketAddr) {
+        let Some(info) = self.session_info.get_mut(&session) else {
+            return;
+        };
+        if let VoiceTransport::Udp(old) = info.voice_transport {
+            self.socketaddr_to_session.remove(&old);
+        }
+        info.voice_transport = VoiceTransport::Udp(addr);
+        self.socketaddr_to_session.insert(addr, session);
+    }
+
+    /// Remove the user from the server. The session stays reserved until the connection
+    /// is closed and `delete_session` is called.
+    pub fn remove_user(&mut self, s: Session) -> Option<SessionInfo> {
         let info = self.session_info.remove(&s);
 
         if let Some(info) = &info {
@@ -209,4 +520,10 @@ impl State {
 
         info
     }
+
+    pub fn delete_session(&mut self, s: Session) -> Option<SessionInfo> {
+        self.sessions.return_session(s);
+        self.session_handshake.remove(&s);
+        self.remove_user(s)
+    }
 }
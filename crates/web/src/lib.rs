@@ -65,8 +65,7 @@ impl MessageBufWrapper {
 
 #[wasm_bindgen]
 pub fn new_message_buf(data: Vec<u8>) -> Option<MessageBufWrapper> {
-    let prefix = control::get_prefix_from_buf(&data)?;
-    let (typ, size) = control::parse_prefix(prefix);
+    let (typ, _size) = control::parse_prefix(&data).ok()?;
     let buf = control::MessageBuf { typ, data };
     Some(MessageBufWrapper(buf))
 }
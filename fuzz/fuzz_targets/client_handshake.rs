@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use speakez::mumble::handshake::client;
+
+fuzz_target!(|data: &[u8]| {
+    let mut s = client::State::new();
+    for m in speakez_fuzz::messages(data) {
+        let _ = s.handle(m);
+    }
+});
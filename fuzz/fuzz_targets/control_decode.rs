@@ -0,0 +1,70 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use prost::Message as _;
+use speakez::common::{events, User};
+use speakez::mumble::control::{self, proto, MessageBuf, MessageType};
+use speakez::mumble::session::Session;
+
+struct NoUsers;
+
+impl events::UserState for NoUsers {
+    fn get_user(&self, _session: &Session) -> Option<&User> {
+        None
+    }
+}
+
+macro_rules! decode {
+    ($typ:expr, $body:expr, $($variant:ident => $msg:ty),* $(,)?) => {
+        match $typ {
+            $(MessageType::$variant => {
+                let _ = <$msg>::decode($body);
+            })*
+        }
+    };
+}
+
+// The first two bytes pick the message type, the rest is the body.
+fuzz_target!(|data: &[u8]| {
+    let Some((typ, body)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let Some(typ) = MessageType::from_u16(u16::from_be_bytes(*typ)) else {
+        return;
+    };
+
+    decode!(typ, body,
+        Version => proto::Version,
+        UDPTunnel => proto::UdpTunnel,
+        Authenticate => proto::Authenticate,
+        Ping => proto::Ping,
+        Reject => proto::Reject,
+        ServerSync => proto::ServerSync,
+        ChannelRemove => proto::ChannelRemove,
+        ChannelState => proto::ChannelState,
+        UserRemove => proto::UserRemove,
+        UserState => proto::UserState,
+        BanList => proto::BanList,
+        TextMessage => proto::TextMessage,
+        PermissionDenied => proto::PermissionDenied,
+        ACL => proto::Acl,
+        QueryUsers => proto::QueryUsers,
+        CryptSetup => proto::CryptSetup,
+        ContextActionModify => proto::ContextActionModify,
+        ContextAction => proto::ContextAction,
+        UserList => proto::UserList,
+        VoiceTarget => proto::VoiceTarget,
+        PermissionQuery => proto::PermissionQuery,
+        CodecVersion => proto::CodecVersion,
+        UserStats => proto::UserStats,
+        RequestBlob => proto::RequestBlob,
+        ServerConfig => proto::ServerConfig,
+        SuggestConfig => proto::SuggestConfig,
+    );
+
+    let mut buf = vec![0u8; proto::PREFIX_TOTAL_SIZE + body.len()];
+    control::write_message_header(typ, body.len(), &mut buf);
+    buf[proto::PREFIX_TOTAL_SIZE..].copy_from_slice(body);
+    let m = MessageBuf { typ, data: buf };
+    let _ = events::mumble_to_event(&NoUsers, &m, Session::new(1));
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use speakez::mumble::control::{self, MessageBuf};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = control::parse_prefix(data);
+    if let Ok(m) = MessageBuf::parse(data.to_vec()) {
+        let _ = m.body();
+    }
+    let _ = speakez_fuzz::messages(data);
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use speakez_server::mumble::crypt::{CryptState, KEY_SIZE};
+
+// Packets are decrypted in order with the same state, like a UDP stream.
+fuzz_target!(|packets: Vec<Vec<u8>>| {
+    let mut crypt = CryptState::new_from_key([7; KEY_SIZE]);
+    for packet in packets {
+        let mut buf = BytesMut::from(&packet[..]);
+        let _ = crypt.decrypt(&mut buf);
+    }
+});
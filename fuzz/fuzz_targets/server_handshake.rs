@@ -0,0 +1,29 @@
+#![no_main]
+
+use std::time::Instant;
+
+use libfuzzer_sys::fuzz_target;
+use speakez::common::{Channel, ROOT_CHANNEL};
+use speakez::server::state::{Peer, State};
+use speakez::server::{self, Message};
+use speakez_server::mumble::crypt::{CryptState, KEY_SIZE};
+
+// Messages go through the whole server, so anything after the handshake is covered too.
+fuzz_target!(|data: &[u8]| {
+    let mut s = State::new(10, || Box::new(CryptState::new_from_key([7; KEY_SIZE])));
+    s.new_channel(Channel::new(
+        ROOT_CHANNEL,
+        "Root".to_string(),
+        String::new(),
+        false,
+        None,
+    ));
+    let session = s.new_session().unwrap();
+
+    let now = Instant::now();
+    let mut s = server::handle_message(s, Message::SessionCreated(session, Peer::default()), now);
+    for m in speakez_fuzz::messages(data) {
+        s = server::handle_message(s, Message::Mumble(session, m), now);
+        s.outbox.clear();
+    }
+});
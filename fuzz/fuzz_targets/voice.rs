@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use speakez::common::events;
+use speakez::mumble::voice;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(voice::Message::Audio(audio)) = voice::Message::decode(data) {
+        let _ = events::mumble_voice_to_event(audio);
+    }
+});
@@ -0,0 +1,17 @@
+use speakez::mumble::control::{self, proto::PREFIX_TOTAL_SIZE, MessageBuf};
+
+/// Split `data` into framed control messages, stopping at the first invalid frame.
+pub fn messages(mut data: &[u8]) -> Vec<MessageBuf> {
+    let mut msgs = vec![];
+    while let Ok((_, size)) = control::parse_prefix(data) {
+        let Some(frame) = data.get(..PREFIX_TOTAL_SIZE + size) else {
+            break;
+        };
+        let Ok(m) = MessageBuf::parse(frame.to_vec()) else {
+            break;
+        };
+        msgs.push(m);
+        data = &data[frame.len()..];
+    }
+    msgs
+}
@@ -37,7 +37,7 @@ func loadConfig() config {
 	flag.StringVar(&cfg.addr, "addr", ":8080", "address to listen on")
 	flag.StringVar(&cfg.tlsCert, "cert", "", "tls cert")
 	flag.StringVar(&cfg.tlsKey, "key", "", "tls key")
-	flag.StringVar(&cfg.socketPath, "socket", "/tmp/speakez.sock", "unix socket for speakez")
+	flag.StringVar(&cfg.socketPath, "socket", "/tmp/speakez/speakez.sock", "unix socket for speakez")
 
 	flag.StringVar(&cfg.Dir, "dir", "", "directory to serve via /")
 	flag.StringVar(&cfg.FilePattern, "pattern", "", "file matching pattern")
@@ -86,6 +86,31 @@ impl ClientRef {
         self.send_with_resp(commands::Disconnect).await
     }
 
+    pub async fn accept_certificate(
+        &self,
+        c: commands::AcceptCertificate,
+    ) -> Result<response::AcceptCertificate, String> {
+        self.send_with_resp(c).await
+    }
+
+    pub async fn import_identity(
+        &self,
+        c: commands::ImportIdentity,
+    ) -> Result<response::ImportIdentity, String> {
+        self.send_with_resp(c).await
+    }
+
+    pub async fn export_identity(
+        &self,
+        c: commands::ExportIdentity,
+    ) -> Result<response::ExportIdentity, String> {
+        self.send_with_resp(c).await
+    }
+
+    pub async fn stats(&self) -> Result<response::Stats, String> {
+        self.send_with_resp(commands::Stats).await
+    }
+
     pub fn switch_channel(&self, channel_id: ChannelID) {
         self.send_command(commands::SwitchChannel { channel_id }.into())
     }
@@ -101,10 +126,10 @@ impl ClientRef {
     }
 
     pub fn input_mute(&self, value: bool) {
-        // self.send_command(cmd);
+        self.send_command(commands::MuteMic { mute: value }.into())
     }
 
     pub fn input_monitor(&self, value: bool) {
-        // self.send_command(cmd);
+        self.send_command(commands::MonitorMic { monitor: value }.into())
     }
 }
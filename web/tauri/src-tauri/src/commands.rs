@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use crate::AppState;
 use speakez_client::{
     audio::DeviceConfig,
@@ -62,6 +64,47 @@ pub async fn connect(
         .map_err(|e| ConnectResp { message: e })
 }
 
+#[tauri::command]
+pub async fn accept_certificate(
+    state: tauri::State<'_, AppState>,
+    host: String,
+    fingerprint: String,
+) -> Result<response::AcceptCertificate, String> {
+    state
+        .client
+        .accept_certificate(commands::AcceptCertificate { host, fingerprint })
+        .await
+}
+
+#[tauri::command]
+pub async fn import_identity(
+    state: tauri::State<'_, AppState>,
+    path: PathBuf,
+    password: String,
+) -> Result<response::ImportIdentity, String> {
+    state
+        .client
+        .import_identity(commands::ImportIdentity { path, password })
+        .await
+}
+
+#[tauri::command]
+pub async fn export_identity(
+    state: tauri::State<'_, AppState>,
+    path: PathBuf,
+    password: String,
+) -> Result<response::ExportIdentity, String> {
+    state
+        .client
+        .export_identity(commands::ExportIdentity { path, password })
+        .await
+}
+
+#[tauri::command]
+pub async fn stats(state: tauri::State<'_, AppState>) -> Result<response::Stats, String> {
+    state.client.stats().await
+}
+
 #[tauri::command]
 pub async fn disconnect(state: tauri::State<'_, AppState>) -> Result<response::Disconnect, String> {
     state.client.disconnect().await
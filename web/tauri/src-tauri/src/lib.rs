@@ -3,18 +3,21 @@
 mod client;
 mod commands;
 
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use client::ClientRef;
+use speakez_client::tls::tofu::{KnownHosts, TofuVerifier};
 use tauri::{Emitter, Manager};
 use tokio::sync::broadcast;
 
-fn get_tls_config() -> rustls::ClientConfig {
+fn get_tls_config(known_hosts: Arc<Mutex<KnownHosts>>) -> rustls::ClientConfig {
     let provider = rustls::crypto::aws_lc_rs::default_provider();
     provider.install_default().unwrap();
 
-    let verifier = speakez_client::tls::danger::NoCertificateVerification::new(
-        rustls::crypto::aws_lc_rs::default_provider(),
+    let verifier = TofuVerifier::new(
+        Arc::new(rustls_platform_verifier::Verifier::new()),
+        known_hosts,
     );
 
     use rustls_platform_verifier::BuilderVerifierExt;
@@ -24,7 +27,7 @@ fn get_tls_config() -> rustls::ClientConfig {
 
     config
         .dangerous()
-        .set_certificate_verifier(std::sync::Arc::new(verifier));
+        .set_certificate_verifier(Arc::new(verifier));
 
     config
 }
@@ -34,7 +37,15 @@ struct AppState {
 }
 
 fn setup(app: &mut tauri::App) {
-    let config = get_tls_config();
+    let config_dir = app
+        .path()
+        .app_config_dir()
+        .expect("failed to find the config directory");
+    let known_hosts = config_dir.join("known_hosts");
+    let known_hosts = KnownHosts::load(known_hosts).expect("failed to load known hosts");
+    let known_hosts = Arc::new(Mutex::new(known_hosts));
+
+    let config = get_tls_config(known_hosts.clone());
     let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
     let tokio_sender = sender.clone();
     let sender = std::sync::Arc::new(move |msg| {
@@ -43,6 +54,10 @@ fn setup(app: &mut tauri::App) {
     });
     let s = sender.clone();
     let mut client = speakez_client::Client::new(config, s);
+    client.set_known_hosts(known_hosts);
+    if let Err(e) = client.load_identity(config_dir.join("identity.p12"), "speakez") {
+        log::error!("failed to load identity, connecting without one: {}", e);
+    }
 
     let (tx, _) = broadcast::channel(16);
     let client_ref = ClientRef::new(sender, tx.clone());
@@ -60,6 +75,10 @@ fn setup(app: &mut tauri::App) {
                 }
             }
 
+            for event in client.outgoing_events() {
+                handle.emit("client", event).unwrap();
+            }
+
             for response in client.responses() {
                 tx.send(response).unwrap();
             }
@@ -88,6 +107,10 @@ pub fn run() {
             commands::set_output,
             commands::connect,
             commands::disconnect,
+            commands::accept_certificate,
+            commands::import_identity,
+            commands::export_identity,
+            commands::stats,
             commands::switch_channel,
             commands::mic_mute,
             commands::mic_monitor,